@@ -1,27 +1,104 @@
-use std::time::SystemTime;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::{errors::Result, transaction::Transaction};
+use crate::{errors::Result, network::NetworkParams, transaction::Transaction};
+use chrono::{DateTime, SecondsFormat};
 use crypto::{digest::Digest, sha2::Sha256};
-use log::info;
+use tracing::info;
+use failure::format_err;
 use merkle_cbt::{merkle_tree::Merge, CBMT};
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Block {
     timestamp: u128,
     transactions: Vec<Transaction>,
+    // root of the merkle tree over the transaction hashes
+    merkle_root: Vec<u8>,
     prev_block_hash: String,
     hash: String,
     height: i32,
-    nonce: i32,
+    // u64 so hard blocks can't exhaust it, this widened the hashed header
+    nonce: u64,
+    // number of leading hex zeros the hash must have
+    difficulty: usize,
 }
 
-const TARGET_HEXT: usize = 4;
+// a block without its transactions, enough to check its proof of work and
+// how it links to its parent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub timestamp: u128,
+    pub merkle_root: Vec<u8>,
+    pub prev_block_hash: String,
+    pub hash: String,
+    pub height: i32,
+    pub nonce: u64,
+    pub difficulty: usize,
+}
+
+impl BlockHeader {
+    pub fn verify_proof_of_work(&self) -> Result<bool> {
+        let hash = hash_header(
+            &self.prev_block_hash,
+            &self.merkle_root,
+            self.timestamp,
+            self.difficulty,
+            self.nonce,
+        )?;
+        Ok(hash == self.hash && meets_difficulty(&hash, self.difficulty))
+    }
+}
+
+pub const TARGET_HEXT: usize = 4;
+
+// what the proof of work of a block took
+#[derive(Debug, Clone)]
+pub struct MiningStats {
+    pub hash: String,
+    pub height: i32,
+    pub difficulty: usize,
+    // nonces tried, the last one solved the block
+    pub attempts: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningStats {
+    // hashes per second
+    pub fn hashrate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.attempts as f64 / secs
+    }
+}
 
 impl Block {
     pub fn get_transactions(&self) -> &Vec<Transaction> {
         &self.transactions
     }
 
+    // drop transactions from a stored block, the header still commits to all
+    // of them so the merkle root no longer matches
+    pub(crate) fn retain_transactions(&mut self, keep: impl FnMut(&Transaction) -> bool) {
+        self.transactions.retain(keep);
+    }
+
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    // the timestamp as an rfc 3339 date in utc, the raw milliseconds if it
+    // is out of range
+    pub fn datetime(&self) -> String {
+        match i64::try_from(self.timestamp).ok().and_then(DateTime::from_timestamp_millis) {
+            Some(time) => time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            None => format!("{}ms", self.timestamp),
+        }
+    }
+
     pub fn get_hash(&self) -> String {
         self.hash.clone()
     }
@@ -30,12 +107,46 @@ impl Block {
         self.height
     }
 
+    pub fn get_merkle_root(&self) -> &[u8] {
+        &self.merkle_root
+    }
+
     pub fn get_prev_hash(&self) -> String {
         self.prev_block_hash.clone()
     }
 
-    pub fn new_genesis_block(coinbase: Transaction) -> Block {
-        Block::new_block(vec![coinbase], String::new(), 0).unwrap()
+    pub fn get_nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn get_difficulty(&self) -> usize {
+        self.difficulty
+    }
+
+    pub fn get_header(&self) -> BlockHeader {
+        BlockHeader {
+            timestamp: self.timestamp,
+            merkle_root: self.merkle_root.clone(),
+            prev_block_hash: self.prev_block_hash.clone(),
+            hash: self.hash.clone(),
+            height: self.height,
+            nonce: self.nonce,
+            difficulty: self.difficulty,
+        }
+    }
+
+    // expected number of hashes to mine the block, every hex zero of the
+    // target makes it 16 times harder
+    pub fn get_work(&self) -> u128 {
+        1 << (4 * self.difficulty)
+    }
+
+    pub fn new_genesis_block(coinbase: Transaction, difficulty: usize) -> Result<Block> {
+        let never = AtomicBool::new(false);
+        let (block, _) =
+            Block::new_block_with_difficulty(vec![coinbase], String::new(), 0, difficulty, &never)?
+                .ok_or_else(|| format_err!("mining was interrupted"))?;
+        Ok(block)
     }
 
     pub fn new_block(
@@ -43,6 +154,21 @@ impl Block {
         prev_block_hash: String,
         height: i32,
     ) -> Result<Block> {
+        let never = AtomicBool::new(false);
+        let (block, _) =
+            Block::new_block_with_difficulty(data, prev_block_hash, height, TARGET_HEXT, &never)?
+                .ok_or_else(|| format_err!("mining was interrupted"))?;
+        Ok(block)
+    }
+
+    // None if `interrupt` was set before the proof of work was found
+    pub fn new_block_with_difficulty(
+        data: Vec<Transaction>,
+        prev_block_hash: String,
+        height: i32,
+        difficulty: usize,
+        interrupt: &AtomicBool,
+    ) -> Result<Option<(Block, MiningStats)>> {
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)?
             .as_millis();
@@ -50,64 +176,230 @@ impl Block {
         let mut block = Block {
             timestamp,
             transactions: data,
+            merkle_root: Vec::new(),
             prev_block_hash,
             hash: String::new(),
             height,
             nonce: 0,
+            difficulty,
+        };
+        block.merkle_root = block.hash_transaction()?;
+        Ok(block.run_proof_of_work(interrupt)?.map(|stats| (block, stats)))
+    }
+
+    // a block whose proof of work was run elsewhere, e.g. by an external
+    // miner, it still has to be validated
+    pub fn assemble(
+        transactions: Vec<Transaction>,
+        prev_block_hash: String,
+        height: i32,
+        difficulty: usize,
+        timestamp: u128,
+        nonce: u64,
+    ) -> Result<Block> {
+        let mut block = Block {
+            timestamp,
+            merkle_root: merkle_root(&transactions)?,
+            transactions,
+            prev_block_hash,
+            hash: String::new(),
+            height,
+            nonce,
+            difficulty,
         };
-        block.run_proof_of_work()?;
+        block.hash = block.compute_hash()?;
         Ok(block)
     }
 
-    fn hash_transaction(&self) -> Result<Vec<u8>> {
-        let mut transactions = Vec::new();
-        for tx in &self.transactions {
-            transactions.push(tx.clone().hash()?.as_bytes().to_owned());
-        }
+    // the bincode encoding of the block as hex, to move it around outside of
+    // the peer protocol
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(hex::encode(bincode::serialize(self)?))
+    }
 
-        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&transactions);
+    pub fn from_hex(data: &str) -> Result<Block> {
+        let bytes = hex::decode(data.trim()).map_err(|e| format_err!("invalid hex: {}", e))?;
+        bincode::deserialize(&bytes).map_err(|e| format_err!("invalid block: {}", e))
+    }
 
-        Ok(tree.root())
+    fn hash_transaction(&self) -> Result<Vec<u8>> {
+        merkle_root(&self.transactions)
     }
 
-    fn prepare_hash_data(&self) -> Result<Vec<u8>> {
+    // proof that a transaction is part of the block, checked against the
+    // merkle root alone with verify_merkle_proof
+    pub fn merkle_proof(&self, txid: &str) -> Result<MerkleProof> {
+        let position = match self.transactions.iter().position(|tx| tx.id == txid) {
+            Some(position) => position as u32,
+            None => {
+                return Err(format_err!(
+                    "transaction {} is not in block {}",
+                    txid,
+                    self.hash
+                ))
+            }
+        };
 
-        let content = (
-            self.prev_block_hash.clone(),
-            self.hash_transaction()?,
-            self.timestamp,
-            TARGET_HEXT,
-            self.nonce
-        );
+        let transactions = transaction_hashes(&self.transactions)?;
+        let proof = CBMT::<Vec<u8>, MergeTX>::build_merkle_proof(&transactions, &[position])
+            .ok_or_else(|| format_err!("cannot build merkle proof for {}", txid))?;
 
-        let bytes = bincode::serialize(&content)?;
-        Ok(bytes)
+        Ok(MerkleProof {
+            index: proof.indices()[0],
+            lemmas: proof.lemmas().to_vec(),
+        })
     }
 
-    pub fn run_proof_of_work(&mut self) -> Result<()> {
+    // None if `interrupt` was set first, e.g. because the block went stale
+    pub fn run_proof_of_work(&mut self, interrupt: &AtomicBool) -> Result<Option<MiningStats>> {
         info!("Minting the block");
+        let start = Instant::now();
+        let mut attempts = 1;
 
         while !self.validate()? {
-            self.nonce += 1
+            if interrupt.load(Ordering::Relaxed) {
+                info!("Minting interrupted at nonce {}", self.nonce);
+                return Ok(None);
+            }
+            self.nonce += 1;
+            attempts += 1;
         }
 
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
-        Ok(())
+        self.hash = self.compute_hash()?;
+        let stats = MiningStats {
+            hash: self.hash.clone(),
+            height: self.height,
+            difficulty: self.difficulty,
+            attempts,
+            elapsed: start.elapsed(),
+        };
+        info!(
+            "Minted block {} after {} attempts in {:?}, {:.0} hashes/s",
+            stats.hash,
+            stats.attempts,
+            stats.elapsed,
+            stats.hashrate()
+        );
+        Ok(Some(stats))
     }
 
-    pub fn validate(&mut self) -> Result<bool> {
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        let mut vec1 = vec![];
-        vec1.resize(TARGET_HEXT, '0' as u8);
-        Ok(&hasher.result_str()[0..TARGET_HEXT] == String::from_utf8(vec1)?)
+    fn compute_hash(&self) -> Result<String> {
+        hash_header(
+            &self.prev_block_hash,
+            &self.merkle_root,
+            self.timestamp,
+            self.difficulty,
+            self.nonce,
+        )
+    }
+
+    pub fn validate(&self) -> Result<bool> {
+        Ok(meets_difficulty(&self.compute_hash()?, self.difficulty))
+    }
+
+    // check a received block: the stored hash must be the hash of its
+    // content and meet the difficulty
+    pub fn verify_proof_of_work(&self) -> Result<bool> {
+        Ok(self.compute_hash()? == self.hash && self.validate()?)
+    }
+
+    // the transactions must be the ones committed to by the header
+    pub fn verify_merkle_root(&self) -> Result<bool> {
+        Ok(self.hash_transaction()? == self.merkle_root)
+    }
+
+    // the header and the transactions as Transaction::display shows them,
+    // Debug shows the raw fields
+    pub fn display<'a>(&'a self, params: &'a NetworkParams) -> BlockDisplay<'a> {
+        BlockDisplay {
+            block: self,
+            params,
+        }
+    }
+}
+
+// a block with the addresses of a network, see Block::display
+pub struct BlockDisplay<'a> {
+    block: &'a Block,
+    params: &'a NetworkParams,
+}
+
+impl fmt::Display for BlockDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let block = self.block;
+        writeln!(f, "block {}", block.hash)?;
+        writeln!(f, "  height: {}", block.height)?;
+        writeln!(f, "  prev: {}", block.prev_block_hash)?;
+        writeln!(f, "  timestamp: {} ({})", block.datetime(), block.timestamp)?;
+        writeln!(f, "  merkle root: {}", hex::encode(&block.merkle_root))?;
+        writeln!(f, "  nonce: {}, difficulty: {}", block.nonce, block.difficulty)?;
+        for tx in &block.transactions {
+            for line in tx.display(self.params).to_string().lines() {
+                writeln!(f, "  {}", line)?;
+            }
+        }
+        Ok(())
     }
 }
 
+// leaves of the merkle tree
+fn transaction_hashes(transactions: &[Transaction]) -> Result<Vec<Vec<u8>>> {
+    transactions.iter().map(merkle_leaf).collect()
+}
+
+// the hash of the whole transaction, signatures included, so the root
+// commits to them too
+fn merkle_leaf(tx: &Transaction) -> Result<Vec<u8>> {
+    Ok(tx.clone().hash()?.into_bytes())
+}
+
+pub fn merkle_root(transactions: &[Transaction]) -> Result<Vec<u8>> {
+    let hashes = transaction_hashes(transactions)?;
+    let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&hashes);
+    Ok(tree.root())
+}
+
+// hash of the header fields, the height is not part of it
+fn hash_header(
+    prev_block_hash: &str,
+    merkle_root: &[u8],
+    timestamp: u128,
+    difficulty: usize,
+    nonce: u64,
+) -> Result<String> {
+    let content = (prev_block_hash, merkle_root, timestamp, difficulty, nonce);
+    let data = bincode::serialize(&content)?;
+    let mut hasher = Sha256::new();
+    hasher.input(&data[..]);
+    Ok(hasher.result_str())
+}
+
+fn meets_difficulty(hash: &str, difficulty: usize) -> bool {
+    hash.len() >= difficulty && hash.bytes().take(difficulty).all(|b| b == b'0')
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MerkleProof {
+    // position of the transaction's node in the tree
+    pub index: u32,
+    // sibling hashes needed to rebuild the root
+    pub lemmas: Vec<Vec<u8>>,
+}
+
+// the transaction the proof was built for is part of the block with the
+// merkle root `root`, it is hashed like the leaves of the tree
+pub fn verify_merkle_proof(root: &[u8], tx: &Transaction, proof: &MerkleProof) -> bool {
+    let leaf = match merkle_leaf(tx) {
+        Ok(leaf) => leaf,
+        Err(_) => return false,
+    };
+    let proof = merkle_cbt::merkle_tree::MerkleProof::<Vec<u8>, MergeTX>::new(
+        vec![proof.index],
+        proof.lemmas.clone(),
+    );
+    proof.verify(&root.to_vec(), &[leaf])
+}
+
 struct MergeTX {}
 
 impl Merge for MergeTX {
@@ -124,3 +416,126 @@ impl Merge for MergeTX {
         re.to_vec()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::REGTEST;
+    use crate::test_utils::{coinbase, mine, utxo_chain, wallet};
+
+    #[test]
+    fn a_signed_transaction_is_proven_part_of_its_block() -> Result<()> {
+        let miner = wallet(1);
+        let address = miner.get_address(&REGTEST);
+        let utxo = utxo_chain(&address)?;
+        let genesis = utxo.blockchain.get_block(&utxo.blockchain.get_tip_hash())?;
+        let payee = wallet(2).get_address(&REGTEST);
+        let mut tx = Transaction::new_utxo(&miner, &payee, 10, &utxo)?;
+        // the id was computed before the signature, the leaf after
+        assert_ne!(tx.id, tx.clone().hash()?);
+
+        let block = mine(&address, vec![tx.clone(), coinbase(&payee, 7)?], &genesis)?;
+        let proof = block.merkle_proof(&tx.id)?;
+        assert!(verify_merkle_proof(block.get_merkle_root(), &tx, &proof));
+
+        // another transaction of the block does not match the proof
+        let other = &block.get_transactions()[2];
+        assert!(!verify_merkle_proof(block.get_merkle_root(), other, &proof));
+        // nor the transaction with another signature
+        tx.vin[0].signature[0] ^= 1;
+        assert!(!verify_merkle_proof(block.get_merkle_root(), &tx, &proof));
+        Ok(())
+    }
+
+    #[test]
+    fn a_nonce_above_i32_max_survives_a_round_trip() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let nonce = u32::MAX as u64 + 7;
+        assert!(nonce > i32::MAX as u64);
+        let txs = vec![coinbase(&address, 1)?];
+        let block = Block::assemble(txs.clone(), "00".to_string(), 1, 1, 1_700_000_000_000, nonce)?;
+
+        let decoded = Block::from_hex(&block.to_hex()?)?;
+        assert_eq!(decoded.get_nonce(), nonce);
+        assert_eq!(decoded.compute_hash()?, block.get_hash());
+        // the high bits are part of the hash
+        let truncated = Block::assemble(txs, "00".to_string(), 1, 1, 1_700_000_000_000, 6)?;
+        assert_ne!(truncated.get_hash(), block.get_hash());
+        Ok(())
+    }
+
+    #[test]
+    fn setting_the_interrupt_stops_the_proof_of_work() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        // no hash has 64 leading zeros, it would run forever
+        let mut block = Block::assemble(vec![coinbase(&address, 1)?], String::new(), 1, 64, 0, 0)?;
+        let interrupt = std::sync::Arc::new(AtomicBool::new(false));
+        let (done, finished) = std::sync::mpsc::channel();
+        let flag = interrupt.clone();
+        std::thread::spawn(move || done.send(block.run_proof_of_work(&flag).map(|s| s.is_none())));
+
+        std::thread::sleep(Duration::from_millis(100));
+        let interrupted = Instant::now();
+        interrupt.store(true, Ordering::Relaxed);
+        let gave_up = finished.recv_timeout(Duration::from_secs(2))??;
+        assert!(gave_up);
+        assert!(interrupted.elapsed() < Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[test]
+    fn the_attempts_count_every_nonce_tried() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let never = AtomicBool::new(false);
+        for height in 1..=5 {
+            let txs = vec![coinbase(&address, height)?];
+            let (block, stats) =
+                Block::new_block_with_difficulty(txs, String::new(), height, 2, &never)?.unwrap();
+            // nonces start at 0, the last one tried solved the block
+            assert_eq!(stats.attempts, block.get_nonce() + 1);
+            assert_eq!(stats.hash, block.get_hash());
+            assert_eq!((stats.height, stats.difficulty), (height, 2));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn a_printed_block_shows_each_output_with_its_recipient() -> Result<()> {
+        let (payer, payee) = (wallet(1), wallet(2));
+        let from = payer.get_address(&REGTEST);
+        let to = payee.get_address(&REGTEST);
+        let mut utxo = utxo_chain(&from)?;
+        let genesis = utxo.blockchain.get_block(&utxo.blockchain.get_tip_hash())?;
+        let spent = genesis.get_transactions()[0].id.clone();
+        let tx = Transaction::new_utxo(&payer, &to, 10, &utxo)?;
+        let block = utxo.mine_block(&to, vec![tx.clone()])?;
+
+        let printed = block.display(&REGTEST).to_string();
+        let lines: Vec<&str> = printed.lines().map(str::trim).collect();
+        for expected in [
+            format!("block {}", block.get_hash()),
+            format!("transaction {}", tx.id),
+            format!("in  {}:0 from {}", spent, from),
+            format!("out 0: 10 to {}", to),
+            format!("out 1: 90 to {}", from),
+            format!("out 0: 100 to {}", to),
+            "in  coinbase".to_string(),
+        ] {
+            assert!(lines.contains(&expected.as_str()), "{} not in\n{}", expected, printed);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn the_datetime_of_a_block_is_its_utc_timestamp() -> Result<()> {
+        let txs = vec![coinbase(&wallet(1).get_address(&REGTEST), 1)?];
+        let block = Block::assemble(txs.clone(), String::new(), 1, 1, 1_700_000_000_123, 0)?;
+        assert_eq!(block.datetime(), "2023-11-14T22:13:20.123Z");
+        let epoch = Block::assemble(txs.clone(), String::new(), 1, 1, 0, 0)?;
+        assert_eq!(epoch.datetime(), "1970-01-01T00:00:00.000Z");
+        // beyond what a date can hold, the milliseconds are shown as they are
+        let far = Block::assemble(txs, String::new(), 1, 1, u128::MAX, 0)?;
+        assert_eq!(far.datetime(), format!("{}ms", u128::MAX));
+        Ok(())
+    }
+}
@@ -1,134 +1,1061 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use std::vec;
 
 use failure::format_err;
-use log::info;
+use tracing::info;
+use serde::Serialize;
 
-use crate::block::Block;
+use crate::block::{merkle_root, Block, BlockHeader, MiningStats};
 use crate::errors::Result;
-use crate::transaction::Transaction;
+use crate::network::{NetworkParams, MAINNET};
+use crate::signature::SignatureScheme;
+use crate::storage::{Batch, MemoryStorage, SledStorage, Storage};
+use crate::transaction::{block_subsidy, Transaction};
 use crate::tx::TXOutputs;
+
+// value of the FORMAT key of a chain whose blocks are zstd compressed, chains
+// created before have no such key and keep their plain bincode blocks
+const FORMAT_ZSTD: u8 = 1;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
 #[derive(Debug, Clone)]
-pub struct BlockChain {
+pub struct BlockChain<S: Storage = SledStorage> {
     current_hash: String,
-    db: sled::Db,
+    db: S,
+    // accumulated work of the chain ending at each stored block
+    work: S,
+    // height -> hash of the blocks on the best chain
+    heights: S,
+    // hashes of the blocks whose spent transactions were dropped
+    pruned: S,
+    // the blocks are stored zstd compressed
+    compressed: bool,
+    // hash the block at each height must have
+    checkpoints: BTreeMap<i32, String>,
+    data_dir: String,
+    // network the chain belongs to
+    params: NetworkParams,
+    // notified of every block joining the best chain
+    subscribers: Arc<Mutex<Vec<Sender<BlockEvent>>>>,
+}
+
+// a block joined the best chain
+#[derive(Debug, Clone)]
+pub struct BlockEvent {
+    pub hash: String,
+    pub height: i32,
+    pub tx_count: usize,
+}
+
+// summary of the best chain, the counts are filled in by the utxo set and
+// the node that know them
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainInfo {
+    pub tip: String,
+    pub height: i32,
+    pub blocks: usize,
+    // of the tip block
+    pub difficulty: usize,
+    pub total_work: u128,
+    pub pruned_height: Option<i32>,
+    pub utxo_count: Option<i32>,
+    pub mempool_size: Option<usize>,
+}
+
+// how the best chain moved after a block was added
+#[derive(Debug, Clone, Default)]
+pub struct TipChange {
+    // blocks removed from the best chain, starting from the old tip
+    pub disconnected: Vec<Block>,
+    // blocks appended to the best chain, starting right after the fork point
+    pub connected: Vec<Block>,
+}
+
+impl TipChange {
+    // non coinbase transactions of the orphaned blocks, they can go back to the mempool
+    pub fn disconnected_transactions(&self) -> Vec<Transaction> {
+        let mut txs = Vec::new();
+        for block in &self.disconnected {
+            for tx in block.get_transactions() {
+                if !tx.is_coinbase() {
+                    txs.push(tx.clone());
+                }
+            }
+        }
+        txs
+    }
 }
 
-pub struct BlockChainIter<'a> {
+pub struct BlockChainIter<'a, S: Storage = SledStorage> {
     current_hash: String,
-    bc: &'a BlockChain,
+    bc: &'a BlockChain<S>,
+}
+
+pub struct BlockTemplate {
+    // the coinbase first
+    pub transactions: Vec<Transaction>,
+    pub prev_block_hash: String,
+    pub height: i32,
+    // leading hex zeros the hash of the block must have
+    pub difficulty: usize,
+    pub merkle_root: Vec<u8>,
+}
+
+impl BlockTemplate {
+    // run the proof of work, the block still has to be added to the chain
+    pub fn mine(self) -> Result<Block> {
+        let never = AtomicBool::new(false);
+        let (block, _) = self
+            .mine_interruptible(&never)?
+            .ok_or_else(|| format_err!("mining was interrupted"))?;
+        Ok(block)
+    }
+
+    // like mine, but gives up with None once `interrupt` is set
+    pub fn mine_interruptible(
+        self,
+        interrupt: &AtomicBool,
+    ) -> Result<Option<(Block, MiningStats)>> {
+        Block::new_block_with_difficulty(
+            self.transactions,
+            self.prev_block_hash,
+            self.height,
+            self.difficulty,
+            interrupt,
+        )
+    }
+}
+
+// refuse transactions whose lock time the block doesn't reach
+fn check_lock_times(block: &Block) -> Result<()> {
+    let time = (block.get_timestamp() / 1000) as u64;
+    for tx in block.get_transactions() {
+        if !tx.is_final(block.get_height(), time) {
+            return Err(invalid_block(format!(
+                "transaction {} in block {} is locked until {}",
+                tx.id,
+                block.get_hash(),
+                tx.lock_time
+            )));
+        }
+    }
+    Ok(())
+}
+
+// a block breaking the rules of the chain, whatever the chain of the node
+// sending it, so the node can be blamed for it. a block refused for another
+// reason, e.g. a failed read, fails with another error
+#[derive(Debug)]
+pub struct InvalidBlock(pub String);
+
+impl fmt::Display for InvalidBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidBlock {}
+
+pub(crate) fn invalid_block(reason: String) -> failure::Error {
+    InvalidBlock(reason).into()
+}
+
+// the coinbase, the first transaction, must pay the subsidy plus `fees`
+pub(crate) fn check_coinbase_value(block: &Block, fees: u64) -> Result<()> {
+    let expected = block_subsidy(block.get_height())
+        .checked_add(fees)
+        .ok_or_else(|| invalid_block(format!("fees of block {} overflow", block.get_hash())))?;
+    let value = block.get_transactions()[0]
+        .vout
+        .iter()
+        .try_fold(0u64, |value, out| value.checked_add(out.value))
+        .ok_or_else(|| invalid_block(format!("coinbase of block {} overflows", block.get_hash())))?;
+    if value != expected {
+        return Err(invalid_block(format!(
+            "coinbase of block {} pays {}, expected {}",
+            block.get_hash(),
+            value,
+            expected
+        )));
+    }
+    Ok(())
+}
+
+// refuse transactions spending the same output twice, each one may be valid
+// on its own
+fn check_double_spends(txs: &[Transaction]) -> Result<()> {
+    let mut spent = HashSet::new();
+    for tx in txs.iter().filter(|tx| !tx.is_coinbase()) {
+        for vin in &tx.vin {
+            if !spent.insert((vin.txid.as_str(), vin.vout)) {
+                return Err(format_err!(
+                    "output {}:{} is spent twice, again by {}",
+                    vin.txid,
+                    vin.vout,
+                    tx.id
+                ));
+            }
+        }
+    }
+    Ok(())
 }
 
 impl BlockChain {
-    pub fn new() -> Result<BlockChain> {
+    pub fn new(data_dir: &str) -> Result<BlockChain> {
         info!("open blockchain");
-        let db = sled::open("data/blocks")?;
-        let hash = db
-            .get("LAST")?
-            .expect("Must create a new block database first");
+        BlockChain::with_storage(SledStorage::open(&format!("{}/blocks", data_dir))?, data_dir)
+    }
+
+    pub fn create_blockchain(
+        address: String,
+        data_dir: &str,
+        params: &NetworkParams,
+    ) -> Result<BlockChain> {
+        let db = SledStorage::open(&format!("{}/blocks", data_dir))?;
+        BlockChain::create_with_storage(address, db, data_dir, params)
+    }
+
+    // rebuild a chain of `params` in an empty `data_dir` from the output of
+    // export_json, every block is validated and must extend the previous one
+    pub fn import_json(
+        reader: impl Read,
+        data_dir: &str,
+        params: &NetworkParams,
+    ) -> Result<BlockChain> {
+        let db = SledStorage::open(&format!("{}/blocks", data_dir))?;
+        BlockChain::import_json_with_storage(reader, db, data_dir, params)
+    }
+}
+
+impl BlockChain<MemoryStorage> {
+    // a new chain that is never written to disk, it has no data directory
+    pub fn create_in_memory(
+        address: String,
+        params: &NetworkParams,
+    ) -> Result<BlockChain<MemoryStorage>> {
+        BlockChain::create_with_storage(address, MemoryStorage::new(), "", params)
+    }
+}
+
+impl<S: Storage> BlockChain<S> {
+    // the chain stored in `db`, `data_dir` is where the other databases of
+    // the node live
+    pub fn with_storage(db: S, data_dir: &str) -> Result<BlockChain<S>> {
+        let hash = match db.get("LAST")? {
+            Some(hash) => hash,
+            None => return Err(format_err!("no blockchain found, run `create` first")),
+        };
         info!("Found block database");
 
-        let last_hash = String::from_utf8(hash.to_vec())?;
+        let last_hash = String::from_utf8(hash)?;
+        let compressed = match db.get("FORMAT")?.as_deref() {
+            None => false,
+            Some([FORMAT_ZSTD]) => true,
+            Some(format) => return Err(format_err!("unknown block format {:?}", format)),
+        };
+        // chains created before networks existed are mainnet ones
+        let params = match db.get("NETWORK")? {
+            Some(name) => NetworkParams::by_name(&String::from_utf8(name)?)?,
+            None => MAINNET,
+        };
         Ok(BlockChain {
-            current_hash: last_hash.clone(),
-            db: db,
+            current_hash: last_hash,
+            work: db.open_tree("work")?,
+            heights: db.open_tree("heights")?,
+            pruned: db.open_tree("pruned")?,
+            compressed,
+            checkpoints: BTreeMap::new(),
+            db,
+            data_dir: data_dir.to_string(),
+            params,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
         })
     }
 
+    pub fn get_tip_hash(&self) -> String {
+        self.current_hash.clone()
+    }
+
+    pub fn get_data_dir(&self) -> &str {
+        &self.data_dir
+    }
+
+    pub fn get_params(&self) -> &NetworkParams {
+        &self.params
+    }
+
+    pub(crate) fn get_storage(&self) -> &S {
+        &self.db
+    }
 
     pub fn get_block(&self, block_hash: &str) -> Result<Block> {
+        if self.pruned.contains_key(block_hash)? {
+            return Err(format_err!("Block {} is pruned", block_hash));
+        }
+        self.read_block(block_hash)
+    }
+
+    // the stored block, only the header and the unspent transactions of a
+    // pruned one
+    fn read_block(&self, block_hash: &str) -> Result<Block> {
         if let Some(data) = self.db.get(block_hash)? {
-            let block: Block = bincode::deserialize(&data)?;
-            Ok(block)
+            self.decode_block(&data)
         } else {
-            Err(format_err!("Block not found"))
+            Err(format_err!("Block not found: {}", block_hash))
         }
     }
 
-    pub fn create_blockchain(address: String) -> Result<BlockChain> {
-        info!("Creating new blockchain");
-        let db = sled::open("data/blocks")?;
-        let bctx = Transaction::new_coinbase(address, String::from("Genesis Block"))?;
-        let genesis = Block::new_genesis_block(bctx);
-        db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
-        db.insert("LAST", genesis.get_hash().as_bytes())?;
-        let bc = BlockChain {
-            current_hash: genesis.get_hash(),
-            db: db,
+    fn encode_block(&self, block: &Block) -> Result<Vec<u8>> {
+        let data = bincode::serialize(block)?;
+        if !self.compressed {
+            return Ok(data);
+        }
+        Ok(zstd::encode_all(&data[..], COMPRESSION_LEVEL)?)
+    }
+
+    fn decode_block(&self, data: &[u8]) -> Result<Block> {
+        if !self.compressed {
+            return Ok(bincode::deserialize(data)?);
+        }
+        Ok(bincode::deserialize(&zstd::decode_all(data)?)?)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn has_block(&self, block_hash: &str) -> Result<bool> {
+        self.db.contains_key(block_hash)
+    }
+
+    // block of the best chain at the given height
+    pub fn get_block_by_height(&self, height: i32) -> Result<Block> {
+        self.get_block(&self.get_hash_by_height(height)?)
+    }
+
+    fn get_hash_by_height(&self, height: i32) -> Result<String> {
+        match self.heights.get(height.to_be_bytes())? {
+            Some(hash) => Ok(String::from_utf8(hash)?),
+            None => Err(format_err!("no block at height {}", height)),
+        }
+    }
+
+    pub fn create_with_storage(
+        address: String,
+        db: S,
+        data_dir: &str,
+        params: &NetworkParams,
+    ) -> Result<BlockChain<S>> {
+        info!("Creating new {} blockchain", params.name);
+        let data = String::from(params.genesis_data);
+        let bctx = Transaction::new_coinbase(address, data, 0, params)?;
+        let genesis = Block::new_genesis_block(bctx, params.difficulty)?;
+        BlockChain::init(genesis, db, data_dir, params)
+    }
+
+    // write every block, genesis first, as a json array
+    pub fn export_json(&self, writer: impl Write) -> Result<()> {
+        if self.is_pruned()? {
+            return Err(format_err!("cannot export a pruned chain"));
+        }
+        let mut blocks: Vec<Block> = self.iter().collect();
+        blocks.reverse();
+        serde_json::to_writer(writer, &blocks)?;
+        Ok(())
+    }
+
+    pub fn import_json_with_storage(
+        reader: impl Read,
+        db: S,
+        data_dir: &str,
+        params: &NetworkParams,
+    ) -> Result<BlockChain<S>> {
+        let blocks: Vec<Block> = serde_json::from_reader(reader)?;
+        let mut blocks = blocks.into_iter();
+        let genesis = match blocks.next() {
+            Some(genesis) => genesis,
+            None => return Err(format_err!("no block to import")),
         };
+        if !genesis.get_prev_hash().is_empty()
+            || genesis.get_height() != 0
+            || genesis.get_difficulty() != params.difficulty
+            || !genesis.verify_proof_of_work()?
+            || !genesis.verify_merkle_root()?
+        {
+            return Err(format_err!("invalid genesis block {}", genesis.get_hash()));
+        }
+        // the coinbase data of the genesis block tells its network
+        let coinbase = genesis.get_transactions().first().and_then(|tx| tx.vin.first());
+        if !coinbase.is_some_and(|vin| vin.pub_key.starts_with(params.genesis_data.as_bytes())) {
+            return Err(format_err!("the chain is not a {} one", params.name));
+        }
+
+        let mut bc = BlockChain::init(genesis, db, data_dir, params)?;
+        for block in blocks {
+            let hash = block.get_hash();
+            if block.get_prev_hash() != bc.current_hash {
+                return Err(format_err!("block {} does not extend {}", hash, bc.current_hash));
+            }
+            bc.add_block(block)
+                .map_err(|e| format_err!("cannot import block {}: {}", hash, e))?;
+        }
+        Ok(bc)
+    }
 
-        bc.db.flush()?;
+    // a new chain of `params` made of `genesis` in `db`, which must not hold
+    // one yet
+    fn init(
+        genesis: Block,
+        db: S,
+        data_dir: &str,
+        params: &NetworkParams,
+    ) -> Result<BlockChain<S>> {
+        if db.contains_key("LAST")? {
+            return Err(format_err!("a blockchain already exists in {}", data_dir));
+        }
+        db.insert("FORMAT", [FORMAT_ZSTD])?;
+        db.insert("NETWORK", params.name.as_bytes())?;
+        let mut bc = BlockChain {
+            current_hash: String::new(),
+            work: db.open_tree("work")?,
+            heights: db.open_tree("heights")?,
+            pruned: db.open_tree("pruned")?,
+            compressed: true,
+            checkpoints: BTreeMap::new(),
+            db,
+            data_dir: data_dir.to_string(),
+            params: *params,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        };
+        let mut batch = Batch::new();
+        let change = bc.stage_new_block(genesis, &mut batch)?;
+        bc.commit_tip(batch, &change)?;
         Ok(bc)
     }
-    pub fn mine_block(&mut self, txs: Vec<Transaction>) -> Result<Block> {
+
+    // mine a block with the given transactions, its coinbase pays the
+    // subsidy for the new height plus the fees to `miner`
+    pub fn mine_block(&mut self, miner: &str, txs: Vec<Transaction>) -> Result<Block> {
         info!("mine a new block");
 
+        let new_block = self.block_template(miner, txs)?.mine()?;
+
+        let mut batch = Batch::new();
+        let change = self.stage_new_block(new_block.clone(), &mut batch)?;
+        self.commit_tip(batch, &change)?;
+        Ok(new_block)
+    }
+
+    // store a block mined on top of the tip, it becomes the tip once the
+    // batch is committed
+    pub(crate) fn stage_new_block(&self, block: Block, batch: &mut Batch<S>) -> Result<TipChange> {
+        self.insert_block(&block, batch)?;
+        self.stage_tip(&block, batch);
+        Ok(TipChange {
+            disconnected: Vec::new(),
+            connected: vec![block],
+        })
+    }
+
+    // the next block on top of the tip before its proof of work, so it can be
+    // mined without holding on to the chain
+    pub fn block_template(&self, miner: &str, txs: Vec<Transaction>) -> Result<BlockTemplate> {
         for tx in &txs {
-            if !self.verify_transaction(&tx)? {
+            if !self.verify_transaction(tx)? {
                 return Err(format_err!("Transaction is not valid: {}", tx.id));
             }
         }
 
-        let last_hash = self.db.get("LAST")?.unwrap();
+        check_double_spends(&txs)?;
 
-        let new_block = Block::new_block(
-            txs,
-            String::from_utf8(last_hash.to_vec())?,
-            self.get_best_height()? + 1,
+        let height = self.get_best_height()? + 1;
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        if let Some(tx) = txs.iter().find(|tx| !tx.is_final(height, now)) {
+            return Err(format_err!(
+                "Transaction {} is locked until {}",
+                tx.id,
+                tx.lock_time
+            ));
+        }
+        let reward = block_subsidy(height) + self.get_fees(&txs)?;
+        let cb_tx = Transaction::new_coinbase_with_reward(
+            miner.to_string(),
+            String::new(),
+            reward,
+            &self.params,
         )?;
+        let mut transactions = vec![cb_tx];
+        transactions.extend(txs);
 
-        self.db
-            .insert(new_block.get_hash(), bincode::serialize(&new_block)?)?;
-        self.db.insert("LAST", new_block.get_hash().as_bytes())?;
+        Ok(BlockTemplate {
+            merkle_root: merkle_root(&transactions)?,
+            transactions,
+            prev_block_hash: self.current_hash.clone(),
+            height,
+            difficulty: self.get_difficulty(),
+        })
+    }
+
+    // leading hex zeros the hash of the next block must have
+    pub fn get_difficulty(&self) -> usize {
+        self.params.difficulty
+    }
+
+    // store a block and switch the best chain to it when it carries more work
+    // than the current tip, the returned change tells which blocks left and joined
+    pub fn add_block(&mut self, block: Block) -> Result<TipChange> {
+        let mut batch = Batch::new();
+        let change = self.stage_block(block, &mut batch)?;
+        self.commit_tip(batch, &change)?;
+        Ok(change)
+    }
+
+    // write the staged move of the best chain, the tip stays where it is
+    // until then
+    pub(crate) fn commit_tip(&mut self, batch: Batch<S>, change: &TipChange) -> Result<()> {
+        S::commit(batch)?;
         self.db.flush()?;
+        if let Some(tip) = change.connected.last() {
+            self.current_hash = tip.get_hash();
+        }
+        self.publish(&change.connected);
+        Ok(())
+    }
 
-        self.current_hash = new_block.get_hash();
-        Ok(new_block)
+    // receive an event for every block joining the best chain from now on,
+    // dropping the receiver unsubscribes
+    pub fn subscribe_blocks(&self) -> Receiver<BlockEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn publish(&self, blocks: &[Block]) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for block in blocks {
+            let event = BlockEvent {
+                hash: block.get_hash(),
+                height: block.get_height(),
+                tx_count: block.get_transactions().len(),
+            };
+            subscribers.retain(|s| s.send(event.clone()).is_ok());
+        }
+    }
+
+    // store a block, the writes switching the best chain to it when it carries
+    // more work than the current tip go to `batch`
+    pub(crate) fn stage_block(&self, block: Block, batch: &mut Batch<S>) -> Result<TipChange> {
+        if self.has_block(&block.get_hash())? {
+            return Ok(TipChange::default());
+        }
+        self.validate_block(&block)?;
+
+        let work = match self.insert_block(&block, batch)? {
+            Some(work) => work,
+            None => {
+                info!(
+                    "block {} has unknown parent {}, keep current tip",
+                    block.get_hash(),
+                    block.get_prev_hash()
+                );
+                return Ok(TipChange::default());
+            }
+        };
+
+        if work <= self.get_total_work()? {
+            return Ok(TipChange::default());
+        }
+
+        if block.get_prev_hash() == self.current_hash {
+            self.stage_tip(&block, batch);
+            return Ok(TipChange {
+                disconnected: Vec::new(),
+                connected: vec![block],
+            });
+        }
+        self.reorganize(block, batch)
+    }
+
+    // walk the stored chain from the tip down to the genesis block and return
+    // the first inconsistency found
+    pub fn validate_chain(&self) -> Result<()> {
+        let mut hash = self.current_hash.clone();
+        let mut height = self.get_best_height()?;
+        loop {
+            let block = self
+                .read_block(&hash)
+                .map_err(|e| format_err!("cannot read block {} at height {}: {}", hash, height, e))?;
+            if block.get_hash() != hash {
+                return Err(format_err!(
+                    "block {} is stored under {}",
+                    block.get_hash(),
+                    hash
+                ));
+            }
+            if block.get_height() != height {
+                return Err(format_err!(
+                    "block {} has height {}, expected {}",
+                    hash,
+                    block.get_height(),
+                    height
+                ));
+            }
+            if block.get_difficulty() != self.params.difficulty {
+                return Err(format_err!(
+                    "block {} has difficulty {}, expected {}",
+                    hash,
+                    block.get_difficulty(),
+                    self.params.difficulty
+                ));
+            }
+            if !block.verify_proof_of_work()? {
+                return Err(format_err!("block {} has an invalid proof of work", hash));
+            }
+            // only the header of a pruned block can be checked
+            if !self.pruned.contains_key(&hash)? {
+                self.validate_body(&block)?;
+            }
+
+            if block.get_prev_hash().is_empty() {
+                if height != 0 {
+                    return Err(format_err!("block {} at height {} has no parent", hash, height));
+                }
+                return Ok(());
+            }
+            hash = block.get_prev_hash();
+            height -= 1;
+        }
+    }
+
+    fn validate_body(&self, block: &Block) -> Result<()> {
+        let hash = block.get_hash();
+        if !block.verify_merkle_root()? {
+            return Err(format_err!("block {} has an invalid merkle root", hash));
+        }
+        for tx in block.get_transactions() {
+            let valid = self
+                .verify_transaction(tx)
+                .map_err(|e| format_err!("transaction {} in block {}: {}", tx.id, hash, e))?;
+            if !valid {
+                return Err(format_err!(
+                    "transaction {} in block {} has an invalid signature",
+                    tx.id,
+                    hash
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // reject a block that was not properly mined at the difficulty of the
+    // network or doesn't extend a known block
+    fn validate_block(&self, block: &Block) -> Result<()> {
+        if let Some(hash) = self.checkpoints.get(&block.get_height()) {
+            if *hash != block.get_hash() {
+                return Err(invalid_block(format!(
+                    "block {} doesn't match the checkpoint {} at height {}",
+                    block.get_hash(),
+                    hash,
+                    block.get_height()
+                )));
+            }
+        }
+        if block.get_difficulty() != self.params.difficulty {
+            return Err(invalid_block(format!(
+                "block {} has difficulty {}, expected {}",
+                block.get_hash(),
+                block.get_difficulty(),
+                self.params.difficulty
+            )));
+        }
+        if !block.verify_proof_of_work()? {
+            return Err(invalid_block(format!(
+                "block {} has an invalid proof of work",
+                block.get_hash()
+            )));
+        }
+        if !block.verify_merkle_root()? {
+            return Err(invalid_block(format!(
+                "block {} transactions don't match its merkle root",
+                block.get_hash()
+            )));
+        }
+        // the merkle root covers the transactions but not the ids the rest of
+        // the chain knows them by
+        for tx in block.get_transactions() {
+            if tx.id != tx.unsigned_hash()? {
+                return Err(invalid_block(format!(
+                    "transaction {} in block {} doesn't match its id",
+                    tx.id,
+                    block.get_hash()
+                )));
+            }
+        }
+        check_double_spends(block.get_transactions())
+            .map_err(|e| invalid_block(format!("block {}: {}", block.get_hash(), e)))?;
+        self.check_coinbase(block)?;
+        self.check_signatures(block)?;
+        check_lock_times(block)?;
+
+        let parent = match self.db.get(block.get_prev_hash())? {
+            Some(data) => self.decode_block(&data)?,
+            None => {
+                return Err(format_err!(
+                    "block {} references unknown parent {}",
+                    block.get_hash(),
+                    block.get_prev_hash()
+                ))
+            }
+        };
+        if block.get_height() != parent.get_height() + 1 {
+            return Err(invalid_block(format!(
+                "block {} has height {}, expected {}",
+                block.get_hash(),
+                block.get_height(),
+                parent.get_height() + 1
+            )));
+        }
+        Ok(())
     }
 
+    // the first transaction and only that one must be a coinbase, paying the
+    // subsidy plus the fees. the fees are looked up in the best chain, so they
+    // are only checked for blocks extending the tip
+    fn check_coinbase(&self, block: &Block) -> Result<()> {
+        let txs = block.get_transactions();
+        match txs.first() {
+            Some(tx) if tx.is_coinbase() => {}
+            _ => {
+                return Err(invalid_block(format!(
+                    "block {} doesn't start with a coinbase",
+                    block.get_hash()
+                )))
+            }
+        }
+        if txs.iter().skip(1).any(Transaction::is_coinbase) {
+            return Err(invalid_block(format!(
+                "block {} has more than one coinbase",
+                block.get_hash()
+            )));
+        }
+
+        if block.get_prev_hash() != self.current_hash {
+            return Ok(());
+        }
+        let fees = self
+            .get_fees(&txs[1..])
+            .map_err(|e| invalid_block(format!("block {}: {}", block.get_hash(), e)))?;
+        check_coinbase_value(block, fees)
+    }
 
-    pub fn add_block(&mut self, block: Block) -> Result<()> {
-        
-        if let Some(_) = self.db.get(block.get_hash())? {
+    // like the fees, the spent outputs are looked up in the best chain, so the
+    // signatures are only checked for blocks extending the tip. the utxo set
+    // checks the blocks of another branch when the chain switches to it
+    fn check_signatures(&self, block: &Block) -> Result<()> {
+        if block.get_prev_hash() != self.current_hash {
             return Ok(());
         }
-        let data = bincode::serialize(&block)?;
-        self.db.insert(block.get_hash(), data)?;
-        let last_height = self.get_best_height()?;
-        if block.get_height() > last_height {
-            self.db.insert("LAST", block.get_hash().as_bytes())?;
-            self.current_hash = block.get_hash();
-            self.db.flush()?;
+        for tx in block.get_transactions().iter().skip(1) {
+            let valid = self.verify_transaction(tx).map_err(|e| {
+                invalid_block(format!("transaction {} in block {}: {}", tx.id, block.get_hash(), e))
+            })?;
+            if !valid {
+                return Err(invalid_block(format!(
+                    "transaction {} in block {} has an invalid signature",
+                    tx.id,
+                    block.get_hash()
+                )));
+            }
         }
         Ok(())
     }
 
-    fn find_unspent_transactions(&self, address: &[u8]) -> Vec<Transaction> {
+    // switch to a branch that forks off below the current tip
+    fn reorganize(&self, tip: Block, batch: &mut Batch<S>) -> Result<TipChange> {
+        let best_chain: HashSet<String> = self.get_block_hashes().into_iter().collect();
+
+        // follow the new branch back until it joins the best chain
+        let mut connected = vec![tip.clone()];
+        let mut fork_hash = tip.get_prev_hash();
+        while !best_chain.contains(&fork_hash) {
+            match self.db.get(&fork_hash)? {
+                Some(data) => {
+                    let block = self.decode_block(&data)?;
+                    fork_hash = block.get_prev_hash();
+                    connected.push(block);
+                }
+                None => {
+                    info!(
+                        "block {} has unknown ancestor {}, keep current tip",
+                        tip.get_hash(),
+                        fork_hash
+                    );
+                    return Ok(TipChange::default());
+                }
+            }
+        }
+        connected.reverse();
+
+        let mut disconnected = Vec::new();
+        for block in self.iter() {
+            if block.get_hash() == fork_hash {
+                break;
+            }
+            disconnected.push(block);
+        }
+
+        // the best chain is fixed up to the deepest checkpoint it went through
+        let fork_height = connected[0].get_height() - 1;
+        let best_height = self.get_best_height()?;
+        if let Some((&height, _)) = self.checkpoints.range(..=best_height).next_back() {
+            if fork_height < height {
+                return Err(invalid_block(format!(
+                    "cannot reorganize at {}, below the checkpoint at height {}",
+                    fork_hash,
+                    height
+                )));
+            }
+        }
+
+        info!(
+            "reorganize at {}: {} blocks disconnected, {} connected",
+            fork_hash,
+            disconnected.len(),
+            connected.len()
+        );
+        for block in &disconnected {
+            // its spent transactions are gone, they cannot be undone
+            if self.pruned.contains_key(block.get_hash())? {
+                return Err(invalid_block(format!(
+                    "cannot reorganize at {}, block {} is pruned",
+                    fork_hash,
+                    block.get_hash()
+                )));
+            }
+            batch.remove(&self.heights, block.get_height().to_be_bytes());
+        }
+        for block in &connected {
+            batch.insert(&self.heights, block.get_height().to_be_bytes(), block.get_hash());
+        }
+        self.stage_tip(&tip, batch);
+        Ok(TipChange {
+            disconnected,
+            connected,
+        })
+    }
+
+    // store the block with the work of its chain, none while the parent is unknown
+    // the block and its chain work go to `batch`, along with the tip and the
+    // utxo set when it joins the best chain, so they are written at once
+    fn insert_block(&self, block: &Block, batch: &mut Batch<S>) -> Result<Option<u128>> {
+        let data = self.encode_block(block)?;
+        if self.compressed {
+            let size = bincode::serialized_size(block)?;
+            info!(
+                "block {} stored in {} bytes, {} uncompressed, ratio {:.2}",
+                block.get_hash(),
+                data.len(),
+                size,
+                size as f64 / data.len() as f64
+            );
+        }
+        batch.insert(&self.db, block.get_hash(), data);
+
+        let parent_work = if block.get_prev_hash().is_empty() {
+            Some(0)
+        } else {
+            self.get_chain_work(&block.get_prev_hash())?
+        };
+        let work = parent_work.map(|w| w + block.get_work());
+        if let Some(w) = work {
+            batch.insert(&self.work, block.get_hash(), w.to_be_bytes());
+        }
+        Ok(work)
+    }
+
+    // blocks at the given heights must have the given hashes, the best
+    // chain must agree with them already
+    pub fn set_checkpoints(&mut self, checkpoints: Vec<(i32, String)>) -> Result<()> {
+        let best_height = self.get_best_height()?;
+        for (height, hash) in &checkpoints {
+            if *height <= best_height && self.get_hash_by_height(*height)? != *hash {
+                return Err(format_err!(
+                    "the chain doesn't match the checkpoint {} at height {}",
+                    hash,
+                    height
+                ));
+            }
+        }
+        self.checkpoints = checkpoints.into_iter().collect();
+        Ok(())
+    }
+
+    // height up to which the blocks of the best chain are pruned
+    pub fn get_pruned_height(&self) -> Result<Option<i32>> {
+        match self.db.get("PRUNED")? {
+            Some(data) => {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(&data);
+                Ok(Some(i32::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_pruned(&self) -> Result<bool> {
+        Ok(self.get_pruned_height()?.is_some())
+    }
+
+    // prune the blocks of the best chain buried deeper than `depth`: the
+    // transactions whose outputs are all spent by the block or one below it are
+    // dropped, `take_spent` lists them as (txid, height) for each block. the
+    // headers stay so the chain can still be walked
+    pub(crate) fn prune(
+        &self,
+        depth: i32,
+        take_spent: impl Fn(&Block, &mut Batch<S>) -> Result<Vec<(String, i32)>>,
+    ) -> Result<()> {
+        let target = self.get_best_height()? - depth;
+        let start = self.get_pruned_height()?.map_or(0, |h| h + 1);
+        if start > target {
+            return Ok(());
+        }
+
+        for height in start..=target {
+            let block = self.read_block(&self.get_hash_by_height(height)?)?;
+            let mut batch = Batch::new();
+            let mut spent: HashMap<i32, HashSet<String>> = HashMap::new();
+            spent.entry(height).or_default();
+            for (txid, tx_height) in take_spent(&block, &mut batch)? {
+                spent.entry(tx_height).or_default().insert(txid);
+            }
+
+            for (tx_height, txids) in spent {
+                let mut holder = if tx_height == height {
+                    block.clone()
+                } else {
+                    self.read_block(&self.get_hash_by_height(tx_height)?)?
+                };
+                holder.retain_transactions(|tx| !txids.contains(&tx.id));
+                batch.insert(&self.db, holder.get_hash(), self.encode_block(&holder)?);
+            }
+            batch.insert(&self.pruned, block.get_hash(), b"");
+            batch.insert(&self.db, "PRUNED", height.to_be_bytes());
+            S::commit(batch)?;
+        }
+        info!("pruned blocks {} to {}", start, target);
+        self.db.flush()
+    }
+
+    fn get_chain_work(&self, block_hash: &str) -> Result<Option<u128>> {
+        match self.work.get(block_hash)? {
+            Some(data) => {
+                let mut bytes = [0; 16];
+                bytes.copy_from_slice(&data);
+                Ok(Some(u128::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // accumulated work of the best chain
+    pub fn get_total_work(&self) -> Result<u128> {
+        Ok(self.get_chain_work(&self.current_hash)?.unwrap_or(0))
+    }
+
+    // coins in existence: the outputs created by the best chain minus the ones
+    // it spends. the fees are only moved to the miners, so this is what the
+    // coinbases paid beyond them
+    pub fn total_supply(&self) -> Result<u64> {
+        if self.is_pruned()? {
+            return Err(format_err!("the supply of a pruned chain is unknown"));
+        }
+        let mut blocks: Vec<Block> = self.iter().collect();
+        blocks.reverse();
+
+        let mut outputs: HashMap<(String, i32), u64> = HashMap::new();
+        let mut supply: u64 = 0;
+        for block in &blocks {
+            for tx in block.get_transactions() {
+                if !tx.is_coinbase() {
+                    for vin in &tx.vin {
+                        let value = outputs
+                            .remove(&(vin.txid.clone(), vin.vout))
+                            .ok_or_else(|| {
+                                format_err!(
+                                    "transaction {} spends unknown output {}:{}",
+                                    tx.id,
+                                    vin.txid,
+                                    vin.vout
+                                )
+                            })?;
+                        supply -= value;
+                    }
+                }
+                for (index, out) in tx.vout.iter().enumerate() {
+                    outputs.insert((tx.id.clone(), index as i32), out.value);
+                    supply = supply
+                        .checked_add(out.value)
+                        .ok_or_else(|| format_err!("supply overflows in block {}", block.get_hash()))?;
+                }
+            }
+        }
+        Ok(supply)
+    }
+
+    pub fn chain_info(&self) -> Result<ChainInfo> {
+        Ok(ChainInfo {
+            tip: self.get_tip_hash(),
+            height: self.get_best_height()?,
+            blocks: self.get_block_hashes().len(),
+            difficulty: self.read_block(&self.current_hash)?.get_difficulty(),
+            total_work: self.get_total_work()?,
+            pruned_height: self.get_pruned_height()?,
+            utxo_count: None,
+            mempool_size: None,
+        })
+    }
+
+    fn stage_tip(&self, block: &Block, batch: &mut Batch<S>) {
+        batch.insert(&self.heights, block.get_height().to_be_bytes(), block.get_hash());
+        batch.insert(&self.db, "LAST", block.get_hash());
+    }
+
+    // transactions that still hold at least one unspent output owned by the address
+    pub fn find_unspent_transactions(&self, address: &[u8]) -> Vec<Transaction> {
         let mut spend_txos: HashMap<String, Vec<i32>> = HashMap::new();
         let mut unspend_txs: Vec<Transaction> = Vec::new();
 
+        // blocks are visited from the tip, so every spend of an output is
+        // recorded before the transaction that created it is reached
         for block in self.iter() {
             for tx in block.get_transactions() {
-                for index in 0..tx.vout.len() {
-                    if let Some(ids) = spend_txos.get(&tx.id) {
-                        if ids.contains(&(index as i32)) {
-                            continue;
-                        }
-                    }
-                    if tx.vout[index].can_be_unlock_with(address) {
-                        unspend_txs.push(tx.to_owned());
-                    }
+                let spent = spend_txos.get(&tx.id);
+                let has_unspent = tx.vout.iter().enumerate().any(|(index, out)| {
+                    let is_spent = spent.is_some_and(|ids| ids.contains(&(index as i32)));
+                    !is_spent && out.can_be_unlock_with(address)
+                });
+                if has_unspent {
+                    unspend_txs.push(tx.clone());
+                }
 
-                    if !tx.is_coinbase() {
-                        for i in &tx.vin {
-                            if i.can_unlock_output_with(address) {
-                                match spend_txos.get_mut(&i.txid) {
-                                    Some(v) => {
-                                        v.push(i.vout);
-                                    }
-                                    None => {
-                                        spend_txos.insert(i.txid.clone(), vec![i.vout]);
-                                    }
+                if !tx.is_coinbase() {
+                    for i in &tx.vin {
+                        if i.can_unlock_output_with(address) {
+                            match spend_txos.get_mut(&i.txid) {
+                                Some(v) => {
+                                    v.push(i.vout);
+                                }
+                                None => {
+                                    spend_txos.insert(i.txid.clone(), vec![i.vout]);
                                 }
                             }
                         }
@@ -152,19 +1079,15 @@ impl BlockChain {
                         }
                     }
 
-                    match utxos.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.outputs.push(tx.vout[index].clone());
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[index].clone()],
-                                },
-                            );
-                        }
-                    }
+                    utxos
+                        .entry(tx.id.clone())
+                        .or_insert_with(|| TXOutputs {
+                            outputs: HashMap::new(),
+                            height: block.get_height(),
+                            is_coinbase: tx.is_coinbase(),
+                        })
+                        .outputs
+                        .insert(index as i32, tx.vout[index].clone());
                 }
 
                 if !tx.is_coinbase() {
@@ -185,28 +1108,129 @@ impl BlockChain {
         utxos
     }
 
-    pub fn find_transaction(&self, id: &str) -> Result<Transaction> {
-        for block in self.iter() {
+    // (txid, height, net amount) of every transaction paying `pub_key_hash`
+    // or spending from it, newest first, the amount is negative for a spend
+    pub fn address_history(&self, pub_key_hash: &[u8]) -> Result<Vec<(String, i32, i64)>> {
+        // the spent transactions of pruned blocks are gone
+        if self.is_pruned()? {
+            return Err(format_err!("cannot list the history of a pruned chain"));
+        }
+        let mut blocks: Vec<Block> = self.iter().collect();
+        blocks.reverse();
+
+        // outputs paid to the address so far, the inputs spending them are
+        // found in later blocks
+        let mut received: HashMap<(String, i32), u64> = HashMap::new();
+        let mut history = Vec::new();
+        for block in &blocks {
             for tx in block.get_transactions() {
-                if tx.id == id {
-                    return Ok(tx.clone());
+                let mut net: i64 = 0;
+                let mut involved = false;
+                for vin in tx.vin.iter().filter(|_| !tx.is_coinbase()) {
+                    // matched by outpoint, the inputs of a multisig spend
+                    // carry no key
+                    if let Some(value) = received.remove(&(vin.txid.clone(), vin.vout)) {
+                        net -= value as i64;
+                        involved = true;
+                    }
                 }
+                for (index, out) in tx.vout.iter().enumerate() {
+                    if out.can_be_unlock_with(pub_key_hash) {
+                        received.insert((tx.id.clone(), index as i32), out.value);
+                        net += out.value as i64;
+                        involved = true;
+                    }
+                }
+                if involved {
+                    history.push((tx.id.clone(), block.get_height(), net));
+                }
+            }
+        }
+        history.reverse();
+        Ok(history)
+    }
+
+    pub fn find_transaction(&self, id: &str) -> Result<Transaction> {
+        Ok(self.find_transaction_block(id)?.0)
+    }
+
+    // the transaction together with the block of the best chain holding it
+    pub fn find_transaction_block(&self, id: &str) -> Result<(Transaction, Block)> {
+        self.lookup_transaction(id)
+            .ok_or_else(|| format_err!("Transaction is not found"))
+    }
+
+    fn lookup_transaction(&self, id: &str) -> Option<(Transaction, Block)> {
+        for block in self.iter() {
+            if let Some(tx) = block.get_transactions().iter().find(|tx| tx.id == id) {
+                return Some((tx.clone(), block));
             }
         }
-        Err(format_err!("Transaction is not found"))
+        None
+    }
+
+    // number of blocks of the best chain from the one holding the transaction
+    // up to the tip, 0 while it is not in the chain
+    pub fn get_confirmations(&self, id: &str) -> Result<i32> {
+        match self.lookup_transaction(id) {
+            Some((_, block)) => Ok(self.get_best_height()? - block.get_height() + 1),
+            None => Ok(0),
+        }
     }
 
-    pub fn sign_transaction(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
+    pub fn sign_transaction(
+        &self,
+        tx: &mut Transaction,
+        scheme: &dyn SignatureScheme,
+        private_key: &[u8],
+    ) -> Result<()> {
         let prev_txs = self.get_prev_txs(tx)?;
-        tx.sign(private_key, prev_txs)
+        tx.sign(scheme, private_key, prev_txs)
     }
 
     pub fn verify_transaction(&self, tx: &Transaction) -> Result<bool> {
+        // a coinbase spends nothing, there are no previous transactions to look up
+        if tx.is_coinbase() {
+            return Ok(true);
+        }
         let prev_txs = self.get_prev_txs(tx)?;
         tx.verify(prev_txs)
     }
 
-    fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+    // value of the inputs not claimed by the outputs, it goes to the miner
+    pub fn get_transaction_fee(&self, tx: &Transaction) -> Result<u64> {
+        if tx.is_coinbase() {
+            return Ok(0);
+        }
+        let prev_txs = self.get_prev_txs(tx)?;
+        let mut prev_outs = Vec::new();
+        for vin in &tx.vin {
+            match prev_txs[&vin.txid].vout.get(vin.vout as usize) {
+                Some(out) => prev_outs.push(out.clone()),
+                None => {
+                    return Err(format_err!(
+                        "transaction {} has no output {}",
+                        vin.txid,
+                        vin.vout
+                    ))
+                }
+            }
+        }
+        tx.fee(&prev_outs)
+    }
+
+    pub fn get_fees(&self, txs: &[Transaction]) -> Result<u64> {
+        let mut fees: u64 = 0;
+        for tx in txs {
+            fees = fees
+                .checked_add(self.get_transaction_fee(tx)?)
+                .ok_or_else(|| format_err!("fees overflow"))?;
+        }
+        Ok(fees)
+    }
+
+    // the transactions spent by the inputs of `tx`
+    pub fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
         let mut prev_txs = HashMap::<String, Transaction>::new();
         for v in &tx.vin {
             let prev_tx = self.find_transaction(&v.txid)?;
@@ -223,10 +1247,55 @@ impl BlockChain {
         list
     }
 
-    pub fn iter(&self) -> BlockChainIter {
+    // hashes of the best chain from the tip down, the first ten one by one
+    // and then twice as far apart each time, always ending with the genesis
+    pub fn get_block_locator(&self) -> Result<Vec<String>> {
+        let mut locator = Vec::new();
+        let mut height = self.get_best_height()?;
+        let mut step = 1;
+        while height > 0 {
+            locator.push(self.get_hash_by_height(height)?);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height -= step;
+        }
+        locator.push(self.get_hash_by_height(0)?);
+        Ok(locator)
+    }
+
+    // headers of the best chain after the first locator hash on it, at most
+    // `max` of them. nothing matching means the chains share no block
+    pub fn get_headers(&self, locator: &[String], max: usize) -> Result<Vec<BlockHeader>> {
+        let mut start = None;
+        for hash in locator {
+            if let Ok(block) = self.read_block(hash) {
+                if self.get_hash_by_height(block.get_height()).ok().as_ref() == Some(hash) {
+                    start = Some(block.get_height() + 1);
+                    break;
+                }
+            }
+        }
+        let start = match start {
+            Some(start) => start,
+            None => return Ok(Vec::new()),
+        };
+
+        let best_height = self.get_best_height()?;
+        let mut headers = Vec::new();
+        for height in start..=best_height {
+            if headers.len() >= max {
+                break;
+            }
+            headers.push(self.read_block(&self.get_hash_by_height(height)?)?.get_header());
+        }
+        Ok(headers)
+    }
+
+    pub fn iter(&self) -> BlockChainIter<'_, S> {
         BlockChainIter {
             current_hash: self.current_hash.clone(),
-            bc: &self,
+            bc: self,
         }
     }
 
@@ -238,18 +1307,18 @@ impl BlockChain {
         };
 
         let last_data = self.db.get(last_hash)?.unwrap();
-        let last_block: Block = bincode::deserialize(&last_data)?;
+        let last_block = self.decode_block(&last_data)?;
         Ok(last_block.get_height())
     }
 }
 
-impl<'a> Iterator for BlockChainIter<'a> {
+impl<'a, S: Storage> Iterator for BlockChainIter<'a, S> {
     type Item = Block;
     fn next(&mut self) -> Option<Self::Item> {
         if let Ok(encode_block) = self.bc.db.get(&self.current_hash) {
             return match encode_block {
                 Some(b) => {
-                    if let Ok(block) = bincode::deserialize::<Block>(&b) {
+                    if let Ok(block) = self.bc.decode_block(&b) {
                         self.current_hash = block.get_prev_hash();
                         Some(block)
                     } else {
@@ -262,3 +1331,471 @@ impl<'a> Iterator for BlockChainIter<'a> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::REGTEST;
+    use crate::test_utils::{chain, coinbase, mine, mine_at, pub_key_hash, utxo_chain, wallet};
+    use crate::utxoset::CoinSelection;
+
+    #[test]
+    fn a_block_with_a_tampered_nonce_is_refused() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let mut bc = chain(&address)?;
+        let genesis = bc.get_block(&bc.get_tip_hash())?;
+        let block = mine(&address, Vec::new(), &genesis)?;
+
+        let mut value = serde_json::to_value(&block)?;
+        value["nonce"] = (block.get_nonce() + 1).into();
+        let tampered: Block = serde_json::from_value(value)?;
+        let e = bc.add_block(tampered).unwrap_err();
+        assert!(e.to_string().contains("invalid proof of work"), "{}", e);
+        assert!(!bc.has_block(&block.get_hash())?);
+        assert_eq!(bc.get_tip_hash(), genesis.get_hash());
+        Ok(())
+    }
+
+    #[test]
+    fn a_block_with_an_unknown_parent_is_refused() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let mut bc = chain(&address)?;
+        let genesis = bc.get_block(&bc.get_tip_hash())?;
+        let txs = vec![coinbase(&address, 1)?];
+        let orphan = mine_at(txs, "00bogus".to_string(), 1, REGTEST.difficulty)?;
+
+        let e = bc.add_block(orphan.clone()).unwrap_err();
+        assert!(e.to_string().contains("unknown parent"), "{}", e);
+        assert!(!bc.has_block(&orphan.get_hash())?);
+        assert_eq!(bc.get_tip_hash(), genesis.get_hash());
+        Ok(())
+    }
+
+    #[test]
+    fn a_block_below_the_network_difficulty_is_refused() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let mut bc = chain(&address)?;
+        let genesis = bc.get_block(&bc.get_tip_hash())?;
+        let easy = mine_at(vec![coinbase(&address, 1)?], genesis.get_hash(), 1, 0)?;
+
+        let e = bc.add_block(easy.clone()).unwrap_err();
+        assert!(e.to_string().contains("difficulty 0"), "{}", e);
+        assert!(!bc.has_block(&easy.get_hash())?);
+        Ok(())
+    }
+
+    #[test]
+    fn a_block_with_a_transaction_under_another_id_is_refused() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let mut bc = chain(&address)?;
+        let genesis = bc.get_block(&bc.get_tip_hash())?;
+        // the merkle root leaves the ids out, the block is mined as it is
+        let mut renamed = coinbase(&address, 1)?;
+        renamed.id = genesis.get_transactions()[0].id.clone();
+        let block = mine_at(vec![renamed], genesis.get_hash(), 1, REGTEST.difficulty)?;
+        assert!(block.verify_merkle_root()?);
+
+        let e = bc.add_block(block.clone()).unwrap_err();
+        assert!(e.to_string().contains("doesn't match its id"), "{}", e);
+        assert!(!bc.has_block(&block.get_hash())?);
+        Ok(())
+    }
+
+    #[test]
+    fn chains_in_separate_dirs_do_not_interfere() -> Result<()> {
+        let dirs = (tempfile::tempdir()?, tempfile::tempdir()?);
+        let first = dirs.0.path().to_str().unwrap();
+        let second = dirs.1.path().to_str().unwrap();
+        let address = wallet(1).get_address(&REGTEST);
+        let mut one = BlockChain::create_blockchain(address.clone(), first, &REGTEST)?;
+        let payee = wallet(2).get_address(&REGTEST);
+        let other = BlockChain::create_blockchain(payee, second, &REGTEST)?;
+        one.mine_block(&address, Vec::new())?;
+        assert_eq!(one.get_best_height()?, 1);
+        assert_eq!(other.get_best_height()?, 0);
+        let tips = (one.get_tip_hash(), other.get_tip_hash());
+        drop((one, other));
+
+        // each directory kept its own chain
+        let (one, other) = (BlockChain::new(first)?, BlockChain::new(second)?);
+        assert_eq!((one.get_tip_hash(), other.get_tip_hash()), tips);
+        assert_eq!(other.get_best_height()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn a_shorter_chain_of_harder_blocks_has_more_work() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        // every block of a network has its difficulty, a shorter chain can
+        // only outweigh a longer one of an easier network
+        let harder = NetworkParams {
+            difficulty: REGTEST.difficulty + 1,
+            ..REGTEST
+        };
+        let mut short = BlockChain::create_in_memory(address.clone(), &harder)?;
+        short.mine_block(&address, Vec::new())?;
+        let mut long = chain(&address)?;
+        for _ in 0..3 {
+            long.mine_block(&address, Vec::new())?;
+        }
+        assert!(short.get_best_height()? < long.get_best_height()?);
+        assert_eq!(short.get_total_work()?, 2 * 256);
+        assert_eq!(long.get_total_work()?, 4 * 16);
+        Ok(())
+    }
+
+    #[test]
+    fn a_block_spending_an_output_twice_is_refused() -> Result<()> {
+        let miner = wallet(1);
+        let address = miner.get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        let genesis = utxo.blockchain.get_block(&utxo.blockchain.get_tip_hash())?;
+        // both spend the genesis coinbase, each one is valid on its own
+        let first = Transaction::new_utxo(&miner, &wallet(2).get_address(&REGTEST), 10, &utxo)?;
+        let second = Transaction::new_utxo(&miner, &wallet(3).get_address(&REGTEST), 20, &utxo)?;
+        assert!(utxo.blockchain.verify_transaction(&second)?);
+
+        let txs = vec![first, second];
+        let e = utxo.mine_block(&address, txs.clone()).unwrap_err();
+        assert!(e.to_string().contains("spent twice"), "{}", e);
+        let block = mine(&address, txs, &genesis)?;
+        let e = utxo.add_block(block.clone()).unwrap_err();
+        assert!(e.to_string().contains("spent twice"), "{}", e);
+        assert!(!utxo.blockchain.has_block(&block.get_hash())?);
+        assert_eq!(utxo.blockchain.get_tip_hash(), genesis.get_hash());
+        Ok(())
+    }
+
+    #[test]
+    fn a_block_needs_a_single_coinbase_paying_the_subsidy() -> Result<()> {
+        let miner = wallet(1);
+        let address = miner.get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        let genesis = utxo.blockchain.get_tip_hash();
+        let payment = Transaction::new_utxo(&miner, &wallet(2).get_address(&REGTEST), 10, &utxo)?;
+        let mut inflated = coinbase(&address, 1)?;
+        inflated.vout[0].value += 1;
+        inflated.id = inflated.unsigned_hash()?;
+
+        let cases = [
+            (vec![payment], "doesn't start with a coinbase"),
+            (vec![coinbase(&address, 1)?, coinbase(&address, 1)?], "more than one coinbase"),
+            (vec![inflated], "expected 100"),
+        ];
+        for (txs, reason) in cases {
+            let block = mine_at(txs, genesis.clone(), 1, REGTEST.difficulty)?;
+            let e = utxo.add_block(block).unwrap_err();
+            assert!(e.to_string().contains(reason), "{}", e);
+            assert_eq!(utxo.blockchain.get_tip_hash(), genesis);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn a_transaction_is_only_mined_from_its_lock_height_on() -> Result<()> {
+        let miner = wallet(1);
+        let address = miner.get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        let outputs = [(wallet(2).get_address(&REGTEST), 10)];
+        let selection = CoinSelection::default();
+        let mut tx =
+            Transaction::create_raw_transaction(&miner.public_key, &outputs, 0, selection, &utxo)?;
+        tx.set_lock_time(3)?;
+        utxo.blockchain.sign_transaction(&mut tx, miner.scheme()?, &miner.secret_key)?;
+        assert!(utxo.blockchain.verify_transaction(&tx)?);
+
+        for height in 1..3 {
+            let tip = utxo.blockchain.get_block(&utxo.blockchain.get_tip_hash())?;
+            let locked = mine(&address, vec![tx.clone()], &tip)?;
+            let e = utxo.add_block(locked).unwrap_err();
+            assert!(e.to_string().contains("locked until 3"), "{}", e);
+            assert_eq!(utxo.blockchain.get_best_height()?, height - 1);
+            utxo.mine_block(&address, Vec::new())?;
+        }
+        let tip = utxo.blockchain.get_block(&utxo.blockchain.get_tip_hash())?;
+        utxo.add_block(mine(&address, vec![tx.clone()], &tip)?)?;
+        assert_eq!(utxo.blockchain.get_best_height()?, 3);
+        assert_eq!(utxo.blockchain.find_transaction(&tx.id)?.id, tx.id);
+        Ok(())
+    }
+
+    #[test]
+    fn a_transaction_paying_an_address_twice_is_found_once() -> Result<()> {
+        let miner = wallet(1);
+        let address = miner.get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        let payee = wallet(2);
+        let outputs = [
+            (payee.get_address(&REGTEST), 10),
+            (payee.get_address(&REGTEST), 20),
+        ];
+        let tx = Transaction::new_utxo_many(&miner, &outputs, &utxo)?;
+        utxo.mine_block(&address, vec![tx.clone()])?;
+
+        let unspent = utxo.blockchain.find_unspent_transactions(&pub_key_hash(&payee));
+        assert_eq!(unspent.len(), 1);
+        assert_eq!(unspent[0].id, tx.id);
+        Ok(())
+    }
+
+    #[test]
+    fn opening_a_directory_without_a_chain_is_an_error() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let e = BlockChain::new(dir.path().to_str().unwrap()).unwrap_err();
+        assert!(e.to_string().contains("no blockchain found"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn blocks_are_found_by_height_on_the_best_chain() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let mut bc = chain(&address)?;
+        let genesis = bc.get_block(&bc.get_tip_hash())?;
+        let mut mined = vec![genesis.clone()];
+        for _ in 0..3 {
+            mined.push(bc.mine_block(&address, Vec::new())?);
+        }
+        for (height, block) in mined.iter().enumerate() {
+            assert_eq!(bc.get_block_by_height(height as i32)?.get_hash(), block.get_hash());
+        }
+        assert!(bc.get_block_by_height(4).is_err());
+
+        // a heavier branch from the genesis block takes the heights over
+        let other = wallet(2).get_address(&REGTEST);
+        let mut branch = vec![genesis];
+        for _ in 0..4 {
+            let block = mine(&other, Vec::new(), branch.last().unwrap())?;
+            bc.add_block(block.clone())?;
+            branch.push(block);
+        }
+        assert_eq!(bc.get_tip_hash(), branch[4].get_hash());
+        for (height, block) in branch.iter().enumerate() {
+            assert_eq!(bc.get_block_by_height(height as i32)?.get_hash(), block.get_hash());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn an_exported_chain_imports_into_a_fresh_dir() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let address = wallet(1).get_address(&REGTEST);
+        let mut bc = chain(&address)?;
+        for _ in 0..4 {
+            bc.mine_block(&address, Vec::new())?;
+        }
+        let mut json = Vec::new();
+        bc.export_json(&mut json)?;
+
+        let imported = BlockChain::import_json(&json[..], dir.path().to_str().unwrap(), &REGTEST)?;
+        assert_eq!(imported.get_tip_hash(), bc.get_tip_hash());
+        assert_eq!(imported.get_best_height()?, 4);
+        assert_eq!(imported.get_block_hashes(), bc.get_block_hashes());
+        Ok(())
+    }
+
+    #[test]
+    fn a_corrupted_block_fails_validation_at_that_block() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let mut bc = chain(&address)?;
+        for _ in 0..4 {
+            bc.mine_block(&address, Vec::new())?;
+        }
+        bc.validate_chain()?;
+
+        let block = bc.get_block_by_height(2)?;
+        let mut value = serde_json::to_value(&block)?;
+        value["nonce"] = (block.get_nonce() + 1).into();
+        let corrupted: Block = serde_json::from_value(value)?;
+        bc.db.insert(block.get_hash(), bc.encode_block(&corrupted)?)?;
+
+        let e = bc.validate_chain().unwrap_err().to_string();
+        assert!(e.contains(&block.get_hash()), "{}", e);
+        assert!(e.contains("invalid proof of work"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn confirmations_grow_with_each_block_mined() -> Result<()> {
+        let miner = wallet(1).get_address(&REGTEST);
+        let mut utxo = utxo_chain(&miner)?;
+        let tx = Transaction::new_utxo(&wallet(1), &wallet(2).get_address(&REGTEST), 30, &utxo)?;
+        assert_eq!(utxo.blockchain.get_confirmations(&tx.id)?, 0);
+
+        utxo.mine_block(&miner, vec![tx.clone()])?;
+        assert_eq!(utxo.blockchain.get_confirmations(&tx.id)?, 1);
+        for _ in 0..3 {
+            utxo.mine_block(&miner, Vec::new())?;
+        }
+        assert_eq!(utxo.blockchain.get_confirmations(&tx.id)?, 4);
+        assert_eq!(utxo.blockchain.get_confirmations("00unknown")?, 0);
+        Ok(())
+    }
+
+    // the names in the working directory, where a relative data directory
+    // would be created
+    fn working_dir_entries() -> Result<Vec<std::ffi::OsString>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(".")? {
+            names.push(entry?.file_name());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    #[test]
+    fn a_chain_in_memory_storage_never_touches_the_disk() -> Result<()> {
+        let before = working_dir_entries()?;
+        let regtest_dir = std::path::Path::new(REGTEST.data_dir).exists();
+        let miner = wallet(1).get_address(&REGTEST);
+        let mut utxo = utxo_chain(&miner)?;
+        let tx = Transaction::new_utxo(&wallet(1), &wallet(2).get_address(&REGTEST), 10, &utxo)?;
+        let block = utxo.mine_block(&miner, vec![tx.clone()])?;
+        utxo.mine_block(&miner, Vec::new())?;
+        utxo.reindex()?;
+        utxo.blockchain.validate_chain()?;
+        assert_eq!(utxo.blockchain.find_transaction(&tx.id)?.id, tx.id);
+        assert_eq!(utxo.blockchain.get_block_by_height(1)?.get_hash(), block.get_hash());
+
+        // a copy goes through export and import, in memory as well
+        let mut json = Vec::new();
+        utxo.blockchain.export_json(&mut json)?;
+        let db = MemoryStorage::new();
+        let copy = BlockChain::import_json_with_storage(&json[..], db, "", &REGTEST)?;
+        assert_eq!(copy.get_tip_hash(), utxo.blockchain.get_tip_hash());
+        assert_eq!(copy.get_data_dir(), "");
+
+        assert_eq!(working_dir_entries()?, before);
+        assert_eq!(std::path::Path::new(REGTEST.data_dir).exists(), regtest_dir);
+        Ok(())
+    }
+
+    #[test]
+    fn create_in_memory_mines_without_writing_files() -> Result<()> {
+        let before = working_dir_entries()?;
+        let address = wallet(1).get_address(&REGTEST);
+        let mut bc = BlockChain::create_in_memory(address.clone(), &REGTEST)?;
+        assert_eq!(bc.get_data_dir(), "");
+        let genesis = bc.get_tip_hash();
+
+        let block = bc.mine_block(&address, Vec::new())?;
+        assert_eq!(bc.get_tip_hash(), block.get_hash());
+        assert_eq!(block.get_prev_hash(), genesis);
+        assert_eq!(bc.get_best_height()?, 1);
+        assert_eq!(working_dir_entries()?, before);
+        Ok(())
+    }
+
+    #[test]
+    fn chain_info_follows_the_mined_blocks() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        let info = utxo.chain_info()?;
+        assert_eq!((info.height, info.blocks, info.utxo_count), (0, 1, Some(1)));
+        let genesis_work = info.total_work;
+
+        let mut last = None;
+        for _ in 0..3 {
+            last = Some(utxo.mine_block(&address, Vec::new())?);
+        }
+        let info = utxo.chain_info()?;
+        assert_eq!(info.tip, last.unwrap().get_hash());
+        assert_eq!(info.tip, utxo.blockchain.get_tip_hash());
+        assert_eq!((info.height, info.blocks, info.utxo_count), (3, 4, Some(4)));
+        assert_eq!(info.difficulty, REGTEST.difficulty);
+        assert!(info.total_work > genesis_work);
+        assert_eq!(info.pruned_height, None);
+        assert_eq!(utxo.blockchain.chain_info()?.utxo_count, None);
+        Ok(())
+    }
+
+    #[test]
+    fn the_supply_is_the_sum_of_the_subsidies() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        assert_eq!(utxo.blockchain.total_supply()?, block_subsidy(0));
+
+        // fees only move coins, they add nothing
+        let payee = wallet(2).get_address(&REGTEST);
+        let tx = Transaction::new_utxo_with_fee(&wallet(1), &payee, 30, 7, &utxo)?;
+        utxo.mine_block(&address, vec![tx])?;
+        for _ in 0..4 {
+            utxo.mine_block(&address, Vec::new())?;
+        }
+        let subsidies: u64 = (0..=5).map(block_subsidy).sum();
+        assert_eq!(utxo.blockchain.total_supply()?, subsidies);
+        assert_eq!(subsidies, crate::transaction::expected_supply(5));
+        Ok(())
+    }
+
+    #[test]
+    fn blocks_are_compressed_and_legacy_ones_still_read() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let mut bc = chain(&address)?;
+        let block = bc.mine_block(&address, Vec::new())?;
+        let plain = bincode::serialize(&block)?;
+        let stored = bc.get_storage().get(block.get_hash())?.unwrap();
+        assert_ne!(stored, plain);
+        assert_eq!(zstd::decode_all(&stored[..])?, plain);
+        assert_eq!(bincode::serialize(&bc.get_block(&block.get_hash())?)?, plain);
+
+        // a chain written before compression has no FORMAT key
+        let db = MemoryStorage::new();
+        db.insert(block.get_hash(), &plain)?;
+        db.insert("LAST", block.get_hash())?;
+        db.insert("NETWORK", REGTEST.name)?;
+        let legacy = BlockChain::with_storage(db, "")?;
+        assert!(!legacy.compressed);
+        assert_eq!(legacy.get_tip_hash(), block.get_hash());
+        assert_eq!(bincode::serialize(&legacy.get_block(&block.get_hash())?)?, plain);
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoints_hold_the_chain_in_place() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let other = wallet(2).get_address(&REGTEST);
+        let mut bc = chain(&address)?;
+        let genesis = bc.get_block(&bc.get_tip_hash())?;
+        let main = bc.mine_block(&address, Vec::new())?;
+        // a fork of the same work is stored without becoming the tip
+        let fork = mine(&other, Vec::new(), &genesis)?;
+        bc.add_block(fork.clone())?;
+        assert_eq!(bc.get_tip_hash(), main.get_hash());
+
+        assert!(bc.set_checkpoints(vec![(1, fork.get_hash())]).is_err());
+        bc.set_checkpoints(vec![(1, main.get_hash()), (3, "00later".to_string())])?;
+
+        let e = bc.add_block(mine(&other, Vec::new(), &genesis)?).unwrap_err();
+        assert!(e.to_string().contains("doesn't match the checkpoint"), "{}", e);
+        // the fork would now outweigh the best chain, below the checkpoint
+        let e = bc.add_block(mine(&other, Vec::new(), &fork)?).unwrap_err();
+        assert!(e.to_string().contains("below the checkpoint at height 1"), "{}", e);
+        assert_eq!(bc.get_tip_hash(), main.get_hash());
+
+        let next = bc.mine_block(&address, Vec::new())?;
+        let e = bc.add_block(mine(&address, Vec::new(), &next)?).unwrap_err();
+        assert!(e.to_string().contains("doesn't match the checkpoint 00later"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn the_history_of_an_address_lists_receipts_and_spends_newest_first() -> Result<()> {
+        let miner = wallet(1).get_address(&REGTEST);
+        let mut utxo = utxo_chain(&miner)?;
+        let payee = wallet(2);
+        let other = wallet(3).get_address(&REGTEST);
+        let received = Transaction::new_utxo(&wallet(1), &payee.get_address(&REGTEST), 40, &utxo)?;
+        utxo.mine_block(&miner, vec![received.clone()])?;
+        utxo.mine_block(&miner, Vec::new())?;
+        // 25 leave, 15 come back as change
+        let spent = Transaction::new_utxo(&payee, &other, 25, &utxo)?;
+        utxo.mine_block(&miner, vec![spent.clone()])?;
+
+        let history = utxo.blockchain.address_history(&pub_key_hash(&payee))?;
+        assert_eq!(history, vec![(spent.id.clone(), 3, -25), (received.id.clone(), 1, 40)]);
+        let history = utxo.blockchain.address_history(&pub_key_hash(&wallet(3)))?;
+        assert_eq!(history, vec![(spent.id, 3, 25)]);
+        assert!(utxo.blockchain.address_history(&pub_key_hash(&wallet(4)))?.is_empty());
+        Ok(())
+    }
+}
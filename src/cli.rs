@@ -1,14 +1,29 @@
 use crate::errors::Result;
-use crate::server::Server;
-use crate::utxoset::Utxoset;
-use crate::wallet::Wallets;
-use crate::{blockchain::BlockChain, transaction::Transaction};
-use bitcoincash_addr::Address;
-use clap::{arg, Command};
-use log::info;
-use std::fs::remove_dir_all;
+use crate::server::{Server, ServerConfig};
+use crate::signature::{Ed25519Scheme, ED25519};
+use crate::storage::Storage;
+use crate::tx::Multisig;
+use crate::utxoset::{CoinSelection, Utxoset};
+use crate::wallet::{hash_pub_key, verify_message, Wallet, Wallets};
+use crate::blockchain::BlockChain;
+use crate::config::Config;
+use crate::network::{NetworkParams, MAINNET, REGTEST};
+use crate::transaction::{expected_supply, Transaction};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use clap::{arg, ArgMatches, Command};
+use failure::format_err;
+use tracing::info;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{remove_dir_all, File};
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
 use std::process::exit;
 
+// the port the commands handing a transaction to the seeds announce, they
+// don't listen on it
+const SENDER_PORT: &str = "7000";
+
 pub struct Cli {}
 
 impl Cli {
@@ -17,131 +32,361 @@ impl Cli {
     }
 
     pub fn run(&mut self) -> Result<()> {
-        let matches = Command::new("blockchain-rust-demo")
-            .version("0.1")
-            .author("bllock.f.zr@gmail.com")
-            .about("blockchain in rust: a simple blockchain for learning")
-            .subcommand(Command::new("printchain").about("print all the chain blocks"))
-            .subcommand(Command::new("createwallet").about("create a wallet"))
-            .subcommand(Command::new("listaddress").about("list all wallet address"))
-            .subcommand(Command::new("reindex").about("re index"))
-            .subcommand(
-                Command::new("getbalance")
-                    .about("get balance in the blockchain")
-                    .arg(arg!(<ADDRESS>"'The Address it get balance for'")),
-            )
-            .subcommand(
-                Command::new("create")
-                    .about("Create new blockchain")
-                    .arg(arg!(<ADDRESS>"'The address to send genesis block reward to' ")),
-            )
-            .subcommand(
-                Command::new("send")
-                    .about("send in a blockchain")
-                    .arg(arg!(<From>"'Source wallet address'"))
-                    .arg(arg!(<To>"'Target wallet address'"))
-                    .arg(arg!(<Amount>"'Amount to transfer'")),
-            )
-            .subcommand(
-                Command::new("startnode")
-                    .about("start the node server")
-                    .arg(arg!(<PORT>"'the port server bind to locally'")),
-            )
-            .subcommand(
-                Command::new("startminer")
-                    .about("start the minner server")
-                    .arg(arg!(<PORT>" 'the port server bind to locally'"))
-                    .arg(arg!(<ADDRESS>" 'wallet address'")),
-            )
-            .get_matches();
-
-        if let Some(ref matches) = matches.subcommand_matches("getbalance") {
+        self.run_from(std::env::args_os())
+    }
+
+    // like run, with `args` in place of the arguments of the process
+    fn run_from<I, T>(&mut self, args: I) -> Result<()>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = command().get_matches_from(args);
+        let passphrase = matches.get_one::<String>("passphrase").map(String::as_str);
+        let ctx = &Context::from_matches(&matches)?;
+        let params = &ctx.params;
+
+        if let Some(matches) = matches.subcommand_matches("getbalance") {
             if let Some(c) = matches.get_one::<String>("ADDRESS") {
-                let bc = BlockChain::new()?;
+                let bc = ctx.open_chain()?;
                 let address = String::from(c);
-                let pub_key_hash = Address::decode(&address).unwrap().body;
-                let utxo_set = Utxoset { blockchain: bc };
+                let pub_key_hash = params.decode_address(&address)?;
+                let utxo_set = Utxoset::open(bc)?;
                 let utxos = utxo_set.find_utxo(&pub_key_hash)?;
-                let mut balance = 0;
-                for item in utxos.outputs {
+                let mut balance: u64 = 0;
+                for item in utxos {
                     balance += item.value;
                 }
                 println!("Balance of {}; {}", address, balance);
             }
         }
 
+        if let Some(matches) = matches.subcommand_matches("generate") {
+            let count: usize = matches.get_one::<String>("COUNT").unwrap().parse()?;
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            cmd_generate(ctx, count, address)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("richlist") {
+            let count: usize = matches.get_one::<String>("COUNT").unwrap().parse()?;
+            cmd_rich_list(ctx, count)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("listutxo") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                let pub_key_hash = params.decode_address(address)?;
+                let utxo_set = Utxoset::open(ctx.open_chain()?)?;
+                for (txid, vout, out) in utxo_set.list_unspent(&pub_key_hash)? {
+                    println!("{}:{} {}", txid, vout, out.value);
+                }
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("gettxout") {
+            let txid = matches.get_one::<String>("TXID").unwrap();
+            let vout: i32 = matches.get_one::<String>("VOUT").unwrap().parse()?;
+            let utxo_set = Utxoset::open(ctx.open_chain()?)?;
+            match utxo_set.get_txout(txid, vout)? {
+                Some(out) => println!("{}:{} unspent, value {}", txid, vout, out.value),
+                None => println!("{}:{} spent or nonexistent", txid, vout),
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getconfirmations") {
+            if let Some(txid) = matches.get_one::<String>("TXID") {
+                let bc = ctx.open_chain()?;
+                println!("{}", bc.get_confirmations(txid)?);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getaddresshistory") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                let pub_key_hash = params.decode_address(address)?;
+                let bc = ctx.open_chain()?;
+                for (txid, height, amount) in bc.address_history(&pub_key_hash)? {
+                    println!("{} height {} {:+}", txid, height, amount);
+                }
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("decoderawtransaction") {
+            if let Some(data) = matches.get_one::<String>("HEX") {
+                cmd_decode_raw_transaction(ctx, data)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createrawtransaction") {
+            let from = matches.get_one::<String>("FROM").unwrap();
+            let mut outputs = Vec::new();
+            if let Some(values) = matches.get_many::<String>("OUTPUTS") {
+                for value in values {
+                    outputs.push(parse_output(value)?);
+                }
+            }
+            let fee: u64 = matches.get_one::<String>("fee").unwrap().parse()?;
+            cmd_create_raw_transaction(ctx, from, &outputs, fee, passphrase)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createmultisig") {
+            let multisig = parse_multisig(params, matches)?;
+            println!("address: {}", params.encode_address(&multisig.hash()?));
+        }
+
+        if let Some(matches) = matches.subcommand_matches("sendmultisig") {
+            let from = matches.get_one::<String>("FROM").unwrap();
+            let amount: u64 = matches.get_one::<String>("AMOUNT").unwrap().parse()?;
+            let fee: u64 = matches.get_one::<String>("fee").unwrap().parse()?;
+            let multisig = parse_multisig(params, matches)?;
+            println!("multisig address: {}", params.encode_address(&multisig.hash()?));
+            cmd_send_multisig(ctx, from, multisig, amount, fee, matches, passphrase)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("signrawtransaction") {
+            if let Some(data) = matches.get_one::<String>("HEX") {
+                cmd_sign_raw_transaction(ctx, data, passphrase)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("sendrawtransaction") {
+            if let Some(data) = matches.get_one::<String>("HEX") {
+                cmd_send_raw_transaction(ctx, data, &sender_config(ctx, matches))?;
+            }
+        }
+
         if let Some(matches) = matches.subcommand_matches("create") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                cmd_create_blockchain(address)?;
+                cmd_create_blockchain(ctx, address)?;
             }
         }
 
-        if let Some(_) = matches.subcommand_matches("createwallet") {
-            let mut ws = Wallets::new()?;
-            let address = ws.create_wallet();
+        if let Some(matches) = matches.subcommand_matches("createwallet") {
+            let mut ws = ctx.open_wallets(passphrase)?;
+            let address = if matches.get_flag("mnemonic") {
+                let (wallet, phrase) = Wallet::new_with_mnemonic()?;
+                println!("mnemonic: {}", phrase);
+                ws.add_wallet(wallet)
+            } else {
+                ws.create_wallet()
+            };
+            ws.save_all()?;
+            println!("success: address {}", address);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("recoverwallet") {
+            let words: Vec<&str> = matches
+                .get_many::<String>("MNEMONIC")
+                .unwrap()
+                .map(String::as_str)
+                .collect();
+            let seed_passphrase = matches.get_one::<String>("mnemonic-passphrase").unwrap();
+            let wallet = Wallet::from_mnemonic(&words.join(" "), seed_passphrase)?;
+            let mut ws = ctx.open_wallets(passphrase)?;
+            let address = ws.add_wallet(wallet);
             ws.save_all()?;
             println!("success: address {}", address);
         }
 
-        if let Some(_) = matches.subcommand_matches("listaddress") {
-            let ws = Wallets::new()?;
+        if let Some(matches) = matches.subcommand_matches("dumpprivkey") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                let ws = ctx.open_wallets(passphrase)?;
+                println!("{}", ws.get_spendable_wallet(address)?.export_key()?);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("importprivkey") {
+            if let Some(key) = matches.get_one::<String>("KEY") {
+                let mut ws = ctx.open_wallets(passphrase)?;
+                let address = ws.import_key(key)?;
+                ws.save_all()?;
+                println!("success: address {}", address);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("importaddress") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                let mut ws = ctx.open_wallets(passphrase)?;
+                ws.add_watch_only(address)?;
+                ws.save_all()?;
+                println!("success: watching {}", address);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("signmessage") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let message = matches.get_one::<String>("MESSAGE").unwrap();
+            cmd_sign_message(ctx, address, message, passphrase)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("verifymessage") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let message = matches.get_one::<String>("MESSAGE").unwrap();
+            let signature = matches.get_one::<String>("SIGNATURE").unwrap();
+            if cmd_verify_message(ctx, address, message, signature)? {
+                println!("valid signature");
+            } else {
+                println!("invalid signature");
+                exit(1);
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("deletewallet") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                cmd_delete_wallet(ctx, address, matches.get_flag("force"), passphrase)?;
+            }
+        }
+
+        if matches.subcommand_matches("listaddress").is_some() {
+            let ws = ctx.open_wallets(passphrase)?;
             let addresses = ws.get_all_wallets();
             for addr in addresses {
-                println!("{}", addr);
+                match ws.get_label(&addr) {
+                    Some(label) => println!("{} {}", addr, label),
+                    None => println!("{}", addr),
+                }
+            }
+            for addr in ws.get_watch_only() {
+                match ws.get_label(&addr) {
+                    Some(label) => println!("{} {} (watch-only)", addr, label),
+                    None => println!("{} (watch-only)", addr),
+                }
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("setlabel") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let label = matches.get_one::<String>("LABEL").unwrap();
+            let mut ws = ctx.open_wallets(passphrase)?;
+            ws.set_label(address, label)?;
+            ws.save_all()?;
+            println!("success!!!");
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getaddressbylabel") {
+            if let Some(label) = matches.get_one::<String>("LABEL") {
+                let ws = ctx.open_wallets(passphrase)?;
+                for address in ws.get_addresses_by_label(label) {
+                    println!("{}", address);
+                }
             }
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("send") {
-            let from = if let Some(address) = matches.get_one::<String>("From") {
+        if let Some(matches) = matches.subcommand_matches("send") {
+            let from = if let Some(address) = matches.get_one::<String>("FROM") {
                 address
             } else {
                 println!("from not supply!: usage");
                 exit(1);
             };
 
-            let to = if let Some(address) = matches.get_one::<String>("To") {
+            let to = if let Some(address) = matches.get_one::<String>("TO") {
                 address
             } else {
                 println!("to not supply!: usage");
                 exit(1);
             };
 
-            let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
+            let amount: u64 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
                 amount.parse()?
             } else {
                 println!("amount not supply!: usage");
                 exit(1);
             };
 
-            if matches.contains_id("mine") {
-                cmd_send(from, to, amount, true)?;
+            cmd_send(ctx, from, to, amount, matches, passphrase)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("sendmany") {
+            let from = if let Some(address) = matches.get_one::<String>("FROM") {
+                address
             } else {
-                cmd_send(from, to, amount, false)?;
+                println!("from not supply!: usage");
+                exit(1);
+            };
+
+            let mut outputs = Vec::new();
+            if let Some(values) = matches.get_many::<String>("OUTPUTS") {
+                for value in values {
+                    outputs.push(parse_output(value)?);
+                }
+            }
+
+            cmd_send_many(ctx, from, &outputs, matches, passphrase)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("printchain") {
+            cmd_print_chain(ctx, matches.get_flag("raw"))?;
+        }
+
+        if matches.subcommand_matches("validatechain").is_some() {
+            let bc = ctx.open_chain()?;
+            match bc.validate_chain() {
+                Ok(()) => println!("ok"),
+                Err(e) => {
+                    println!("invalid chain: {}", e);
+                    exit(1);
+                }
+            }
+        }
+
+        if matches.subcommand_matches("getblockcount").is_some() {
+            let bc = ctx.open_chain()?;
+            println!("height: {}", bc.get_best_height()?);
+            println!("tip: {}", bc.get_tip_hash());
+        }
+
+        if matches.subcommand_matches("getchaininfo").is_some() {
+            let utxo_set = Utxoset::open(ctx.open_chain()?)?;
+            println!("{}", serde_json::to_string_pretty(&utxo_set.chain_info()?)?);
+        }
+
+        if matches.subcommand_matches("getsupply").is_some() {
+            let bc = ctx.open_chain()?;
+            println!("supply: {}", bc.total_supply()?);
+            println!("expected: {}", expected_supply(bc.get_best_height()?));
+        }
+
+        if matches.subcommand_matches("getdifficulty").is_some() {
+            let bc = ctx.open_chain()?;
+            println!("difficulty: {}", bc.get_difficulty());
+        }
+
+        if let Some(matches) = matches.subcommand_matches("getblock") {
+            if let Some(hash) = matches.get_one::<String>("HASH") {
+                cmd_get_block(ctx, hash)?;
             }
         }
 
-        if let Some(_) = matches.subcommand_matches("printchain") {
-            cmd_print_chain()?;
+        if let Some(matches) = matches.subcommand_matches("exportchain") {
+            if let Some(file) = matches.get_one::<String>("FILE") {
+                let bc = ctx.open_chain()?;
+                bc.export_json(BufWriter::new(File::create(file)?))?;
+                println!("exported {} blocks to {}", bc.get_best_height()? + 1, file);
+            }
         }
 
-        if let Some(_) = matches.subcommand_matches("reindex") {
-            let bc = BlockChain::new()?;
-            let utxo_set = Utxoset { blockchain: bc };
+        if let Some(matches) = matches.subcommand_matches("importchain") {
+            if let Some(file) = matches.get_one::<String>("FILE") {
+                cmd_import_chain(ctx, file)?;
+            }
+        }
+
+        if matches.subcommand_matches("reindex").is_some() {
+            let bc = ctx.open_chain()?;
+            let utxo_set = Utxoset::open(bc)?;
             utxo_set.reindex()?;
             let count = utxo_set.count_transaction()?;
             println!("done, there are {} transactions in the utxo set", count);
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("startnode") {
-            if let Some(port) = matches.get_one::<String>("PORT") {
-                let bc = BlockChain::new()?;
-                let utxo_set = Utxoset { blockchain: bc };
-                let server = Server::new(port, "", utxo_set)?;
-                server.start()?;
-            }
+        if let Some(matches) = matches.subcommand_matches("startnode") {
+            let port = match matches.get_one::<String>("PORT") {
+                Some(port) => port.as_str(),
+                None => params.default_port,
+            };
+            cmd_start_node(ctx, port, "", matches)?;
         }
 
-        if let Some(ref matches) = matches.subcommand_matches("startminer") {
+        if let Some(matches) = matches.subcommand_matches("startminer") {
             let port = if let Some(port) = matches.get_one::<String>("PORT") {
                 port
             } else {
@@ -156,52 +401,1201 @@ impl Cli {
                 exit(1);
             };
 
-            let bc = BlockChain::new()?;
-            let utxo_set = Utxoset { blockchain: bc };
-            let server = Server::new(port, address, utxo_set)?;
-            server.start()?;
+            cmd_start_node(ctx, port, address, matches)?;
         }
         Ok(())
     }
 }
 
-fn cmd_print_chain() -> Result<()> {
-    let bc = BlockChain::new()?;
+// the commands and flags of the cli
+fn command() -> Command {
+    Command::new("blockchain-rust-demo")
+        .version("0.1")
+        .author("bllock.f.zr@gmail.com")
+        .about("blockchain in rust: a simple blockchain for learning")
+        .arg(
+            arg!(--passphrase <PASSPHRASE> "passphrase encrypting the wallets")
+                .global(true),
+        )
+        .arg(
+            arg!(--network <NETWORK> "mainnet, testnet or regtest, mainnet by default")
+                .global(true),
+        )
+        .arg(arg!(--config <FILE> "toml file of settings the flags override").global(true))
+        .arg(
+            arg!(--datadir <PATH> "where the chain and the wallets are kept, data by default")
+                .global(true),
+        )
+        .arg(
+            arg!(--wallet <PATH> "wallet store to use, data_dir/wallets by default")
+                .global(true),
+        )
+        .subcommand(
+            Command::new("printchain")
+                .about("print all the chain blocks")
+                .arg(arg!(--raw "print the raw fields, key hashes instead of addresses")),
+        )
+        .subcommand(
+            Command::new("createwallet")
+                .about("create a wallet")
+                .arg(arg!(--mnemonic "derive the wallet from a new bip39 mnemonic and print it")),
+        )
+        .subcommand(
+            Command::new("recoverwallet")
+                .about("restore a wallet from its bip39 mnemonic")
+                .arg(arg!(<MNEMONIC>... "'The words of the mnemonic'"))
+                .arg(arg!(--"mnemonic-passphrase" <PASSPHRASE> "bip39 passphrase of the mnemonic").default_value("")),
+        )
+        .subcommand(
+            Command::new("dumpprivkey")
+                .about("print the private key of a wallet")
+                .arg(arg!(<ADDRESS>"'The address of the wallet'")),
+        )
+        .subcommand(
+            Command::new("importprivkey")
+                .about("add the wallet of a key printed by dumpprivkey")
+                .arg(arg!(<KEY>"'The private key'")),
+        )
+        .subcommand(
+            Command::new("importaddress")
+                .about("watch the balance of an address without its private key")
+                .arg(arg!(<ADDRESS>"'The address to watch'")),
+        )
+        .subcommand(
+            Command::new("signmessage")
+                .about("sign a message with the key of a wallet")
+                .arg(arg!(<ADDRESS>"'The address of the wallet'"))
+                .arg(arg!(<MESSAGE>"'The message to sign'")),
+        )
+        .subcommand(
+            Command::new("verifymessage")
+                .about("check a signature printed by signmessage")
+                .arg(arg!(<ADDRESS>"'The address that signed'"))
+                .arg(arg!(<MESSAGE>"'The signed message'"))
+                .arg(arg!(<SIGNATURE>"'The signature'")),
+        )
+        .subcommand(
+            Command::new("deletewallet")
+                .about("remove a wallet from the store")
+                .arg(arg!(<ADDRESS>"'The address of the wallet'"))
+                .arg(arg!(--force "delete it even if it still holds coins")),
+        )
+        .subcommand(
+            Command::new("setlabel")
+                .about("name a wallet, an empty label removes the name")
+                .arg(arg!(<ADDRESS>"'The address of the wallet'"))
+                .arg(arg!(<LABEL>"'The name'")),
+        )
+        .subcommand(
+            Command::new("getaddressbylabel")
+                .about("print the addresses with a label")
+                .arg(arg!(<LABEL>"'The name'")),
+        )
+        .subcommand(Command::new("listaddress").about("list all wallet address"))
+        .subcommand(Command::new("reindex").about("re index"))
+        .subcommand(Command::new("validatechain").about("check every block of the stored chain"))
+        .subcommand(Command::new("getblockcount").about("print the height and tip of the chain"))
+        .subcommand(Command::new("getchaininfo").about("print a summary of the chain as json"))
+        .subcommand(Command::new("getsupply").about("print the number of coins minted so far"))
+        .subcommand(
+            Command::new("getdifficulty")
+                .about("print the leading hex zeros the next block needs"),
+        )
+        .subcommand(
+            Command::new("getblock")
+                .about("print a single block")
+                .arg(arg!(<HASH>"'The hash of the block'")),
+        )
+        .subcommand(
+            Command::new("exportchain")
+                .about("write the whole chain to a json file")
+                .arg(arg!(<FILE>"'The file to write'")),
+        )
+        .subcommand(
+            Command::new("importchain")
+                .about("rebuild the chain from a json file written by exportchain")
+                .arg(arg!(<FILE>"'The file to read'")),
+        )
+        .subcommand(
+            Command::new("getbalance")
+                .about("get balance in the blockchain")
+                .arg(arg!(<ADDRESS>"'The Address it get balance for'")),
+        )
+        .subcommand(
+            Command::new("listutxo")
+                .about("list the unspent outputs of an address")
+                .arg(arg!(<ADDRESS>"'The address to list the outputs of'")),
+        )
+        .subcommand(
+            Command::new("generate")
+                .about("mine blocks at once on regtest, without a node")
+                .arg(arg!(<COUNT>"'The number of blocks to mine'"))
+                .arg(arg!(<ADDRESS>"'The address to send the rewards to'")),
+        )
+        .subcommand(
+            Command::new("richlist")
+                .about("print the addresses holding the most coins, scans every unspent output")
+                .arg(arg!([COUNT]"'The number of addresses to print'").default_value("10")),
+        )
+        .subcommand(
+            Command::new("gettxout")
+                .about("print an output if it is unspent")
+                .arg(arg!(<TXID>"'The transaction of the output'"))
+                .arg(arg!(<VOUT>"'The index of the output'")),
+        )
+        .subcommand(
+            Command::new("getconfirmations")
+                .about("print how many blocks confirm a transaction")
+                .arg(arg!(<TXID>"'The transaction to look up'")),
+        )
+        .subcommand(
+            Command::new("getaddresshistory")
+                .about("print the transactions paying or spending from an address")
+                .arg(arg!(<ADDRESS>"'The address to list the transactions of'")),
+        )
+        .subcommand(
+            Command::new("decoderawtransaction")
+                .about("print a transaction given as hex")
+                .arg(arg!(<HEX>"'The encoded transaction'")),
+        )
+        .subcommand(
+            Command::new("createrawtransaction")
+                .about("print an unsigned transaction as hex")
+                .arg(arg!(<FROM>"'Source wallet address'"))
+                .arg(arg!(<OUTPUTS>... "'Recipients as ADDRESS:AMOUNT'"))
+                .arg(arg!(--fee <FEE> "fee left to the miner").default_value("0")),
+        )
+        .subcommand(
+            Command::new("signrawtransaction")
+                .about("sign a transaction given as hex with the local wallet")
+                .arg(arg!(<HEX>"'The encoded transaction'")),
+        )
+        .subcommand(
+            Command::new("sendrawtransaction")
+                .about("broadcast a signed transaction given as hex")
+                .arg(arg!(<HEX>"'The encoded transaction'"))
+                .arg(arg!(--seed <ADDR>... "node to send it to, can be repeated")),
+        )
+        .subcommand(
+            Command::new("create")
+                .about("Create new blockchain")
+                .arg(arg!(<ADDRESS>"'The address to send genesis block reward to' ")),
+        )
+        .subcommand(
+            Command::new("send")
+                .about("send in a blockchain")
+                .arg(arg!(<FROM>"'Source wallet address'"))
+                .arg(arg!(<TO>"'Target wallet address'"))
+                .arg(arg!(<AMOUNT>"'Amount to transfer'"))
+                .arg(arg!(-m --mine "mine the transaction locally instead of broadcasting"))
+                .arg(arg!(--fee <FEE> "fee left to the miner").default_value("0"))
+                .arg(arg!(--locktime <LOCKTIME> "height, or unix time from 500000000 on, to mine it from"))
+                .arg(arg!(--seed <ADDR>... "node to send it to when not mined, can be repeated")),
+        )
+        .subcommand(
+            Command::new("createmultisig")
+                .about("print the address of the outputs THRESHOLD of the ADDRESSES must sign")
+                .arg(arg!(<THRESHOLD>"'The number of signatures needed'"))
+                .arg(arg!(<ADDRESSES>... "'The addresses of the cosigners'")),
+        )
+        .subcommand(
+            Command::new("sendmultisig")
+                .about("send to an output THRESHOLD of the ADDRESSES must sign to spend")
+                .arg(arg!(<FROM>"'Source wallet address'"))
+                .arg(arg!(<AMOUNT>"'Amount to transfer'"))
+                .arg(arg!(<THRESHOLD>"'The number of signatures needed'"))
+                .arg(arg!(<ADDRESSES>... "'The addresses of the cosigners'"))
+                .arg(arg!(-m --mine "mine the transaction locally instead of broadcasting"))
+                .arg(arg!(--fee <FEE> "fee left to the miner").default_value("0"))
+                .arg(arg!(--seed <ADDR>... "node to send it to when not mined, can be repeated")),
+        )
+        .subcommand(
+            Command::new("sendmany")
+                .about("send to several addresses in one transaction")
+                .arg(arg!(<FROM>"'Source wallet address'"))
+                .arg(arg!(<OUTPUTS>... "'Recipients as ADDRESS:AMOUNT'"))
+                .arg(arg!(-m --mine "mine the transaction locally instead of broadcasting"))
+                .arg(arg!(--seed <ADDR>... "node to send it to when not mined, can be repeated")),
+        )
+        .subcommand(
+            Command::new("startnode")
+                .about("start the node server")
+                .arg(arg!([PORT]"'the port server bind to locally, the network one by default'"))
+                .arg(arg!(--seed <ADDR>... "node to bootstrap from, can be repeated"))
+                .arg(arg!(--bind <HOST> "interface to listen on, 0.0.0.0 by default"))
+                .arg(arg!(--advertise <ADDR> "address announced to peers"))
+                .arg(arg!(--rpc <PORT> "serve json-rpc over http on this port"))
+                .arg(arg!(--"rpc-bind" <HOST> "interface json-rpc listens on, 127.0.0.1 by default"))
+                .arg(arg!(--prune <DEPTH> "drop the spent transactions of blocks buried deeper"))
+                .arg(arg!(--"max-peers" <COUNT> "known nodes to keep, the seeds included"))
+                .arg(arg!(--"download-window" <COUNT> "blocks requested at once while syncing"))
+                .arg(arg!(--checkpoint <CHECKPOINT>... "HEIGHT:HASH the block at HEIGHT must have, can be repeated")),
+        )
+        .subcommand(
+            Command::new("startminer")
+                .about("start the minner server")
+                .arg(arg!(<PORT>" 'the port server bind to locally'"))
+                .arg(arg!(<ADDRESS>" 'wallet address'"))
+                .arg(arg!(--seed <ADDR>... "node to bootstrap from, can be repeated"))
+                .arg(arg!(--bind <HOST> "interface to listen on, 0.0.0.0 by default"))
+                .arg(arg!(--advertise <ADDR> "address announced to peers"))
+                .arg(arg!(--rpc <PORT> "serve json-rpc over http on this port"))
+                .arg(arg!(--"rpc-bind" <HOST> "interface json-rpc listens on, 127.0.0.1 by default"))
+                .arg(arg!(--prune <DEPTH> "drop the spent transactions of blocks buried deeper"))
+                .arg(arg!(--"max-peers" <COUNT> "known nodes to keep, the seeds included"))
+                .arg(arg!(--"download-window" <COUNT> "blocks requested at once while syncing"))
+                .arg(arg!(--checkpoint <CHECKPOINT>... "HEIGHT:HASH the block at HEIGHT must have, can be repeated")),
+        )
+}
+
+// what the commands run against, the settings of the config file with the
+// flags applied
+struct Context {
+    params: NetworkParams,
+    // where the chain is kept
+    data_dir: String,
+    // the wallet store, the wallets directory of data_dir unless --wallet
+    // names another
+    wallet_path: String,
+    config: Config,
+}
+
+impl Context {
+    fn new(config: Config, wallet_path: Option<String>) -> Result<Context> {
+        let params = NetworkParams::by_name(config.network.as_deref().unwrap_or(MAINNET.name))?;
+        let data_dir = match &config.data_dir {
+            Some(data_dir) => data_dir.clone(),
+            None => params.data_dir.to_string(),
+        };
+        let wallet_path = wallet_path.unwrap_or_else(|| format!("{}/wallets", data_dir));
+        Ok(Context {
+            params,
+            data_dir,
+            wallet_path,
+            config,
+        })
+    }
+
+    // the config file given with --config, if any, and the global flags
+    // overriding it
+    fn from_matches(matches: &ArgMatches) -> Result<Context> {
+        let mut config = match matches.get_one::<String>("config") {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if let Some(network) = matches.get_one::<String>("network") {
+            config.network = Some(network.clone());
+        }
+        if let Some(data_dir) = matches.get_one::<String>("datadir") {
+            config.data_dir = Some(data_dir.clone());
+        }
+        let wallet = matches.get_one::<String>("wallet").cloned();
+        Context::new(config, wallet)
+    }
+
+    fn open_chain(&self) -> Result<BlockChain> {
+        BlockChain::new(&self.data_dir)
+    }
+
+    fn open_wallets(&self, passphrase: Option<&str>) -> Result<Wallets> {
+        Wallets::open(&self.params, &self.wallet_path, passphrase)
+    }
+
+    // a node keeps its databases under its own port, e.g. data/3000, when a
+    // chain was created there, and shares the one of `create` otherwise
+    fn node_data_dir(&self, port: &str) -> String {
+        let node_dir = format!("{}/{}", self.data_dir, port);
+        if Path::new(&node_dir).join("blocks").exists() {
+            node_dir
+        } else {
+            self.data_dir.clone()
+        }
+    }
+}
+
+fn cmd_print_chain(ctx: &Context, raw: bool) -> Result<()> {
+    let bc = ctx.open_chain()?;
     for b in bc.iter() {
-        println!("{:#?}", b);
+        if raw {
+            println!("{:#?}", b);
+        } else {
+            println!("{}", b.display(&ctx.params));
+        }
+    }
+    Ok(())
+}
+
+fn cmd_get_block(ctx: &Context, hash: &str) -> Result<()> {
+    let bc = ctx.open_chain()?;
+    let block = bc.get_block(hash)?;
+    println!("height: {}", block.get_height());
+    println!("time: {}", block.datetime());
+    println!("transactions: {}", block.get_transactions().len());
+    println!("{:#?}", block);
+    Ok(())
+}
+
+fn cmd_generate(ctx: &Context, count: usize, address: &str) -> Result<()> {
+    if ctx.params != REGTEST {
+        return Err(format_err!("generate is only available on regtest"));
+    }
+    ctx.params.decode_address(address)?;
+    let mut utxo_set = Utxoset::open(ctx.open_chain()?)?;
+    for _ in 0..count {
+        println!("{}", utxo_set.mine_block(address, vec![])?.get_hash());
     }
+    println!("height: {}", utxo_set.blockchain.get_best_height()?);
     Ok(())
 }
 
-fn cmd_create_blockchain(address: &str) -> Result<()> {
+fn cmd_rich_list(ctx: &Context, count: usize) -> Result<()> {
+    let utxo_set = Utxoset::open(ctx.open_chain()?)?;
+    let balances = rich_list(&ctx.params, &utxo_set, count)?;
+    for (rank, (address, balance)) in balances.into_iter().enumerate() {
+        println!("{}. {} {}", rank + 1, address, balance);
+    }
+    Ok(())
+}
+
+// the `count` addresses holding the most, largest first, ties by address so
+// the order is stable
+fn rich_list<S: Storage>(
+    params: &NetworkParams,
+    utxo_set: &Utxoset<S>,
+    count: usize,
+) -> Result<Vec<(String, u64)>> {
+    let mut balances: Vec<(String, u64)> = utxo_set
+        .balances_by_address()?
+        .into_iter()
+        .map(|(pub_key_hash, balance)| (params.encode_address(&pub_key_hash), balance))
+        .collect();
+    balances.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    balances.truncate(count);
+    Ok(balances)
+}
+
+fn cmd_decode_raw_transaction(ctx: &Context, data: &str) -> Result<()> {
+    let tx = Transaction::from_hex(data)?;
+    println!("txid: {}", tx.id);
+    println!("inputs:");
+    if tx.is_coinbase() {
+        println!("  coinbase");
+    } else {
+        for vin in &tx.vin {
+            println!("  {}:{}", vin.txid, vin.vout);
+        }
+    }
+    println!("outputs:");
+    for (index, out) in tx.vout.iter().enumerate() {
+        println!("  {}: {} {}", index, ctx.params.encode_address(&out.pub_key_hash), out.value);
+    }
+    Ok(())
+}
+
+fn cmd_delete_wallet(
+    ctx: &Context,
+    address: &str,
+    force: bool,
+    passphrase: Option<&str>,
+) -> Result<()> {
+    let mut ws = ctx.open_wallets(passphrase)?;
+    if ws.get_wallet(address).is_none() && !ws.is_watch_only(address) {
+        return Err(format_err!("no wallet for address {}", address));
+    }
+    // a watch-only address loses no coins
+    if !force && !ws.is_watch_only(address) {
+        let utxo_set = Utxoset::open(ctx.open_chain()?)?;
+        let balance: u64 = utxo_set
+            .find_utxo(&ctx.params.decode_address(address)?)?
+            .iter()
+            .map(|out| out.value)
+            .sum();
+        if balance != 0 {
+            return Err(format_err!(
+                "{} still holds {}, use --force to delete it anyway",
+                address,
+                balance
+            ));
+        }
+    }
+    ws.delete_wallet(address)?;
+    println!("success: deleted {}", address);
+    Ok(())
+}
+
+// the signature is printed as base64 of the public key followed by the
+// ed25519 signature, the key is needed to check it against the address
+fn cmd_sign_message(
+    ctx: &Context,
+    address: &str,
+    message: &str,
+    passphrase: Option<&str>,
+) -> Result<()> {
+    let ws = ctx.open_wallets(passphrase)?;
+    let wallet = ws.get_spendable_wallet(address)?;
+    if wallet.scheme != ED25519 {
+        return Err(format_err!("only ed25519 wallets can sign messages"));
+    }
+    let mut data = wallet.public_key.clone();
+    data.extend(wallet.sign_message(message.as_bytes())?);
+    println!("{}", BASE64_STANDARD.encode(data));
+    Ok(())
+}
+
+fn cmd_verify_message(
+    ctx: &Context,
+    address: &str,
+    message: &str,
+    signature: &str,
+) -> Result<bool> {
+    let pub_key_hash = ctx.params.decode_address(address)?;
+    let data = BASE64_STANDARD
+        .decode(signature)
+        .map_err(|e| format_err!("invalid signature: {}", e))?;
+    if data.len() != 96 {
+        return Err(format_err!("invalid signature: wrong length"));
+    }
+    let (pub_key, sig) = data.split_at(32);
+
+    let mut signer = pub_key.to_vec();
+    hash_pub_key(&mut signer);
+    Ok(signer == pub_key_hash && verify_message(&Ed25519Scheme, pub_key, message.as_bytes(), sig))
+}
+
+fn cmd_create_blockchain(ctx: &Context, address: &str) -> Result<()> {
     println!("Creating new block");
-    if let Err(e) = remove_dir_all("data/blocks") {
+    if let Err(e) = remove_dir_all(format!("{}/blocks", ctx.data_dir)) {
         info!("block not exist to delete,  {}", e);
     }
     println!("creating new block database");
 
     let address = String::from(address);
-    let bc = BlockChain::create_blockchain(address)?;
-    let utxo_set = Utxoset { blockchain: bc };
-    utxo_set.reindex()?;
+    let bc = BlockChain::create_blockchain(address, &ctx.data_dir, &ctx.params)?;
+    Utxoset::open(bc)?;
+    Ok(())
+}
+
+fn cmd_import_chain(ctx: &Context, file: &str) -> Result<()> {
+    let reader = BufReader::new(File::open(file)?);
+    let bc = BlockChain::import_json(reader, &ctx.data_dir, &ctx.params)?;
+    let utxo_set = Utxoset::open(bc)?;
+    println!(
+        "imported {} blocks, tip {}",
+        utxo_set.blockchain.get_best_height()? + 1,
+        utxo_set.blockchain.get_tip_hash()
+    );
+    Ok(())
+}
+
+// the fee, lock time, whether to mine and the seeds come from the options of
+// `matches`
+fn cmd_send(
+    ctx: &Context,
+    from: &str,
+    to: &str,
+    amount: u64,
+    matches: &ArgMatches,
+    passphrase: Option<&str>,
+) -> Result<()> {
+    let fee: u64 = match matches.get_one::<String>("fee") {
+        Some(fee) => fee.parse()?,
+        None => 0,
+    };
+    let lock_time: u64 = match matches.get_one::<String>("locktime") {
+        Some(lock_time) => lock_time.parse()?,
+        None => 0,
+    };
+
+    let bc = ctx.open_chain()?;
+    let utxo_set = Utxoset::open(bc)?;
+    let ws = ctx.open_wallets(passphrase)?;
+    let wallet = ws.get_spendable_wallet(from)?;
+    let tx = if lock_time == 0 {
+        Transaction::new_utxo_with_fee(wallet, to, amount, fee, &utxo_set)?
+    } else {
+        let outputs = [(to.to_string(), amount)];
+        let mut tx = Transaction::create_raw_transaction(
+            &wallet.public_key,
+            &outputs,
+            fee,
+            CoinSelection::default(),
+            &utxo_set,
+        )?;
+        tx.set_lock_time(lock_time)?;
+        utxo_set.blockchain.sign_transaction(&mut tx, wallet.scheme()?, &wallet.secret_key)?;
+        tx
+    };
+    submit_transaction(ctx, tx, from, utxo_set, matches)
+}
+
+fn cmd_send_many(
+    ctx: &Context,
+    from: &str,
+    outputs: &[(String, u64)],
+    matches: &ArgMatches,
+    passphrase: Option<&str>,
+) -> Result<()> {
+    let bc = ctx.open_chain()?;
+    let utxo_set = Utxoset::open(bc)?;
+    let ws = ctx.open_wallets(passphrase)?;
+    let wallet = ws.get_spendable_wallet(from)?;
+    let tx = Transaction::new_utxo_many(wallet, outputs, &utxo_set)?;
+    submit_transaction(ctx, tx, from, utxo_set, matches)
+}
+
+fn cmd_send_multisig(
+    ctx: &Context,
+    from: &str,
+    multisig: Multisig,
+    amount: u64,
+    fee: u64,
+    matches: &ArgMatches,
+    passphrase: Option<&str>,
+) -> Result<()> {
+    let utxo_set = Utxoset::open(ctx.open_chain()?)?;
+    let ws = ctx.open_wallets(passphrase)?;
+    let wallet = ws.get_spendable_wallet(from)?;
+    let tx = Transaction::new_multisig_payment(wallet, multisig, amount, fee, &utxo_set)?;
+    submit_transaction(ctx, tx, from, utxo_set, matches)
+}
+
+fn cmd_create_raw_transaction(
+    ctx: &Context,
+    from: &str,
+    outputs: &[(String, u64)],
+    fee: u64,
+    passphrase: Option<&str>,
+) -> Result<()> {
+    let utxo_set = Utxoset::open(ctx.open_chain()?)?;
+    let ws = ctx.open_wallets(passphrase)?;
+    let wallet = match ws.get_wallet(from) {
+        Some(wallet) => wallet,
+        None => {
+            // the outputs of a multisig address tell its cosigners
+            let multisig = utxo_set
+                .list_unspent(&ctx.params.decode_address(from)?)?
+                .into_iter()
+                .find_map(|(_, _, out)| out.multisig);
+            let multisig = match multisig {
+                Some(multisig) => multisig,
+                None if ws.is_watch_only(from) => {
+                    return Err(format_err!(
+                        "address {} is watch-only, its public key is unknown",
+                        from
+                    ))
+                }
+                None => {
+                    return Err(format_err!("no wallet or multisig output for address {}", from))
+                }
+            };
+            let tx = Transaction::create_multisig_raw_transaction(
+                multisig,
+                outputs,
+                fee,
+                CoinSelection::default(),
+                &utxo_set,
+            )?;
+            println!("{}", tx.to_hex()?);
+            return Ok(());
+        }
+    };
+    let tx = Transaction::create_raw_transaction(
+        &wallet.public_key,
+        outputs,
+        fee,
+        CoinSelection::default(),
+        &utxo_set,
+    )?;
+    println!("{}", tx.to_hex()?);
+    Ok(())
+}
+
+// sign with the wallet owning the inputs, or with every local wallet among
+// the cosigners of multisig inputs
+fn cmd_sign_raw_transaction(
+    ctx: &Context,
+    data: &str,
+    passphrase: Option<&str>,
+) -> Result<()> {
+    let mut tx = Transaction::from_hex(data)?;
+    let bc = ctx.open_chain()?;
+    let prev_txs = bc.get_prev_txs(&tx)?;
+    if tx.vin.iter().any(|vin| vin.pub_key.is_empty()) {
+        return cmd_sign_multisig_transaction(ctx, tx, prev_txs, passphrase);
+    }
+
+    let mut addresses: Vec<String> = tx
+        .vin
+        .iter()
+        .map(|vin| {
+            let mut pub_key_hash = vin.pub_key.clone();
+            hash_pub_key(&mut pub_key_hash);
+            ctx.params.encode_address(&pub_key_hash)
+        })
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    if addresses.len() != 1 {
+        return Err(format_err!("the inputs must all belong to one wallet"));
+    }
+
+    let ws = ctx.open_wallets(passphrase)?;
+    let wallet = ws.get_spendable_wallet(&addresses[0])?;
+    tx = Transaction::sign_raw_transaction(tx, wallet.scheme()?, &wallet.secret_key, prev_txs)?;
+    println!("{}", tx.to_hex()?);
     Ok(())
 }
 
-fn cmd_send(from: &str, to: &str, amount: i32, mine: bool) -> Result<()> {
-    let bc = BlockChain::new()?;
-    let mut utxo_set = Utxoset { blockchain: bc };
-    let ws = Wallets::new()?;
-    let wallet = ws.get_wallet(from).unwrap();
-    let tx = Transaction::new_utxo(wallet, to, amount, &utxo_set).unwrap();
+fn cmd_sign_multisig_transaction(
+    ctx: &Context,
+    mut tx: Transaction,
+    prev_txs: HashMap<String, Transaction>,
+    passphrase: Option<&str>,
+) -> Result<()> {
+    let mut cosigners: Vec<String> = Vec::new();
+    for vin in &tx.vin {
+        let multisig = prev_txs
+            .get(&vin.txid)
+            .and_then(|prev_tx| prev_tx.vout.get(vin.vout as usize))
+            .and_then(|out| out.multisig.as_ref())
+            .ok_or_else(|| format_err!("cannot sign a mix of multisig and single key inputs"))?;
+        cosigners.extend(
+            multisig
+                .pub_key_hashes
+                .iter()
+                .map(|hash| ctx.params.encode_address(hash)),
+        );
+    }
+    cosigners.sort();
+    cosigners.dedup();
+
+    let ws = ctx.open_wallets(passphrase)?;
+    let wallets: Vec<&Wallet> = cosigners.iter().filter_map(|a| ws.get_wallet(a)).collect();
+    if wallets.is_empty() {
+        return Err(format_err!("no local wallet is a cosigner of the inputs"));
+    }
+    for wallet in wallets {
+        let scheme = wallet.scheme()?;
+        tx = Transaction::sign_raw_transaction(tx, scheme, &wallet.secret_key, prev_txs.clone())?;
+    }
+    println!("{}", tx.to_hex()?);
+    Ok(())
+}
 
-    if mine {
-        let cb_tx = Transaction::new_coinbase(from.to_string(), String::from("Mining Reward"))?;
-        let new_block = utxo_set.blockchain.mine_block(vec![cb_tx, tx])?;
-        utxo_set.update(&new_block)?;
+// check the transaction against the local chain before handing it to the network
+fn cmd_send_raw_transaction(ctx: &Context, data: &str, config: &ServerConfig) -> Result<()> {
+    let tx = Transaction::from_hex(data)?;
+    if tx.is_coinbase() {
+        return Err(format_err!("cannot send a coinbase transaction"));
+    }
+    let utxo_set = Utxoset::open(ctx.open_chain()?)?;
+    for vin in &tx.vin {
+        if utxo_set.get_txout(&vin.txid, vin.vout)?.is_none() {
+            return Err(format_err!(
+                "output {}:{} is spent or nonexistent",
+                vin.txid,
+                vin.vout
+            ));
+        }
+    }
+    if !utxo_set.blockchain.verify_transaction(&tx)? {
+        return Err(format_err!("invalid signature in transaction {}", tx.id));
+    }
+    utxo_set.blockchain.get_transaction_fee(&tx)?;
+
+    Server::send_transaction(&tx, config)?;
+    println!("{}", tx.id);
+    Ok(())
+}
+
+// mine the transaction in a local block with --mine, or hand it to the seeds
+fn submit_transaction(
+    ctx: &Context,
+    tx: Transaction,
+    from: &str,
+    mut utxo_set: Utxoset,
+    matches: &ArgMatches,
+) -> Result<()> {
+    if matches.get_flag("mine") {
+        utxo_set.mine_block(from, vec![tx])?;
     } else {
-        Server::send_transaction(&tx, utxo_set)?;
+        Server::send_transaction(&tx, &sender_config(ctx, matches))?;
     }
 
     println!("success!!!");
     Ok(())
 }
+
+// what the commands handing a transaction to the network know of it, the
+// seeds of the config file unless --seed in `matches` names others
+fn sender_config(ctx: &Context, matches: &ArgMatches) -> ServerConfig {
+    let mut config = ServerConfig::new(SENDER_PORT, &ctx.params);
+    config.seeds = match matches.get_many::<String>("seed") {
+        Some(seeds) => seeds.cloned().collect(),
+        None => ctx.config.seeds.clone(),
+    };
+    config
+}
+
+// the settings of the config file for a node on `port`, the flags of
+// startnode and startminer in `matches` override them
+fn server_config(
+    ctx: &Context,
+    port: &str,
+    mining_address: &str,
+    matches: &ArgMatches,
+) -> Result<ServerConfig> {
+    let mut config = sender_config(ctx, matches);
+    config.port = port.to_string();
+    config.mining_address = match &ctx.config.mining_address {
+        Some(address) if mining_address.is_empty() => address.clone(),
+        _ => mining_address.to_string(),
+    };
+    if let Some(bind) = matches.get_one::<String>("bind").or(ctx.config.bind.as_ref()) {
+        config.bind = bind.clone();
+    }
+    config.advertise = matches.get_one::<String>("advertise").cloned();
+    config.rpc_port = matches.get_one::<String>("rpc").cloned();
+    if let Some(bind) = matches.get_one::<String>("rpc-bind").or(ctx.config.rpc_bind.as_ref()) {
+        config.rpc_bind = bind.clone();
+    }
+    if let Some(max_peers) = matches.get_one::<String>("max-peers") {
+        config.max_peers = max_peers.parse()?;
+    }
+    if let Some(window) = matches.get_one::<String>("download-window") {
+        config.download_window = window.parse()?;
+    }
+    Ok(config)
+}
+
+fn cmd_start_node(
+    ctx: &Context,
+    port: &str,
+    mining_address: &str,
+    matches: &ArgMatches,
+) -> Result<()> {
+    let config = server_config(ctx, port, mining_address, matches)?;
+    let bc = BlockChain::new(&ctx.node_data_dir(port))?;
+    let mut utxo_set = Utxoset::open(bc)?;
+    if let Some(depth) = matches.get_one::<String>("prune") {
+        utxo_set.set_prune_depth(Some(depth.parse()?))?;
+    }
+    if let Some(checkpoints) = matches.get_many::<String>("checkpoint") {
+        let checkpoints = checkpoints
+            .map(|c| parse_checkpoint(c))
+            .collect::<Result<Vec<_>>>()?;
+        utxo_set.blockchain.set_checkpoints(checkpoints)?;
+    }
+    let server = Server::new(config, utxo_set)?;
+
+    // ctrl-c lets the server finish what it is doing and flush its databases
+    let stopper = server.clone();
+    ctrlc::set_handler(move || stopper.stop())?;
+    server.start()
+}
+
+// HEIGHT:HASH
+fn parse_checkpoint(value: &str) -> Result<(i32, String)> {
+    match value.split_once(':') {
+        Some((height, hash)) => Ok((height.parse()?, hash.to_string())),
+        None => Err(format_err!("invalid checkpoint {}, expected HEIGHT:HASH", value)),
+    }
+}
+
+// THRESHOLD and ADDRESSES of createmultisig and sendmultisig
+fn parse_multisig(params: &NetworkParams, matches: &ArgMatches) -> Result<Multisig> {
+    let threshold: u32 = matches.get_one::<String>("THRESHOLD").unwrap().parse()?;
+    let mut pub_key_hashes = Vec::new();
+    for address in matches.get_many::<String>("ADDRESSES").unwrap() {
+        pub_key_hashes.push(params.decode_address(address)?);
+    }
+    Multisig::new(threshold, pub_key_hashes)
+}
+
+// ADDRESS:AMOUNT
+fn parse_output(value: &str) -> Result<(String, u64)> {
+    match value.rsplit_once(':') {
+        Some((address, amount)) => Ok((address.to_string(), amount.parse()?)),
+        None => Err(format_err!("invalid output {}, expected ADDRESS:AMOUNT", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{pub_key_hash, utxo_chain, wallet};
+    use crate::utxoset::COINBASE_MATURITY;
+    use std::io::{ErrorKind, Read};
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    // run the cli on regtest with the data kept in `dir`
+    fn run_regtest(dir: &TempDir, args: &[&str]) -> Result<()> {
+        let data_dir = dir.path().to_str().unwrap();
+        let mut all = vec!["blockchain", "--network", "regtest", "--datadir", data_dir];
+        all.extend(args);
+        Cli::new()?.run_from(all)
+    }
+
+    #[test]
+    fn generate_mines_blocks_at_once() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let address = wallet(1).get_address(&REGTEST);
+        run_regtest(&dir, &["create", &address])?;
+
+        let started = Instant::now();
+        run_regtest(&dir, &["generate", "10", &address])?;
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let utxo = Utxoset::open(BlockChain::new(dir.path().to_str().unwrap())?)?;
+        assert_eq!(utxo.blockchain.get_best_height()?, 10);
+        let pub_key_hash = REGTEST.decode_address(&address)?;
+        let balance: u64 = utxo.find_utxo(&pub_key_hash)?.iter().map(|out| out.value).sum();
+        assert_eq!(balance, 1100);
+        drop(utxo);
+
+        let mainnet = ["blockchain", "--datadir", dir.path().to_str().unwrap(), "generate", "1"];
+        let e = Cli::new()?.run_from(mainnet.iter().chain([&address.as_str()])).unwrap_err();
+        assert!(e.to_string().contains("only available on regtest"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn the_config_file_settles_what_the_flags_leave() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let data_dir = dir.path().join("chain");
+        let mining_address = wallet(1).get_address(&REGTEST);
+        let path = dir.path().join("node.toml");
+        let settings = format!(
+            r#"network = "regtest"
+data_dir = {:?}
+bind = "127.0.0.1"
+seeds = ["10.0.0.2:23000"]
+mining_address = "{}"
+"#,
+            data_dir.to_str().unwrap(),
+            mining_address
+        );
+        std::fs::write(&path, settings)?;
+        let path = path.to_str().unwrap();
+        let mut args = vec!["blockchain", "--config", path, "startnode", "23001"];
+        args.extend(["--seed", "10.0.0.3:23000"]);
+        let matches = command().get_matches_from(args);
+        let ctx = Context::from_matches(&matches)?;
+        assert_eq!(ctx.params, REGTEST);
+        assert_eq!(ctx.data_dir, data_dir.to_str().unwrap());
+
+        let node = matches.subcommand_matches("startnode").unwrap();
+        let config = server_config(&ctx, "23001", "", node)?;
+        assert_eq!(config.params, REGTEST);
+        assert_eq!(config.bind, "127.0.0.1");
+        assert_eq!(config.mining_address, mining_address);
+        // the flag wins over the file
+        assert_eq!(config.seeds, vec!["10.0.0.3:23000".to_string()]);
+
+        // the node keeps its chain under the data directory of the file
+        let node_dir = ctx.node_data_dir("23001");
+        assert!(node_dir.starts_with(data_dir.to_str().unwrap()));
+        let bc = BlockChain::create_blockchain(mining_address, &node_dir, &ctx.params)?;
+        let server = Server::new(config, Utxoset::open(bc)?)?;
+        assert_eq!(server.get_difficulty(), REGTEST.difficulty);
+        Ok(())
+    }
+
+    #[test]
+    fn a_node_opens_the_chain_create_made() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let data_dir = dir.path().to_str().unwrap();
+        let address = wallet(1).get_address(&REGTEST);
+        run_regtest(&dir, &["create", &address])?;
+        let tip = BlockChain::new(data_dir)?.get_tip_hash();
+
+        let mut args = vec!["blockchain", "--network", "regtest", "--datadir", data_dir];
+        args.extend(["startnode", "23001"]);
+        let ctx = Context::from_matches(&command().get_matches_from(args))?;
+        let bc = BlockChain::new(&ctx.node_data_dir("23001"))?;
+        assert_eq!(bc.get_tip_hash(), tip);
+        drop(bc);
+
+        // a chain created under the port of a node is its own
+        let node_dir = format!("{}/23002", data_dir);
+        let args = ["blockchain", "--network", "regtest", "--datadir", &node_dir, "create"];
+        Cli::new()?.run_from(args.iter().chain([&address.as_str()]))?;
+        assert_eq!(ctx.node_data_dir("23002"), node_dir);
+        assert_eq!(ctx.node_data_dir("23001"), data_dir);
+        Ok(())
+    }
+
+    #[test]
+    fn createwallet_keeps_the_wallet_under_datadir() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let default_store = std::path::Path::new(REGTEST.data_dir).join("wallets");
+        let existed = default_store.exists();
+        run_regtest(&dir, &["createwallet"])?;
+
+        let store = dir.path().join("wallets");
+        assert!(store.is_dir());
+        let wallets = Wallets::open(&REGTEST, store.to_str().unwrap(), None)?;
+        assert_eq!(wallets.get_all_wallets().len(), 1);
+        assert_eq!(default_store.exists(), existed);
+        Ok(())
+    }
+
+    #[test]
+    fn createwallet_keeps_the_wallet_in_the_store_of_wallet() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let store = dir.path().join("elsewhere");
+        run_regtest(&dir, &["--wallet", store.to_str().unwrap(), "createwallet"])?;
+
+        assert!(!dir.path().join("wallets").exists());
+        let wallets = Wallets::open(&REGTEST, store.to_str().unwrap(), None)?;
+        assert_eq!(wallets.get_all_wallets().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn a_transaction_is_sent_to_the_seed_flags() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let data_dir = dir.path().to_str().unwrap();
+        let mut args = vec!["blockchain", "--network", "regtest", "--datadir", data_dir];
+        args.extend(["send", "a", "b", "10", "--seed", "10.0.0.2:23000"]);
+        let matches = command().get_matches_from(args);
+        let ctx = Context::from_matches(&matches)?;
+        let send = matches.subcommand_matches("send").unwrap();
+        let config = sender_config(&ctx, send);
+        assert_eq!(config.params, REGTEST);
+        assert_eq!(config.seeds, vec!["10.0.0.2:23000".to_string()]);
+        Ok(())
+    }
+
+    // a regtest chain in `dir` whose genesis coinbase pays wallet(1), mature
+    // and in the wallets, returns its address
+    fn funded_regtest(dir: &TempDir) -> Result<String> {
+        let address = wallet(1).get_address(&REGTEST);
+        run_regtest(dir, &["create", &address])?;
+        run_regtest(dir, &["importprivkey", &wallet(1).export_key()?])?;
+        let miner = wallet(3).get_address(&REGTEST);
+        run_regtest(dir, &["generate", &COINBASE_MATURITY.to_string(), &miner])?;
+        Ok(address)
+    }
+
+    #[test]
+    fn send_with_mine_mines_the_transaction_locally() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let from = funded_regtest(&dir)?;
+        let payee = wallet(2);
+        let to = payee.get_address(&REGTEST);
+        let height = COINBASE_MATURITY;
+
+        // without --mine it goes to the seeds, regtest has none
+        assert!(run_regtest(&dir, &["send", &from, &to, "10"]).is_err());
+        assert_eq!(BlockChain::new(dir.path().to_str().unwrap())?.get_best_height()?, height);
+
+        run_regtest(&dir, &["send", &from, &to, "10", "--mine"])?;
+        let utxo = Utxoset::open(BlockChain::new(dir.path().to_str().unwrap())?)?;
+        assert_eq!(utxo.blockchain.get_best_height()?, height + 1);
+        let received: u64 = utxo.find_utxo(&pub_key_hash(&payee))?.iter().map(|o| o.value).sum();
+        assert_eq!(received, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn send_reads_its_amount() -> Result<()> {
+        let matches = command().get_matches_from(["blockchain", "send", "alice", "bob", "42"]);
+        let send = matches.subcommand_matches("send").unwrap();
+        assert_eq!(send.get_one::<String>("FROM").unwrap(), "alice");
+        assert_eq!(send.get_one::<String>("TO").unwrap(), "bob");
+        let amount: u64 = send.get_one::<String>("AMOUNT").unwrap().parse()?;
+        assert_eq!(amount, 42);
+        assert!(!send.get_flag("mine"));
+
+        // and the amount is what the payee gets
+        let dir = tempfile::tempdir()?;
+        let from = funded_regtest(&dir)?;
+        let payee = wallet(2);
+        run_regtest(&dir, &["send", &from, &payee.get_address(&REGTEST), "42", "--mine"])?;
+        let utxo = Utxoset::open(BlockChain::new(dir.path().to_str().unwrap())?)?;
+        let received: u64 = utxo.find_utxo(&pub_key_hash(&payee))?.iter().map(|o| o.value).sum();
+        assert_eq!(received, 42);
+        Ok(())
+    }
+
+    #[test]
+    fn getbalance_of_a_garbage_address_is_an_error() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        run_regtest(&dir, &["create", &wallet(1).get_address(&REGTEST)])?;
+        for garbage in ["garbage", "0OIl", ""] {
+            let e = run_regtest(&dir, &["getbalance", garbage]).unwrap_err();
+            assert!(e.to_string().contains("invalid address"), "{}", e);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn getblock_finds_every_block_of_the_chain() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let address = wallet(1).get_address(&REGTEST);
+        run_regtest(&dir, &["create", &address])?;
+        run_regtest(&dir, &["generate", "2", &address])?;
+        let hashes = BlockChain::new(dir.path().to_str().unwrap())?.get_block_hashes();
+        assert_eq!(hashes.len(), 3);
+
+        for hash in &hashes {
+            run_regtest(&dir, &["getblock", hash])?;
+        }
+        let unknown = "0".repeat(64);
+        let e = run_regtest(&dir, &["getblock", &unknown]).unwrap_err();
+        assert!(e.to_string().contains("not found"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn getblockcount_grows_by_one_per_block() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let data_dir = dir.path().to_str().unwrap();
+        let address = wallet(1).get_address(&REGTEST);
+        run_regtest(&dir, &["create", &address])?;
+        run_regtest(&dir, &["getblockcount"])?;
+        assert_eq!(BlockChain::new(data_dir)?.get_best_height()?, 0);
+
+        run_regtest(&dir, &["generate", "1", &address])?;
+        run_regtest(&dir, &["getblockcount"])?;
+        assert_eq!(BlockChain::new(data_dir)?.get_best_height()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn sendrawtransaction_forwards_only_a_valid_transaction() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let address = wallet(1).get_address(&REGTEST);
+        run_regtest(&dir, &["create", &address])?;
+        let mut utxo = Utxoset::open(BlockChain::new(dir.path().to_str().unwrap())?)?;
+        utxo.set_coinbase_maturity(0);
+        let tx = Transaction::new_utxo(&wallet(1), &wallet(2).get_address(&REGTEST), 10, &utxo)?;
+        drop(utxo);
+        let seed = TcpListener::bind("127.0.0.1:0")?;
+        let seed_address = seed.local_addr()?.to_string();
+
+        // a tampered signature is caught before anything is sent
+        let mut tampered = tx.clone();
+        tampered.vin[0].signature[0] ^= 1;
+        let args = ["sendrawtransaction", &tampered.to_hex()?, "--seed", &seed_address];
+        let e = run_regtest(&dir, &args).unwrap_err();
+        assert!(e.to_string().contains("invalid signature"), "{}", e);
+        seed.set_nonblocking(true)?;
+        assert!(matches!(seed.accept(), Err(e) if e.kind() == ErrorKind::WouldBlock));
+        seed.set_nonblocking(false)?;
+
+        run_regtest(&dir, &["sendrawtransaction", &tx.to_hex()?, "--seed", &seed_address])?;
+        let (mut stream, _) = seed.accept()?;
+        let mut frame = Vec::new();
+        stream.read_to_end(&mut frame)?;
+        assert_eq!(frame[..4], REGTEST.magic);
+        // the transaction closes the message
+        assert!(frame.ends_with(&bincode::serialize(&tx)?));
+        Ok(())
+    }
+
+    #[test]
+    fn deletewallet_drops_the_wallet_unless_it_holds_coins() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let funded = funded_regtest(&dir)?;
+        let store = dir.path().join("wallets");
+        let mut wallets = Wallets::new(&REGTEST, store.to_str().unwrap())?;
+        let (kept, deleted) = (wallets.create_wallet(), wallets.create_wallet());
+        wallets.save_all()?;
+        drop(wallets);
+
+        run_regtest(&dir, &["deletewallet", &deleted])?;
+        let wallets = Wallets::new(&REGTEST, store.to_str().unwrap())?;
+        let mut all = wallets.get_all_wallets();
+        all.sort();
+        let mut expected = vec![funded.clone(), kept];
+        expected.sort();
+        assert_eq!(all, expected);
+        drop(wallets);
+
+        let e = run_regtest(&dir, &["deletewallet", &funded]).unwrap_err();
+        assert!(e.to_string().contains("use --force"), "{}", e);
+        assert!(Wallets::new(&REGTEST, store.to_str().unwrap())?.get_wallet(&funded).is_some());
+        run_regtest(&dir, &["deletewallet", &funded, "--force"])?;
+        assert!(Wallets::new(&REGTEST, store.to_str().unwrap())?.get_wallet(&funded).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn send_refuses_amounts_out_of_range() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let from = funded_regtest(&dir)?;
+        let to = wallet(2).get_address(&REGTEST);
+        let max = u64::MAX.to_string();
+        let cases = [
+            (vec!["0"], "invalid amount 0"),
+            (vec!["--", "-5"], "invalid digit"),
+            (vec!["18446744073709551616"], "too large"),
+            (vec![max.as_str()], "No Enough Balance"),
+            (vec![max.as_str(), "--fee", "1"], "add up to more than"),
+        ];
+        for (amount, error) in cases {
+            let mut args = vec!["send", from.as_str(), to.as_str(), "--mine"];
+            args.extend(amount);
+            let e = run_regtest(&dir, &args).unwrap_err();
+            assert!(e.to_string().contains(error), "{}", e);
+        }
+        // a negative amount is not even an argument
+        let args = ["blockchain", "send", &from, &to, "-5"];
+        assert!(command().try_get_matches_from(args).is_err());
+        let bc = BlockChain::new(dir.path().to_str().unwrap())?;
+        assert_eq!(bc.get_best_height()?, COINBASE_MATURITY);
+        Ok(())
+    }
+
+    #[test]
+    fn the_rich_list_ranks_the_balances() -> Result<()> {
+        let mut utxo = utxo_chain(&wallet(1).get_address(&REGTEST))?;
+        let to = |seed| wallet(seed).get_address(&REGTEST);
+        let first = Transaction::new_utxo(&wallet(1), &to(2), 30, &utxo)?;
+        utxo.mine_block(&to(3), vec![first])?;
+        let second = Transaction::new_utxo(&wallet(1), &to(4), 30, &utxo)?;
+        utxo.mine_block(&to(3), vec![second])?;
+
+        let ranked = rich_list(&REGTEST, &utxo, 10)?;
+        // wallet(2) and wallet(4) tie, the address settles it
+        let mut tied = vec![(to(2), 30), (to(4), 30)];
+        tied.sort();
+        let mut expected = vec![(to(3), 200), (to(1), 40)];
+        expected.extend(tied);
+        assert_eq!(ranked, expected);
+        let total: u64 = ranked.iter().map(|(_, balance)| balance).sum();
+        assert_eq!(total, utxo.blockchain.total_supply()?);
+        let by_hash = utxo.balances_by_address()?;
+        assert_eq!(by_hash[&pub_key_hash(&wallet(1))], 40);
+
+        assert_eq!(rich_list(&REGTEST, &utxo, 2)?, expected[..2]);
+        Ok(())
+    }
+
+    #[test]
+    fn a_watch_only_address_has_a_balance_but_cannot_send() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let from = funded_regtest(&dir)?;
+        let watched = wallet(2).get_address(&REGTEST);
+        run_regtest(&dir, &["importaddress", &watched])?;
+        run_regtest(&dir, &["send", &from, &watched, "25", "--mine"])?;
+        run_regtest(&dir, &["getbalance", &watched])?;
+        let utxo = Utxoset::open(BlockChain::new(dir.path().to_str().unwrap())?)?;
+        let outputs = utxo.find_utxo(&pub_key_hash(&wallet(2)))?;
+        assert_eq!(outputs.iter().map(|o| o.value).sum::<u64>(), 25);
+        let height = utxo.blockchain.get_best_height()?;
+        drop(utxo);
+
+        let e = run_regtest(&dir, &["send", &watched, &from, "10", "--mine"]).unwrap_err();
+        assert!(e.to_string().contains("is watch-only"), "{}", e);
+        let store = dir.path().join("wallets");
+        let wallets = Wallets::new(&REGTEST, store.to_str().unwrap())?;
+        assert!(wallets.is_watch_only(&watched));
+        assert!(wallets.get_wallet(&watched).is_none());
+        assert_eq!(BlockChain::new(dir.path().to_str().unwrap())?.get_best_height()?, height);
+        Ok(())
+    }
+}
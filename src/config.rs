@@ -0,0 +1,32 @@
+use crate::errors::Result;
+use failure::format_err;
+use serde::Deserialize;
+use std::fs;
+
+// settings read from the toml file given with --config, the flags given on
+// the command line override them
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // mainnet, testnet or regtest
+    pub network: Option<String>,
+    // where the chain and the wallets are kept, the one of the network when
+    // not set
+    pub data_dir: Option<String>,
+    // interface nodes listen on
+    pub bind: Option<String>,
+    // interface the json-rpc server of a node listens on
+    pub rpc_bind: Option<String>,
+    // nodes to bootstrap from, the seeds of the network when empty
+    pub seeds: Vec<String>,
+    // a node started with one mines to it
+    pub mining_address: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format_err!("cannot read config {}: {}", path, e))?;
+        toml::from_str(&text).map_err(|e| format_err!("invalid config {}: {}", path, e))
+    }
+}
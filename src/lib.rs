@@ -1,9 +1,18 @@
 pub mod block;
 pub mod blockchain;
 pub mod cli;
+pub mod config;
 pub mod errors;
+pub mod mempool;
+pub mod network;
+pub mod rpc;
 pub mod transaction;
 pub mod tx;
 pub mod utxoset;
 pub mod wallet;
-pub mod server;
\ No newline at end of file
+pub mod server;
+pub mod signature;
+pub mod storage;
+
+#[cfg(test)]
+mod test_utils;
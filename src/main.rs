@@ -1,8 +1,17 @@
+use std::io::IsTerminal;
+
 use blockchain::cli::Cli;
 use blockchain::errors::Result;
+use tracing_subscriber::EnvFilter;
 
 fn main() -> Result<()> {
-    env_logger::init();
+    // filtered by RUST_LOG as before, on stderr so it stays apart from the
+    // output of the commands, colored only for a terminal
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .init();
     let mut cli = Cli::new()?;
     cli.run()
 }
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use failure::format_err;
+
+use crate::{blockchain::BlockChain, errors::Result, storage::open_sled, transaction::Transaction};
+
+// summary of a pending transaction
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub txid: String,
+    // serialized size in bytes
+    pub size: usize,
+    // none while the spent outputs are not in the chain yet
+    pub fee: Option<u64>,
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+// transactions waiting to be mined, at most one of them may spend an output
+#[derive(Debug, Default)]
+pub struct Mempool {
+    transactions: HashMap<String, Transaction>,
+    // (txid, vout) of every output spent by a pending transaction, to the
+    // id of the transaction spending it
+    spent: HashMap<(String, i32), String>,
+    // transactions are also written here so they survive a restart
+    db: Option<sled::Db>,
+}
+
+impl Mempool {
+    // a mempool kept in memory only
+    pub fn new() -> Mempool {
+        Mempool::default()
+    }
+
+    // a mempool stored in the database at `path`, reloading the transactions
+    // left pending there
+    pub fn open(path: &str) -> Result<Mempool> {
+        let db = open_sled(path)?;
+        let mut mempool = Mempool::new();
+        for kv in db.iter() {
+            let (_, v) = kv?;
+            let tx: Transaction = bincode::deserialize(&v)?;
+            mempool.insert(tx)?;
+        }
+        mempool.db = Some(db);
+        Ok(mempool)
+    }
+
+    // refuse a transaction spending an output already claimed by another one
+    pub fn insert(&mut self, tx: Transaction) -> Result<()> {
+        if self.transactions.contains_key(&tx.id) {
+            return Ok(());
+        }
+
+        for vin in &tx.vin {
+            if let Some(other) = self.spent.get(&(vin.txid.clone(), vin.vout)) {
+                return Err(format_err!(
+                    "transaction {} spends output {}:{} already spent by {}",
+                    tx.id,
+                    vin.txid,
+                    vin.vout,
+                    other
+                ));
+            }
+        }
+
+        for vin in &tx.vin {
+            self.spent.insert((vin.txid.clone(), vin.vout), tx.id.clone());
+        }
+        if let Some(db) = &self.db {
+            db.insert(tx.id.as_bytes(), bincode::serialize(&tx)?)?;
+            db.flush()?;
+        }
+        self.transactions.insert(tx.id.clone(), tx);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, txid: &str) -> Result<Option<Transaction>> {
+        let tx = match self.transactions.remove(txid) {
+            Some(tx) => tx,
+            None => return Ok(None),
+        };
+        for vin in &tx.vin {
+            self.spent.remove(&(vin.txid.clone(), vin.vout));
+        }
+        if let Some(db) = &self.db {
+            db.remove(txid)?;
+            db.flush()?;
+        }
+        Ok(Some(tx))
+    }
+
+    pub fn get(&self, txid: &str) -> Option<&Transaction> {
+        self.transactions.get(txid)
+    }
+
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.transactions.values()
+    }
+
+    // the pending transactions, their fees are computed against `blockchain`
+    pub fn entries(&self, blockchain: &BlockChain) -> Result<Vec<MempoolEntry>> {
+        let mut entries = Vec::new();
+        for tx in self.transactions.values() {
+            entries.push(MempoolEntry {
+                txid: tx.id.clone(),
+                size: bincode::serialized_size(tx)? as usize,
+                fee: blockchain.get_transaction_fee(tx).ok(),
+                inputs: tx.vin.len(),
+                outputs: tx.vout.len(),
+            });
+        }
+        entries.sort_by(|a, b| a.txid.cmp(&b.txid));
+        Ok(entries)
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        self.transactions.clear();
+        self.spent.clear();
+        if let Some(db) = &self.db {
+            db.clear()?;
+            db.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::REGTEST;
+    use crate::test_utils::{node_chain, utxo_chain, wallet};
+
+    #[test]
+    fn a_second_spend_of_an_output_is_refused() -> Result<()> {
+        let miner = wallet(1);
+        let utxo = utxo_chain(&miner.get_address(&REGTEST))?;
+        let first = Transaction::new_utxo(&miner, &wallet(2).get_address(&REGTEST), 10, &utxo)?;
+        let second = Transaction::new_utxo(&miner, &wallet(3).get_address(&REGTEST), 20, &utxo)?;
+        assert_eq!(first.vin[0].txid, second.vin[0].txid);
+
+        let mut mempool = Mempool::new();
+        mempool.insert(first.clone())?;
+        let e = mempool.insert(second.clone()).unwrap_err();
+        assert!(e.to_string().contains("already spent by"), "{}", e);
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.get(&second.id).is_none());
+
+        // the output is free again once the first one leaves
+        mempool.remove(&first.id)?;
+        mempool.insert(second.clone())?;
+        assert!(mempool.get(&second.id).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn entries_list_each_transaction_with_its_inputs_and_outputs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut utxo = node_chain(&dir)?;
+        utxo.mine_block(&wallet(2).get_address(&REGTEST), Vec::new())?;
+        let payee = wallet(3).get_address(&REGTEST);
+        // the whole genesis coinbase leaves no change
+        let whole = Transaction::new_utxo(&wallet(1), &payee, 100, &utxo)?;
+        let part = Transaction::new_utxo(&wallet(2), &payee, 10, &utxo)?;
+
+        let mut mempool = Mempool::new();
+        mempool.insert(whole.clone())?;
+        mempool.insert(part.clone())?;
+        let entries = mempool.entries(&utxo.blockchain)?;
+        let mut expected = vec![(whole.id.clone(), 1, 1), (part.id.clone(), 1, 2)];
+        expected.sort();
+        let listed: Vec<_> =
+            entries.iter().map(|e| (e.txid.clone(), e.inputs, e.outputs)).collect();
+        assert_eq!(listed, expected);
+        assert!(entries.iter().all(|e| e.fee == Some(0) && e.size > 0));
+        Ok(())
+    }
+}
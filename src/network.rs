@@ -0,0 +1,145 @@
+use crate::{block::TARGET_HEXT, errors::Result};
+use failure::format_err;
+
+// what sets the chains, addresses and nodes of a network apart from those of
+// the others
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkParams {
+    pub name: &'static str,
+    // coinbase data of the genesis block
+    pub genesis_data: &'static str,
+    // first byte of the base58check addresses
+    pub address_version: u8,
+    // first bytes of every message, nodes drop messages from another network
+    pub magic: [u8; 4],
+    // leading hex zeros the hash of every block must have
+    pub difficulty: usize,
+    pub default_port: &'static str,
+    // nodes to bootstrap from when none are configured
+    pub seeds: &'static [&'static str],
+    // root directory of the databases and wallets, unless a config file sets
+    // another
+    pub data_dir: &'static str,
+}
+
+pub const MAINNET: NetworkParams = NetworkParams {
+    name: "mainnet",
+    genesis_data: "Genesis Block",
+    address_version: 0x05,
+    magic: [0xf9, 0xbe, 0xb4, 0xd9],
+    difficulty: TARGET_HEXT,
+    default_port: "3000",
+    seeds: &["localhost:3000"],
+    data_dir: "data",
+};
+
+pub const TESTNET: NetworkParams = NetworkParams {
+    name: "testnet",
+    genesis_data: "Testnet Genesis Block",
+    address_version: 0xc4,
+    magic: [0x0b, 0x11, 0x09, 0x07],
+    difficulty: TARGET_HEXT,
+    default_port: "13000",
+    seeds: &["localhost:13000"],
+    data_dir: "data/testnet",
+};
+
+// a network of local nodes only, it has no seeds. blocks are found at once
+// and `generate` mines them on demand
+pub const REGTEST: NetworkParams = NetworkParams {
+    name: "regtest",
+    genesis_data: "Regtest Genesis Block",
+    address_version: 0x6f,
+    magic: [0xfa, 0xbf, 0xb5, 0xda],
+    difficulty: 1,
+    default_port: "23000",
+    seeds: &[],
+    data_dir: "data/regtest",
+};
+
+pub const NETWORKS: [NetworkParams; 3] = [MAINNET, TESTNET, REGTEST];
+
+impl NetworkParams {
+    pub fn by_name(name: &str) -> Result<NetworkParams> {
+        NETWORKS
+            .iter()
+            .find(|params| params.name == name)
+            .copied()
+            .ok_or_else(|| format_err!("unknown network {}", name))
+    }
+
+    // the address paying to a public key hash
+    pub fn encode_address(&self, pub_key_hash: &[u8]) -> String {
+        bs58::encode(pub_key_hash)
+            .with_check_version(self.address_version)
+            .into_string()
+    }
+
+    // the public key hash an address pays to. the checksum and the version
+    // byte are checked so a mistyped address, or one of another network,
+    // cannot lock coins to a hash nobody holds the key of
+    pub fn decode_address(&self, address: &str) -> Result<Vec<u8>> {
+        let data = match bs58::decode(address).with_check(None).into_vec() {
+            Ok(data) => data,
+            Err(bs58::decode::Error::InvalidChecksum { .. }) => {
+                return Err(format_err!("invalid address {}: wrong checksum", address))
+            }
+            Err(e) => return Err(format_err!("invalid address {}: {}", address, e)),
+        };
+        if data.len() != 21 {
+            return Err(format_err!("invalid address {}: wrong length", address));
+        }
+        if data[0] != self.address_version {
+            return match NETWORKS.iter().find(|params| params.address_version == data[0]) {
+                Some(other) => Err(format_err!(
+                    "invalid address {}: it is a {} address, not a {} one",
+                    address,
+                    other.name,
+                    self.name
+                )),
+                None => Err(format_err!(
+                    "invalid address {}: unknown version byte {:#04x}",
+                    address,
+                    data[0]
+                )),
+            };
+        }
+        Ok(data[1..].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_address_is_only_valid_on_its_network() -> Result<()> {
+        let pub_key_hash = vec![7; 20];
+        for params in NETWORKS {
+            let address = params.encode_address(&pub_key_hash);
+            assert_eq!(params.decode_address(&address)?, pub_key_hash);
+            for other in NETWORKS.iter().filter(|other| other.name != params.name) {
+                let e = other.decode_address(&address).unwrap_err();
+                let expected = format!("it is a {} address, not a {} one", params.name, other.name);
+                assert!(e.to_string().contains(&expected), "{}", e);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn an_address_with_a_changed_character_fails_the_checksum() -> Result<()> {
+        let pub_key_hash = vec![7; 20];
+        let address = REGTEST.encode_address(&pub_key_hash);
+        assert_eq!(REGTEST.decode_address(&address)?, pub_key_hash);
+
+        for position in [5, address.len() / 2, address.len() - 1] {
+            let mut corrupted: Vec<char> = address.chars().collect();
+            corrupted[position] = if corrupted[position] == 'z' { 'y' } else { 'z' };
+            let corrupted: String = corrupted.into_iter().collect();
+            let e = REGTEST.decode_address(&corrupted).unwrap_err();
+            assert!(e.to_string().contains("wrong checksum"), "{}", e);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,351 @@
+use std::io::Read;
+
+use failure::format_err;
+use tracing::info;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::{
+    block::Block,
+    blockchain::BlockTemplate,
+    errors::Result,
+    server::{Server, ACCEPT_INTERVAL},
+    transaction::Transaction,
+};
+
+// requests with a larger body are refused
+const MAX_REQUEST_SIZE: u64 = 1024 * 1024;
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const SERVER_ERROR: i32 = -32000;
+
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i32, message: impl ToString) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<failure::Error> for RpcError {
+    fn from(e: failure::Error) -> RpcError {
+        RpcError::new(SERVER_ERROR, e)
+    }
+}
+
+// json-rpc over http for the tools that don't speak the peer protocol, it
+// runs until the node is stopped
+pub(crate) fn serve(node: Server, address: &str) -> Result<()> {
+    let http = tiny_http::Server::http(address)
+        .map_err(|e| format_err!("cannot start rpc server on {}: {}", address, e))?;
+    info!("rpc server listen on {}", address);
+
+    while !node.is_stopped() {
+        if let Some(request) = http.recv_timeout(ACCEPT_INTERVAL)? {
+            if let Err(e) = handle_request(&node, request) {
+                info!("rpc request failed: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_request(node: &Server, mut request: Request) -> Result<()> {
+    let body = if *request.method() != Method::Post {
+        error_response(
+            Value::Null,
+            RpcError::new(INVALID_REQUEST, "expected a POST request"),
+        )
+    } else {
+        let mut data = String::new();
+        request
+            .as_reader()
+            .take(MAX_REQUEST_SIZE)
+            .read_to_string(&mut data)?;
+        handle_call(node, &data)
+    };
+
+    let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .map_err(|_| format_err!("invalid header"))?;
+    request.respond(Response::from_string(body.to_string()).with_header(header))?;
+    Ok(())
+}
+
+fn handle_call(node: &Server, data: &str) -> Value {
+    let call: Value = match serde_json::from_str(data) {
+        Ok(call) => call,
+        Err(e) => return error_response(Value::Null, RpcError::new(PARSE_ERROR, e)),
+    };
+    let id = call.get("id").cloned().unwrap_or(Value::Null);
+    let method = match call.get("method").and_then(Value::as_str) {
+        Some(method) => method,
+        None => return error_response(id, RpcError::new(INVALID_REQUEST, "missing method")),
+    };
+    let params = match call.get("params") {
+        Some(Value::Array(params)) => params.clone(),
+        None | Some(Value::Null) => Vec::new(),
+        Some(_) => {
+            return error_response(id, RpcError::new(INVALID_PARAMS, "params must be an array"))
+        }
+    };
+
+    match dispatch(node, method, &params) {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(e) => error_response(id, e),
+    }
+}
+
+fn dispatch(node: &Server, method: &str, params: &[Value]) -> std::result::Result<Value, RpcError> {
+    match method {
+        "getbalance" => {
+            let address = string_param(params, 0)?;
+            Ok(json!(node.get_balance(&address)?))
+        }
+        "getblockcount" => Ok(json!(node.get_block_count()?)),
+        "getchaininfo" => Ok(json!(node.get_chain_info()?)),
+        "getblock" => {
+            let hash = string_param(params, 0)?;
+            Ok(block_to_json(&node.find_block(&hash)?))
+        }
+        "getpeerinfo" => Ok(json!(node.get_peer_info())),
+        "getdifficulty" => Ok(json!(node.get_difficulty())),
+        "getmininginfo" => Ok(json!(node.get_mining_info())),
+        "getconfirmations" => {
+            let txid = string_param(params, 0)?;
+            Ok(json!(node.get_confirmations(&txid)?))
+        }
+        "getmempool" => {
+            let entries: Vec<Value> = node
+                .get_mempool_entries()?
+                .into_iter()
+                .map(|entry| {
+                    json!({
+                        "txid": entry.txid,
+                        "size": entry.size,
+                        "fee": entry.fee,
+                        "inputs": entry.inputs,
+                        "outputs": entry.outputs,
+                    })
+                })
+                .collect();
+            Ok(json!(entries))
+        }
+        "addnode" => {
+            let address = string_param(params, 0)?;
+            node.add_peer(&address)?;
+            Ok(Value::Null)
+        }
+        "getblocktemplate" => {
+            let address = string_param(params, 0)?;
+            template_to_json(&node.get_block_template(&address)?)
+        }
+        "submitblock" => {
+            let raw = string_param(params, 0)?;
+            let block = Block::from_hex(&raw).map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+            let hash = block.get_hash();
+            node.submit_block(block)?;
+            Ok(json!(hash))
+        }
+        "generate" => {
+            let count = count_param(params, 0)?;
+            let address = string_param(params, 1)?;
+            Ok(json!(node.generate(count, &address)?))
+        }
+        "sendrawtransaction" => {
+            let raw = string_param(params, 0)?;
+            let tx = Transaction::from_hex(&raw).map_err(|e| RpcError::new(INVALID_PARAMS, e))?;
+            let id = tx.id.clone();
+            node.submit_transaction(tx)?;
+            Ok(json!(id))
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method {}", method),
+        )),
+    }
+}
+
+fn count_param(params: &[Value], index: usize) -> std::result::Result<usize, RpcError> {
+    match params.get(index).and_then(Value::as_u64) {
+        Some(value) => Ok(value as usize),
+        None => Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("expected a number as parameter {}", index),
+        )),
+    }
+}
+
+fn string_param(params: &[Value], index: usize) -> std::result::Result<String, RpcError> {
+    match params.get(index).and_then(Value::as_str) {
+        Some(value) => Ok(value.to_string()),
+        None => Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("expected a string as parameter {}", index),
+        )),
+    }
+}
+
+fn block_to_json(block: &Block) -> Value {
+    let txids: Vec<String> = block
+        .get_transactions()
+        .iter()
+        .map(|tx| tx.id.clone())
+        .collect();
+    json!({
+        "hash": block.get_hash(),
+        "prev_block_hash": block.get_prev_hash(),
+        "height": block.get_height(),
+        "timestamp": block.get_timestamp() as u64,
+        "time": block.datetime(),
+        "merkle_root": hex::encode(block.get_merkle_root()),
+        "nonce": block.get_nonce(),
+        "difficulty": block.get_difficulty(),
+        "transactions": txids,
+    })
+}
+
+// the transactions as hex like sendrawtransaction takes them, the coinbase first
+fn template_to_json(template: &BlockTemplate) -> std::result::Result<Value, RpcError> {
+    let transactions = template
+        .transactions
+        .iter()
+        .map(Transaction::to_hex)
+        .collect::<Result<Vec<String>>>()?;
+    Ok(json!({
+        "prev_block_hash": template.prev_block_hash,
+        "height": template.height,
+        "difficulty": template.difficulty,
+        "merkle_root": hex::encode(&template.merkle_root),
+        "transactions": transactions,
+    }))
+}
+
+fn error_response(id: Value, e: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": e.code, "message": e.message },
+        "id": id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockChain;
+    use crate::network::{NetworkParams, REGTEST};
+    use crate::server::ServerConfig;
+    use crate::utxoset::Utxoset;
+    use crate::test_utils::{free_port, node_chain, wallet};
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    // post a call without parameters to the json-rpc server at `address`,
+    // waiting for it to listen
+    fn call(address: &str, method: &str) -> Result<Value> {
+        let body = json!({ "jsonrpc": "2.0", "method": method, "id": 1 }).to_string();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut stream = loop {
+            match TcpStream::connect(address) {
+                Ok(stream) => break stream,
+                Err(e) if Instant::now() > deadline => return Err(e.into()),
+                Err(_) => thread::sleep(Duration::from_millis(50)),
+            }
+        };
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            address,
+            body.len(),
+            body
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let (_, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| format_err!("no body in {}", response))?;
+        Ok(serde_json::from_str(body)?)
+    }
+
+    #[test]
+    fn a_node_answers_getblockcount_on_the_loopback() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let rpc_port = free_port()?;
+        let mut config = ServerConfig::new(&free_port()?, &REGTEST);
+        config.rpc_port = Some(rpc_port.clone());
+        let node = Server::new(config, node_chain(&dir)?)?;
+        node.generate(2, &wallet(2).get_address(&REGTEST))?;
+
+        let running = node.clone();
+        let started = thread::spawn(move || running.start());
+        let response = call(&format!("127.0.0.1:{}", rpc_port), "getblockcount");
+        node.stop();
+        started.join().unwrap()?;
+        assert_eq!(response?["result"], json!(2));
+        Ok(())
+    }
+
+    #[test]
+    fn getpeerinfo_lists_each_peer_with_its_last_seen_time() -> Result<()> {
+        let now = || SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs());
+        let before = now()?;
+        let dir = tempfile::tempdir()?;
+        let mut config = ServerConfig::new("0", &REGTEST);
+        config.seeds = vec!["10.0.0.2:3000".to_string()];
+        let node = Server::new(config, node_chain(&dir)?)?;
+        let peer = TcpListener::bind("127.0.0.1:0")?;
+        let added = peer.local_addr()?.to_string();
+        node.add_peer(&added)?;
+
+        let peers = dispatch(&node, "getpeerinfo", &[]).map_err(|e| format_err!("{}", e.message))?;
+        let after = now()?;
+        let peers = peers.as_array().unwrap();
+        let listed: Vec<(&str, bool)> = peers
+            .iter()
+            .map(|peer| (peer["address"].as_str().unwrap(), peer["seed"].as_bool().unwrap()))
+            .collect();
+        let mut expected = vec![("10.0.0.2:3000", true), (added.as_str(), false)];
+        expected.sort();
+        assert_eq!(listed, expected);
+        for peer in peers {
+            let last_seen = peer["last_seen"].as_u64().unwrap();
+            assert!(before <= last_seen && last_seen <= after, "{}", peer);
+            assert_eq!(peer["responsive"], json!(true));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn the_difficulty_reported_is_the_configured_one() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let params = NetworkParams {
+            difficulty: 2,
+            ..REGTEST
+        };
+        let miner = wallet(3).get_address(&params);
+        let data_dir = dir.path().to_str().unwrap();
+        let bc = BlockChain::create_blockchain(miner.clone(), data_dir, &params)?;
+        let mut config = ServerConfig::new("0", &params);
+        config.mining_address = miner.clone();
+        let node = Server::new(config, Utxoset::open(bc)?)?;
+        let call = |method| dispatch(&node, method, &[]).map_err(|e| format_err!("{}", e.message));
+
+        assert_eq!(call("getdifficulty")?, json!(2));
+        let info = call("getmininginfo")?;
+        assert_eq!(info["difficulty"], json!(2));
+        assert_eq!(info["mining"], json!(true));
+        assert_eq!(info["mining_address"], json!(miner));
+        assert_eq!(info["mempool_size"], json!(0));
+        Ok(())
+    }
+}
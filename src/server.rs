@@ -1,40 +1,265 @@
 use std::{
-    collections::{HashMap, HashSet},
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    io::{ErrorKind, Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, RwLock,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
     vec,
 };
 
+use crypto::{digest::Digest, sha2::Sha256};
 use failure::format_err;
-use log::{debug, info};
+use tracing::{debug, field, info, info_span, Span};
+use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 
-use crate::{block::Block, errors::Result, transaction::Transaction, utxoset::Utxoset};
+use crate::{
+    block::{Block, BlockHeader, MiningStats},
+    blockchain::{BlockEvent, BlockTemplate, ChainInfo, InvalidBlock, TipChange},
+    errors::Result,
+    mempool::{Mempool, MempoolEntry},
+    network::{NetworkParams, REGTEST},
+    rpc,
+    storage::open_sled,
+    transaction::Transaction,
+    utxoset::Utxoset,
+};
+
+const VERSION: i32 = 2;
+// first version answering getheaders, older nodes are synced block by block
+const HEADERS_VERSION: i32 = 2;
+// headers sent in one message, a full message means there are more
+const MAX_HEADERS: usize = 2000;
+// how often the listener checks for a shutdown when no peer connects
+pub(crate) const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+// how often the mining thread looks for transactions when none arrive
+const MINE_INTERVAL: Duration = Duration::from_secs(1);
+// a peer that stalls longer than this on a connection is dropped
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+// frames announcing a larger payload are rejected before reading it
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+// blocks kept while their parent is fetched, more are dropped
+const MAX_ORPHAN_BLOCKS: usize = 100;
+// how often the known nodes are pinged
+pub const PING_INTERVAL: Duration = Duration::from_secs(60);
+// a node that doesn't answer a ping within this time is dropped
+pub const PING_TIMEOUT: Duration = Duration::from_secs(30);
+// known nodes kept when no other maximum is configured
+pub const MAX_PEERS: usize = 32;
+// addresses of an addr message taken in, the others are dropped
+const MAX_ADDR_PER_MESSAGE: usize = 10;
+// blocks mined by this node the reported hashrate is averaged over
+const RECENT_MINED_BLOCKS: usize = 10;
+// blocks requested at once while syncing when no other window is configured
+pub const DOWNLOAD_WINDOW: usize = 16;
+// a requested block that doesn't arrive within this time is requested again
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+// a peer whose misbehavior adds up to this score is banned for BAN_TIME
+const BAN_THRESHOLD: u32 = 100;
+const BAN_TIME: Duration = Duration::from_secs(24 * 60 * 60);
+// scores of the misbehaviors
+const MALFORMED_MESSAGE_SCORE: u32 = 20;
+const INVALID_BLOCK_SCORE: u32 = 50;
+const INVALID_TX_SCORE: u32 = 10;
+
+// interface the listener binds to when none is configured
+pub const DEFAULT_BIND: &str = "0.0.0.0";
+// the json-rpc server asks for no credentials, it is only reachable from
+// the host unless another interface is configured
+pub const DEFAULT_RPC_BIND: &str = "127.0.0.1";
+
+pub struct ServerConfig {
+    pub port: String,
+    // interface to listen on, an ip address or host name without the port
+    pub bind: String,
+    // address announced to peers, localhost:<port> when not set
+    pub advertise: Option<String>,
+    // wallet address for mining rewards, empty for a node that does not mine
+    pub mining_address: String,
+    // nodes to bootstrap from, the seeds of the network when empty
+    pub seeds: Vec<String>,
+    // network of the node, its chain must be one of it
+    pub params: NetworkParams,
+    // port of the json-rpc server, none to not serve it
+    pub rpc_port: Option<String>,
+    // interface the json-rpc server listens on
+    pub rpc_bind: String,
+    pub ping_interval: Duration,
+    pub ping_timeout: Duration,
+    // known nodes kept, the seeds included
+    pub max_peers: usize,
+    // blocks requested at once while syncing
+    pub download_window: usize,
+}
+
+impl ServerConfig {
+    pub fn new(port: &str, params: &NetworkParams) -> ServerConfig {
+        ServerConfig {
+            port: port.to_string(),
+            bind: String::from(DEFAULT_BIND),
+            advertise: None,
+            mining_address: String::new(),
+            seeds: Vec::new(),
+            params: *params,
+            rpc_port: None,
+            rpc_bind: String::from(DEFAULT_RPC_BIND),
+            ping_interval: PING_INTERVAL,
+            ping_timeout: PING_TIMEOUT,
+            max_peers: MAX_PEERS,
+            download_window: DOWNLOAD_WINDOW,
+        }
+    }
+
+    // the seeds of the network when none are given
+    fn seeds(&self) -> Vec<String> {
+        if self.seeds.is_empty() {
+            self.params.seeds.iter().map(|seed| seed.to_string()).collect()
+        } else {
+            self.seeds.clone()
+        }
+    }
 
-const KNOWN_NODE_1: &str = "localhost:3000";
-const CMD_LEN: usize = 12;
-const VERSION: i32 = 1;
+    fn node_address(&self) -> String {
+        match &self.advertise {
+            Some(address) => address.clone(),
+            None => format!("localhost:{}", self.port),
+        }
+    }
+}
 
+#[derive(Clone)]
 pub struct Server {
     // current node address
     node_address: String,
+    // address the listener binds to
+    bind_address: String,
+    // address the json-rpc server binds to
+    rpc_address: Option<String>,
+    // network the node belongs to
+    params: NetworkParams,
+    // wakes the mining thread, set once the server is started as a miner
+    miner: Option<Sender<()>>,
+    // set by stop()
+    shutdown: Arc<AtomicBool>,
+    // set when the tip moves or the server stops, the block being mined is
+    // then given up
+    interrupt_mining: Arc<AtomicBool>,
     // wallet address for mining rewards
     mining_address: String,
-    inner: Arc<Mutex<ServerInner>>,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    max_peers: usize,
+    download_window: usize,
+    inner: Arc<RwLock<ServerInner>>,
 }
 
 pub struct ServerInner {
     // store collections the current peer nodes
-    known_nodes: HashSet<String>,
+    known_nodes: HashMap<String, Peer>,
+    // bootstrap nodes, they relay the transactions they receive
+    seeds: Vec<String>,
+    // the known nodes as stored in the data directory, reloaded on restart
+    peers: sled::Db,
     // hold state of all unspent transaction outputs
     utxo: Utxoset,
-    // keep track of the hashes from other peer nodes, that're not processed yet
-    blocks_in_transit: Vec<String>,
+    // blocks to fetch, parents first, with the peer that announced them
+    blocks_in_transit: Vec<(String, String)>,
+    // blocks requested and not received yet, with the peer and time of the request
+    blocks_requested: HashMap<String, (String, Instant)>,
+    // hashes of the checked headers whose block isn't stored yet, the next
+    // headers may build on them
+    pending_headers: HashSet<String>,
     // received and validated by this node
-    mempool: HashMap<String, Transaction>,
+    mempool: Mempool,
+    // received blocks whose parent is unknown, keyed by the parent hash
+    orphans: HashMap<String, Vec<Block>>,
+    // misbehavior of the peers not banned yet
+    ban_scores: HashMap<PeerId, u32>,
+    // banned peers with the end of their ban
+    banned: HashMap<PeerId, Instant>,
+    // of the last blocks the mining thread found, oldest first
+    recent_mined: VecDeque<MiningStats>,
+}
+
+impl ServerInner {
+    // the block is queued, requested or waiting for its parent
+    fn is_block_pending(&self, hash: &str) -> bool {
+        self.blocks_requested.contains_key(hash)
+            || self.blocks_in_transit.iter().any(|(h, _)| h == hash)
+            || self.orphans.values().flatten().any(|b| b.get_hash() == hash)
+    }
+}
+
+// a peer as the ban scores know it, by the ip it connects from and the
+// address it announces so that nodes sharing a host don't share a score. a
+// message that cannot be read announces none
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PeerId {
+    ip: IpAddr,
+    addr: Option<String>,
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.addr {
+            Some(addr) => write!(f, "{} from {}", addr, self.ip),
+            None => write!(f, "{}", self.ip),
+        }
+    }
+}
+
+// what the node knows of a peer
+#[derive(Debug, Clone)]
+struct Peer {
+    // when it was added or last answered a ping
+    last_seen: SystemTime,
+    // nonce and time of the ping it hasn't answered yet
+    ping: Option<(u64, Instant)>,
+    // it answered a ping or was added by the operator, false for a node we
+    // were only told about
+    connected: bool,
+}
+
+impl Peer {
+    fn new() -> Peer {
+        Peer {
+            last_seen: SystemTime::now(),
+            ping: None,
+            connected: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerInfo {
+    pub address: String,
+    // unix time in seconds
+    pub last_seen: u64,
+    pub seed: bool,
+    // false while a ping is waiting for its pong
+    pub responsive: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MiningInfo {
+    // leading hex zeros the next block needs
+    pub difficulty: usize,
+    // whether the node runs its mining thread, paying mining_address
+    pub mining: bool,
+    pub mining_address: Option<String>,
+    pub mempool_size: usize,
+    // the last block this node mined, how many nonces it took and how long
+    pub last_block: Option<String>,
+    pub last_attempts: Option<u64>,
+    pub last_time_ms: Option<u128>,
+    // hashes per second over the last RECENT_MINED_BLOCKS mined blocks
+    pub hashrate: Option<f64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -48,6 +273,19 @@ struct GetBlockMsg {
     addr_from: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetHeadersMsg {
+    addr_from: String,
+    // see BlockChain::get_block_locator
+    locator: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct HeadersMsg {
+    addr_from: String,
+    headers: Vec<BlockHeader>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct GetDataMsg {
     addr_from: String,
@@ -68,6 +306,14 @@ struct TxMsg {
     transaction: Transaction,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PingMsg {
+    addr_from: String,
+    // echoed by the pong, the node may answer from another address than
+    // the one it was pinged at
+    nonce: u64,
+}
+
 // used for initial handshake
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct VersionMsg {
@@ -92,110 +338,406 @@ enum Message {
     Inv(InvMsg),
     // block message
     Block(BlockMsg),
+    // heartbeat, answered with a pong
+    Ping(PingMsg),
+    Pong(PingMsg),
+    // headers of the best chain after the locator, answered with headers
+    GetHeaders(GetHeadersMsg),
+    Headers(HeadersMsg),
+}
+
+impl Message {
+    // tags the span of the connection it came in on
+    fn kind(&self) -> &'static str {
+        match self {
+            Message::Addr(_) => "addr",
+            Message::Version(_) => "version",
+            Message::Tx(_) => "tx",
+            Message::GetData(_) => "getdata",
+            Message::GetBlock(_) => "getblock",
+            Message::Inv(_) => "inv",
+            Message::Block(_) => "block",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::GetHeaders(_) => "getheaders",
+            Message::Headers(_) => "headers",
+        }
+    }
+
+    // the address the sender listens on, as it announces it
+    fn addr_from(&self) -> Option<&str> {
+        match self {
+            Message::Addr(_) => None,
+            Message::Version(msg) => Some(&msg.addr_from),
+            Message::Tx(msg) => Some(&msg.addr_from),
+            Message::GetData(msg) => Some(&msg.addr_from),
+            Message::GetBlock(msg) => Some(&msg.addr_from),
+            Message::Inv(msg) => Some(&msg.addr_from),
+            Message::Block(msg) => Some(&msg.addr_from),
+            Message::Ping(msg) | Message::Pong(msg) => Some(&msg.addr_from),
+            Message::GetHeaders(msg) => Some(&msg.addr_from),
+            Message::Headers(msg) => Some(&msg.addr_from),
+        }
+    }
 }
 
 impl Server {
-    pub fn new(port: &str, minter_address: &str, utxo: Utxoset) -> Result<Server> {
-        let mut known_nodes = HashSet::new();
-        known_nodes.insert(String::from(KNOWN_NODE_1));
+    pub fn new(config: ServerConfig, utxo: Utxoset) -> Result<Server> {
+        let chain_params = utxo.blockchain.get_params();
+        if chain_params.name != config.params.name {
+            return Err(format_err!(
+                "the chain in {} is a {} one, not {}",
+                utxo.blockchain.get_data_dir(),
+                chain_params.name,
+                config.params.name
+            ));
+        }
+        if !config.mining_address.is_empty() {
+            config.params.decode_address(&config.mining_address)?;
+        }
+        let seeds = config.seeds();
+        let peers = open_sled(&format!("{}/peers", utxo.blockchain.get_data_dir()))?;
+        let mut known_nodes = HashMap::new();
+        for kv in peers.iter().take(config.max_peers) {
+            let (k, _) = kv?;
+            known_nodes.insert(String::from_utf8(k.to_vec())?, Peer::new());
+        }
+        // nothing learned yet, start from the seeds
+        if known_nodes.is_empty() {
+            known_nodes.extend(seeds.iter().map(|seed| (seed.clone(), Peer::new())));
+        }
+        let mempool = Mempool::open(&format!("{}/mempool", utxo.blockchain.get_data_dir()))?;
         Ok(Server {
-            node_address: format!("localhost:{}", port),
-            mining_address: minter_address.to_string(),
-            inner: Arc::new(Mutex::new(ServerInner {
-                known_nodes: known_nodes,
+            node_address: config.node_address(),
+            bind_address: host_port(&config.bind, &config.port),
+            rpc_address: config.rpc_port.map(|port| host_port(&config.rpc_bind, &port)),
+            params: config.params,
+            miner: None,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            interrupt_mining: Arc::new(AtomicBool::new(false)),
+            mining_address: config.mining_address,
+            ping_interval: config.ping_interval,
+            ping_timeout: config.ping_timeout,
+            max_peers: config.max_peers,
+            download_window: config.download_window,
+            inner: Arc::new(RwLock::new(ServerInner {
+                known_nodes,
+                seeds,
+                peers,
                 utxo,
                 blocks_in_transit: Vec::new(),
-                mempool: HashMap::new(),
+                blocks_requested: HashMap::new(),
+                pending_headers: HashSet::new(),
+                mempool,
+                orphans: HashMap::new(),
+                ban_scores: HashMap::new(),
+                banned: HashMap::new(),
+                recent_mined: VecDeque::new(),
             })),
         })
     }
 
     pub fn start(&self) -> Result<()> {
+        let mut node = self.clone();
+        let mut workers = Vec::new();
+        if !self.mining_address.is_empty() {
+            // the mining thread holds no sender, it stops on stop() or once
+            // every connection is done and `node` is dropped
+            let miner = self.clone();
+            let (sender, receiver) = mpsc::channel();
+            node.miner = Some(sender);
+            workers.push(thread::spawn(move || miner.run_miner(receiver)));
+        }
+
+        if let Some(address) = self.rpc_address.clone() {
+            let rpc_node = node.clone();
+            workers.push(thread::spawn(move || {
+                if let Err(e) = rpc::serve(rpc_node, &address) {
+                    info!("rpc server failed: {}", e);
+                }
+            }));
+        }
+
+        let heartbeat = node.clone();
+        workers.push(thread::spawn(move || heartbeat.run_heartbeat()));
+
         // init new server instance
-        let server1 = Server {
-            node_address: self.node_address.clone(),
-            mining_address: self.mining_address.clone(),
-            inner: Arc::clone(&self.inner),
-        };
+        let server1 = node.clone();
 
         info!(
-            "start server at {}, minting address: {}",
-            &self.node_address, &self.mining_address
+            "start server at {} as {}, minting address: {}",
+            &self.bind_address, &self.node_address, &self.mining_address
         );
-        // schedule a thread to send version to master node
-        thread::spawn(move || {
+        // schedule a thread to send version to the known nodes
+        let mut connections = vec![thread::spawn(move || {
             thread::sleep(Duration::from_millis(1000));
-            if server1.get_best_height() == -1 {
+            let result = if server1.get_best_height() == -1 {
                 server1.request_blocks()
             } else {
-                server1.send_version(KNOWN_NODE_1)
+                server1.announce_version()
+            };
+            if let Err(e) = result {
+                info!("cannot reach the known nodes: {}", e);
             }
-        });
+        })];
 
-        let listener = TcpListener::bind(&self.node_address)?;
+        let listener = TcpListener::bind(&self.bind_address)?;
+        // polled so the loop notices stop()
+        listener.set_nonblocking(true)?;
         info!("Server listen...");
 
-        for stream in listener.incoming() {
-            let stream = stream?;
-            let server1 = Server {
-                node_address: self.node_address.clone(),
-                mining_address: self.mining_address.clone(),
-                inner: Arc::clone(&self.inner),
+        while !self.is_stopped() {
+            let (stream, peer) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
             };
-            thread::spawn(move || server1.handle_connection(stream));
+            stream.set_nonblocking(false)?;
+
+            let server1 = node.clone();
+            connections.retain(|c| !c.is_finished());
+            connections.push(thread::spawn(move || server1.serve_connection(stream, peer)));
+        }
+
+        info!("Server stopping...");
+        drop(listener);
+        for c in connections {
+            let _ = c.join();
         }
+        drop(node);
+        for w in workers {
+            let _ = w.join();
+        }
+        self.flush()?;
+        info!("Server stopped");
         Ok(())
     }
 
+    // what is logged while handling the message carries the peer, and the
+    // message kind once it is read
+    fn serve_connection(&self, stream: TcpStream, peer: SocketAddr) {
+        let span = info_span!("connection", %peer, kind = field::Empty);
+        let _enter = span.enter();
+        if let Err(e) = self.handle_connection(stream) {
+            info!("drop connection: {}", e);
+        }
+    }
+
+    // make start() return once the pending work is done, can be called from
+    // any clone of the server or a signal handler
+    pub fn stop(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.interrupt_mining.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.write().unwrap().utxo.flush()
+    }
+
     // handle incoming connection
     fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
-        let mut buffer = vec![];
-        let count = stream.read_to_end(&mut buffer)?;
-        info!("Accept request: length {}", count);
+        let ip = stream.peer_addr()?.ip();
+        let mut peer = PeerId { ip, addr: None };
+        if self.is_banned(&peer) {
+            return Err(format_err!("{} is banned", peer));
+        }
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let buffer = match read_frame(&mut stream, &self.params.magic, MAX_MESSAGE_SIZE) {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                // a peer that is merely slow or gone is not to blame
+                if e.downcast_ref::<std::io::Error>().is_none() {
+                    self.misbehave(&peer, MALFORMED_MESSAGE_SCORE);
+                }
+                return Err(e);
+            }
+        };
+        info!("Accept request: length {}", buffer.len());
 
-        // serialize the bytes to command
-        let cmd = bytes_to_cmd(&buffer)?;
+        let cmd: Message = match bincode::deserialize(&buffer) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                self.misbehave(&peer, MALFORMED_MESSAGE_SCORE);
+                return Err(e.into());
+            }
+        };
 
+        Span::current().record("kind", cmd.kind());
+        peer.addr = cmd.addr_from().map(str::to_string);
+        if peer.addr.is_some() && self.is_banned(&peer) {
+            return Err(format_err!("{} is banned", peer));
+        }
         match cmd {
             Message::Addr(data) => self.handle_addr(data)?,
             Message::Version(data) => self.handle_version(data)?,
-            Message::Tx(data) => self.handle_tx(data)?,
+            Message::Tx(data) => self.handle_tx(data, &peer)?,
             Message::GetData(data) => self.handle_get_data(data)?,
             Message::GetBlock(data) => self.handle_get_block(data)?,
             Message::Inv(data) => self.handle_inv(data)?,
-            Message::Block(data) => self.handle_block(data)?,
+            Message::Block(data) => self.handle_block(data, &peer)?,
+            Message::Ping(data) => self.handle_ping(data)?,
+            Message::Pong(data) => self.handle_pong(data),
+            Message::GetHeaders(data) => self.handle_get_headers(data)?,
+            Message::Headers(data) => self.handle_headers(data, &peer)?,
         }
 
         Ok(())
     }
 
-    // sync the address of the peer nodes
+    // sync the address of the peer nodes, a message only brings in a few
+    // new ones
     fn handle_addr(&self, msg: Vec<String>) -> Result<()> {
         info!("handle addr message: {:?}", msg);
-        for node in msg {
-            self.add_nodes(&node);
+        let new_nodes = msg.into_iter().filter(|node| !self.node_is_known(node));
+        for node in new_nodes.take(MAX_ADDR_PER_MESSAGE) {
+            self.add_nodes(&node, false)?;
+        }
+        Ok(())
+    }
+
+    fn handle_ping(&self, msg: PingMsg) -> Result<()> {
+        debug!("receive ping msg: {}", msg.addr_from);
+        let data = PingMsg {
+            addr_from: self.node_address.clone(),
+            nonce: msg.nonce,
+        };
+        let data = bincode::serialize(&Message::Pong(data))?;
+        self.send_data(&msg.addr_from, &data)
+    }
+
+    // the pinged node is alive, it is the last one to be evicted
+    fn handle_pong(&self, msg: PingMsg) {
+        debug!("receive pong msg: {}", msg.addr_from);
+        let known_nodes = &mut self.inner.write().unwrap().known_nodes;
+        let pinged = known_nodes
+            .values_mut()
+            .find(|peer| matches!(peer.ping, Some((nonce, _)) if nonce == msg.nonce));
+        if let Some(peer) = pinged {
+            peer.ping = None;
+            peer.last_seen = SystemTime::now();
+            peer.connected = true;
+        }
+    }
+
+    // heartbeat thread, pings the known nodes every ping interval and drops
+    // those that didn't answer within the timeout
+    fn run_heartbeat(&self) {
+        let mut next_round = Instant::now() + self.ping_interval;
+        while !self.is_stopped() {
+            thread::sleep(ACCEPT_INTERVAL);
+            if let Err(e) = self.drop_silent_nodes() {
+                info!("cannot drop silent nodes: {}", e);
+            }
+            if let Err(e) = self.retry_downloads() {
+                info!("cannot request blocks again: {}", e);
+            }
+            if Instant::now() < next_round {
+                continue;
+            }
+            next_round = Instant::now() + self.ping_interval;
+            for node in self.get_known_nodes() {
+                if let Err(e) = self.send_ping(&node) {
+                    info!("cannot ping {}: {}", node, e);
+                }
+            }
+        }
+    }
+
+    fn drop_silent_nodes(&self) -> Result<()> {
+        let silent: Vec<String> = self
+            .inner
+            .read()
+            .unwrap()
+            .known_nodes
+            .iter()
+            .filter(|(_, peer)| {
+                matches!(peer.ping, Some((_, sent)) if sent.elapsed() >= self.ping_timeout)
+            })
+            .map(|(node, _)| node.clone())
+            .collect();
+        for node in silent {
+            info!("{} didn't answer the ping, drop it", node);
+            self.remove_node(&node)?;
         }
         Ok(())
     }
 
-    fn handle_block(&self, msg: BlockMsg) -> Result<()> {
+    fn handle_block(&self, msg: BlockMsg, peer: &PeerId) -> Result<()> {
         info!(
             "receive block msg: {}, {}",
             msg.addr_from,
             msg.block.get_hash()
         );
-        self.add_block(msg.block)?;
-
-        let mut in_transit = self.get_in_transit();
-        if in_transit.len() > 0 {
-            let block_hash = &in_transit[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
-            in_transit.remove(0);
-            self.replace_in_transit(in_transit);
+        self.inner
+            .write()
+            .unwrap()
+            .blocks_requested
+            .remove(&msg.block.get_hash());
+        // no honest node sends these, whatever the state of its chain
+        if !msg.block.verify_proof_of_work()? || !msg.block.verify_merkle_root()? {
+            info!(
+                "reject block from {}: {} is invalid",
+                msg.addr_from,
+                msg.block.get_hash()
+            );
+            self.misbehave(peer, INVALID_BLOCK_SCORE);
+            return Ok(());
+        }
+        // a block arriving before its parent waits for it, the parent is
+        // fetched from the same peer unless it is on its way already
+        let parent = msg.block.get_prev_hash();
+        let mut blocks = if !parent.is_empty() && !self.has_block(&parent)? {
+            let fetching = self.inner.read().unwrap().is_block_pending(&parent);
+            if self.add_orphan(msg.block) && !fetching {
+                self.send_get_data(&msg.addr_from, "block", &parent)?;
+            }
+            // the parent may have been connected meanwhile, before this
+            // block was kept
+            if !self.has_block(&parent)? {
+                return self.request_blocks_in_transit();
+            }
+            self.take_orphans(&parent)
         } else {
-            self.utxo_reindex()?;
+            vec![msg.block]
+        };
+
+        // then the orphans waiting for it, and for them
+        while let Some(block) = blocks.pop() {
+            let hash = block.get_hash();
+            self.connect_block(block, peer);
+            blocks.extend(self.take_orphans(&hash));
+        }
+        self.request_blocks_in_transit()
+    }
+
+    fn connect_block(&self, block: Block, peer: &PeerId) {
+        self.inner
+            .write()
+            .unwrap()
+            .pending_headers
+            .remove(&block.get_hash());
+        // an invalid block is dropped but the sync goes on
+        let change = match self.add_block(block) {
+            Ok(change) => change,
+            Err(e) => {
+                info!("reject block from {}: {}", peer, e);
+                if e.downcast_ref::<InvalidBlock>().is_some() {
+                    self.misbehave(peer, INVALID_BLOCK_SCORE);
+                }
+                TipChange::default()
+            }
+        };
+        if let Err(e) = self.update_mempool(&change) {
+            info!("cannot update the mempool: {}", e);
         }
-        Ok(())
     }
 
     fn handle_get_block(&self, msg: GetBlockMsg) -> Result<()> {
@@ -205,17 +747,90 @@ impl Server {
         Ok(())
     }
 
+    fn handle_get_headers(&self, msg: GetHeadersMsg) -> Result<()> {
+        info!(
+            "receive get headers msg: {}, locator of {} hashes",
+            msg.addr_from,
+            msg.locator.len()
+        );
+        let headers = self
+            .inner
+            .read()
+            .unwrap()
+            .utxo
+            .blockchain
+            .get_headers(&msg.locator, MAX_HEADERS)?;
+        self.send_headers(&msg.addr_from, headers)
+    }
+
+    // the headers are checked as a chain before any block is fetched
+    fn handle_headers(&self, msg: HeadersMsg, peer: &PeerId) -> Result<()> {
+        info!(
+            "receive headers msg: {}, {} headers",
+            msg.addr_from,
+            msg.headers.len()
+        );
+        let (first, last) = match (msg.headers.first(), msg.headers.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok(()),
+        };
+        for (i, header) in msg.headers.iter().enumerate() {
+            let linked = i == 0 || {
+                let prev = &msg.headers[i - 1];
+                header.prev_block_hash == prev.hash && header.height == prev.height + 1
+            };
+            let mined = header.difficulty == self.params.difficulty
+                && header.verify_proof_of_work()?;
+            if !linked || !mined {
+                info!(
+                    "reject headers from {}: {} is invalid",
+                    msg.addr_from, header.hash
+                );
+                self.misbehave(peer, INVALID_BLOCK_SCORE);
+                return Ok(());
+            }
+        }
+        if !self.has_block(&first.prev_block_hash)?
+            && !self.is_header_pending(&first.prev_block_hash)
+        {
+            info!(
+                "headers from {} don't connect, {} is unknown",
+                msg.addr_from, first.prev_block_hash
+            );
+            return Ok(());
+        }
+
+        if msg.headers.len() >= MAX_HEADERS {
+            // the next ones follow the last header, before the blocks arrive
+            let mut locator = vec![last.hash.clone()];
+            locator.extend(self.get_block_locator()?);
+            self.send_get_headers(&msg.addr_from, locator)?;
+        }
+        let hashes: Vec<String> = msg.headers.iter().map(|header| header.hash.clone()).collect();
+        self.inner
+            .write()
+            .unwrap()
+            .pending_headers
+            .extend(hashes.iter().cloned());
+        self.fetch_blocks(&msg.addr_from, hashes)
+    }
+
     fn handle_get_data(&self, msg: GetDataMsg) -> Result<()> {
         info!(
             "receive get data msg: {}, kind: {}, id: {}",
             msg.addr_from, msg.kind, msg.id
         );
+        // the requested item may have been dropped since it was announced
         if msg.kind == "block" {
-            let block = self.get_block(&msg.id)?;
-            self.send_block(&msg.addr_from, &block)?
+            match self.get_block(&msg.id) {
+                Ok(block) => self.send_block(&msg.addr_from, &block)?,
+                Err(e) => info!("cannot serve block to {}: {}", msg.addr_from, e),
+            }
         } else if msg.kind == "tx" {
-            let tx = self.get_mempool_tx(&msg.id).unwrap();
-            self.send_tx(&msg.addr_from, &tx)?;
+            match self.get_mempool_tx(&msg.id) {
+                Some(tx) => self.send_tx(&msg.addr_from, &tx)?,
+                None => info!("cannot serve tx to {}: {} is not in the mempool", msg.addr_from, msg.id),
+            }
         }
         Ok(())
     }
@@ -228,8 +843,12 @@ impl Server {
         );
         let my_best_height = self.get_best_height();
         if my_best_height < msg.best_height {
-            // send getblock message to the address
-            self.send_get_blocks(&msg.addr_from)?;
+            if msg.version >= HEADERS_VERSION {
+                self.send_get_headers(&msg.addr_from, self.get_block_locator()?)?;
+            } else {
+                // send getblock message to the address
+                self.send_get_blocks(&msg.addr_from)?;
+            }
         } else if my_best_height > msg.best_height {
             // send itself version to the address
             self.send_version(&msg.addr_from)?;
@@ -239,107 +858,281 @@ impl Server {
         self.send_addr(&msg.addr_from)?;
 
         if !self.node_is_known(&msg.addr_from) {
-            self.add_nodes(&msg.addr_from);
+            self.add_nodes(&msg.addr_from, false)?;
         }
 
         Ok(())
     }
 
-    fn handle_tx(&self, msg: TxMsg) -> Result<()> {
+    fn handle_tx(&self, msg: TxMsg, peer: &PeerId) -> Result<()> {
         info!(
             "receive tx msg: {}, tx id: {}",
             msg.addr_from, msg.transaction.id
         );
+        match self.verify_tx(&msg.transaction) {
+            Ok(true) => {}
+            Ok(false) => {
+                info!(
+                    "reject tx from {}: {} has a bad signature",
+                    msg.addr_from, msg.transaction.id
+                );
+                self.misbehave(peer, INVALID_TX_SCORE);
+                return Ok(());
+            }
+            // its inputs are unknown here, which may be no fault of the peer
+            Err(e) => {
+                info!("reject tx from {}: {}", msg.addr_from, e);
+                return Ok(());
+            }
+        }
+
+        if let Err(e) = self.accept_tx(msg.transaction, &msg.addr_from) {
+            info!("reject tx from {}: {}", msg.addr_from, e);
+        }
+        Ok(())
+    }
 
-        // add the transaction to the mempool(processed or verified by current node)
-        self.insert_mempool(msg.transaction.clone());
+    // add the transaction to the mempool(processed or verified by current
+    // node), then a seed node relays it and a miner mines it
+    fn accept_tx(&self, tx: Transaction, addr_from: &str) -> Result<()> {
+        // only what the next block may hold, a locked transaction is sent
+        // again once its lock time is reached
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        if !tx.is_final(self.get_best_height() + 1, now) {
+            return Err(format_err!(
+                "transaction {} is locked until {}",
+                tx.id,
+                tx.lock_time
+            ));
+        }
+        self.insert_mempool(tx.clone())?;
 
         let known_nodes = self.get_known_nodes();
-        if self.node_address == KNOWN_NODE_1 {
-            // if the node is the master node, send inv message to all known nodes
+        if self.get_seeds().contains(&self.node_address) {
+            // if the node is a seed node, send inv message to all known nodes
             for node in known_nodes {
                 // do not send to itself or the sender
-                if node != self.node_address && node != msg.addr_from {
-                    self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
+                if node != self.node_address && node != addr_from {
+                    self.send_inv(&node, "tx", vec![tx.id.clone()])?;
                 }
             }
-        } else {
-            let mut mempool = self.get_mempool();
-            debug!("Current mempool: {:#?}", &mempool);
-            if mempool.len() >= 1 && !self.mining_address.is_empty() {
-                loop {
-                    // iterate through the mempool and verify each transaction
-                    let mut txs = vec![];
-                    for (_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
-                    }
-
-                    if txs.is_empty() {
-                        return Ok(());
-                    }
+        }
+        if let Some(miner) = &self.miner {
+            // wake the mining thread, a closed channel means it is gone
+            let _ = miner.send(());
+        }
+        Ok(())
+    }
 
-                    let cb_tx =
-                        Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
-                    txs.push(cb_tx);
+    // mining thread, mines whenever the mempool holds transactions, also
+    // those loaded at startup, a new transaction wakes it early
+    fn run_miner(&self, notifications: Receiver<()>) {
+        let _span = info_span!("miner", address = %self.mining_address).entered();
+        while !self.is_stopped() {
+            if let Err(RecvTimeoutError::Disconnected) = notifications.recv_timeout(MINE_INTERVAL) {
+                break;
+            }
+            // transactions that came in meanwhile go into the same block
+            while notifications.try_recv().is_ok() {}
+            if self.mempool_is_empty() {
+                continue;
+            }
 
-                    for tx in &txs {
-                        mempool.remove(&tx.id);
-                    }
+            if let Err(e) = self.mine_mempool() {
+                info!("mining failed: {}", e);
+            }
+        }
+    }
 
-                    // mine a new block with the transactions
-                    let new_block = self.mine_block(txs)?;
-                    self.utxo_reindex()?;
+    fn mine_mempool(&self) -> Result<()> {
+        loop {
+            // cleared before the template is built, a block connected from
+            // now on makes the template stale
+            self.interrupt_mining.store(false, Ordering::SeqCst);
+            if self.is_stopped() {
+                // what is left stays in the mempool for the next start
+                return Ok(());
+            }
 
-                    for node in self.get_known_nodes() {
-                        if node != self.node_address {
-                            // send the new block to all known nodes
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                        }
-                    }
+            let txs = self.get_minable_txs()?;
+            if txs.is_empty() {
+                // whatever is left waits for its parents or its lock time
+                return Ok(());
+            }
 
-                    if mempool.len() == 0 {
-                        break;
-                    }
+            let template = self
+                .inner
+                .read()
+                .unwrap()
+                .utxo
+                .blockchain
+                .block_template(&self.mining_address, txs.clone())?;
+
+            let _span = info_span!("block", height = template.height, txs = txs.len()).entered();
+            // the proof of work runs without the lock, so the node keeps
+            // serving its peers meanwhile
+            let (new_block, stats) = match template.mine_interruptible(&self.interrupt_mining)? {
+                Some(mined) => mined,
+                None => {
+                    info!("tip moved while mining, start over");
+                    continue;
                 }
-                self.clear_mempool();
+            };
+            let change = self.add_block(new_block.clone())?;
+            if change.connected.is_empty() {
+                info!("tip moved while mining {}, try again", new_block.get_hash());
+                continue;
+            }
+
+            self.record_mined(stats);
+            for tx in &txs {
+                self.remove_mempool(&tx.id)?;
+            }
+            self.announce_block(&new_block.get_hash())?;
+        }
+    }
+
+    // send the new block to all known nodes
+    fn announce_block(&self, hash: &str) -> Result<()> {
+        for node in self.get_known_nodes() {
+            if node != self.node_address {
+                self.send_inv(&node, "block", vec![hash.to_string()])?;
+            }
+        }
+        Ok(())
+    }
+
+    // the mempool transactions that can go into the next block. those
+    // spending outputs of other pending ones wait until these are mined, like
+    // the locked ones, those the chain refuses are dropped one by one
+    fn get_minable_txs(&self) -> Result<Vec<Transaction>> {
+        let mempool = self.get_mempool();
+        debug!("Current mempool: {:#?}", &mempool);
+        let height = self.get_best_height() + 1;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut txs = Vec::new();
+        for tx in mempool.values() {
+            if tx.vin.iter().any(|vin| mempool.contains_key(&vin.txid)) {
+                continue;
+            }
+            if !tx.is_final(height, now) {
+                continue;
+            }
+            if let Err(e) = self.check_spendable(tx) {
+                info!("drop {} from the mempool: {}", tx.id, e);
+                self.remove_mempool(&tx.id)?;
+                continue;
+            }
+            txs.push(tx.clone());
+        }
+        Ok(txs)
+    }
+
+    // the outputs the transaction spends are unspent, its signatures verify
+    // and it doesn't spend more than these outputs hold
+    fn check_spendable(&self, tx: &Transaction) -> Result<()> {
+        let inner = self.inner.read().unwrap();
+        for vin in &tx.vin {
+            if inner.utxo.get_txout(&vin.txid, vin.vout)?.is_none() {
+                return Err(format_err!(
+                    "output {}:{} is spent or unknown",
+                    vin.txid,
+                    vin.vout
+                ));
             }
         }
+        if !inner.utxo.blockchain.verify_transaction(tx)? {
+            return Err(format_err!("invalid signature"));
+        }
+        inner.utxo.blockchain.get_transaction_fee(tx)?;
         Ok(())
     }
 
     fn handle_inv(&self, msg: InvMsg) -> Result<()> {
         info!("receive inv msg: {:#?}", msg);
         if msg.kind == "block" {
-            let block_hash = &msg.items[0];
-            self.send_get_data(&msg.addr_from, "block", block_hash)?;
-
-            let mut new_in_transit = vec![];
-
-            for b in &msg.items {
-                if !self.get_in_transit().contains(b) {
-                    new_in_transit.push(b.clone());
+            // peers list hashes from their tip down, fetch the oldest first so
+            // every block arrives after its parent
+            let hashes = msg.items.into_iter().rev().collect();
+            self.fetch_blocks(&msg.addr_from, hashes)?;
+        } else if msg.kind == "tx" {
+            for tx_id in &msg.items {
+                if self.get_mempool_tx(tx_id).is_none() {
+                    self.send_get_data(&msg.addr_from, "tx", tx_id)?;
                 }
             }
-            self.replace_in_transit(new_in_transit);
-        } else if msg.kind == "tx" {
-            let tx_id = &msg.items[0];
-            match self.get_mempool_tx(tx_id) {
-                Some(tx) => {
-                    if tx.id.is_empty() {
-                        self.send_get_data(&msg.addr_from, "tx", &tx_id)?
-                    }
+        }
+        Ok(())
+    }
+
+    // queue the blocks not stored yet, parents first, they are fetched from
+    // `addr` a window at a time
+    fn fetch_blocks(&self, addr: &str, hashes: Vec<String>) -> Result<()> {
+        {
+            let mut inner = self.inner.write().unwrap();
+            for hash in hashes {
+                if inner.is_block_pending(&hash) || inner.utxo.blockchain.has_block(&hash)?
+                {
+                    continue;
                 }
-                None => self.send_get_data(&msg.addr_from, "tx", &tx_id)?,
+                inner.blocks_in_transit.push((hash, addr.to_string()));
+            }
+        }
+        self.request_blocks_in_transit()
+    }
+
+    // request queued blocks until download_window of them are on their way,
+    // they may arrive in any order
+    fn request_blocks_in_transit(&self) -> Result<()> {
+        let requests = {
+            let mut inner = self.inner.write().unwrap();
+            let mut requests = Vec::new();
+            while inner.blocks_requested.len() < self.download_window
+                && !inner.blocks_in_transit.is_empty()
+            {
+                let (hash, peer) = inner.blocks_in_transit.remove(0);
+                inner
+                    .blocks_requested
+                    .insert(hash.clone(), (peer.clone(), Instant::now()));
+                requests.push((hash, peer));
             }
+            requests
+        };
+        for (hash, peer) in requests {
+            self.send_get_data(&peer, "block", &hash)?;
         }
         Ok(())
     }
 
+    // a block requested more than DOWNLOAD_TIMEOUT ago goes back to the front
+    // of the queue, it is forgotten when its peer is gone
+    fn retry_downloads(&self) -> Result<()> {
+        {
+            let inner = &mut *self.inner.write().unwrap();
+            let stale: Vec<String> = inner
+                .blocks_requested
+                .iter()
+                .filter(|(_, (_, sent))| sent.elapsed() >= DOWNLOAD_TIMEOUT)
+                .map(|(hash, _)| hash.clone())
+                .collect();
+            for hash in stale {
+                let (peer, _) = inner.blocks_requested.remove(&hash).unwrap();
+                if inner.known_nodes.contains_key(&peer) {
+                    info!("block {} didn't arrive from {}, request it again", hash, peer);
+                    inner.blocks_in_transit.insert(0, (hash, peer));
+                }
+            }
+        }
+        self.request_blocks_in_transit()
+    }
+
+    fn has_block(&self, block_hash: &str) -> Result<bool> {
+        self.inner.read().unwrap().utxo.blockchain.has_block(block_hash)
+    }
+
     fn get_block_hashes(&self) -> Vec<String> {
         self.inner
-            .lock()
+            .read()
             .unwrap()
             .utxo
             .blockchain
@@ -350,7 +1143,7 @@ impl Server {
     fn send_addr(&self, addr: &str) -> Result<()> {
         info!("send address info to {}", addr);
         let nodes = self.get_known_nodes();
-        let data = bincode::serialize(&(Server::cmd_to_bytes("addr"), nodes))?;
+        let data = bincode::serialize(&Message::Addr(nodes.into_iter().collect()))?;
 
         self.send_data(addr, &data)
     }
@@ -362,7 +1155,7 @@ impl Server {
             addr_from: self.node_address.clone(),
             block: b.clone(),
         };
-        let data = bincode::serialize(&(Server::cmd_to_bytes("block"), data))?;
+        let data = bincode::serialize(&Message::Block(data))?;
         self.send_data(addr, &data)
     }
 
@@ -379,7 +1172,7 @@ impl Server {
             items,
         };
 
-        let data = bincode::serialize(&(Server::cmd_to_bytes("inv"), data))?;
+        let data = bincode::serialize(&Message::Inv(data))?;
         self.send_data(addr, &data)
     }
 
@@ -389,7 +1182,7 @@ impl Server {
             addr_from: self.node_address.clone(),
             transaction: tx.clone(),
         };
-        let data = bincode::serialize(&(Server::cmd_to_bytes("tx"), data))?;
+        let data = bincode::serialize(&Message::Tx(data))?;
         self.send_data(addr, &data)
     }
 
@@ -401,7 +1194,32 @@ impl Server {
             version: VERSION,
             best_height: self.get_best_height(),
         };
-        let data = bincode::serialize(&(Server::cmd_to_bytes("version"), data))?;
+        let data = bincode::serialize(&Message::Version(data))?;
+        self.send_data(addr, &data)
+    }
+
+    // a node still waiting for the pong of the last ping is not pinged again
+    fn send_ping(&self, addr: &str) -> Result<()> {
+        if addr == self.node_address {
+            return Ok(());
+        }
+        let nonce = OsRng.next_u64();
+        {
+            let known_nodes = &mut self.inner.write().unwrap().known_nodes;
+            match known_nodes.get_mut(addr) {
+                Some(peer) if peer.ping.is_none() => {
+                    // before sending, the pong may come back before send_data returns
+                    peer.ping = Some((nonce, Instant::now()));
+                }
+                _ => return Ok(()),
+            }
+        }
+        debug!("send ping message to: {}", addr);
+        let data = PingMsg {
+            addr_from: self.node_address.clone(),
+            nonce,
+        };
+        let data = bincode::serialize(&Message::Ping(data))?;
         self.send_data(addr, &data)
     }
 
@@ -411,7 +1229,27 @@ impl Server {
         let data = GetBlockMsg {
             addr_from: self.node_address.clone(),
         };
-        let data = bincode::serialize(&(Server::cmd_to_bytes("getblock"), data))?;
+        let data = bincode::serialize(&Message::GetBlock(data))?;
+        self.send_data(addr, &data)
+    }
+
+    fn send_get_headers(&self, addr: &str, locator: Vec<String>) -> Result<()> {
+        info!("send get headers message to: {}", addr);
+        let data = GetHeadersMsg {
+            addr_from: self.node_address.clone(),
+            locator,
+        };
+        let data = bincode::serialize(&Message::GetHeaders(data))?;
+        self.send_data(addr, &data)
+    }
+
+    fn send_headers(&self, addr: &str, headers: Vec<BlockHeader>) -> Result<()> {
+        info!("send {} headers to: {}", headers.len(), addr);
+        let data = HeadersMsg {
+            addr_from: self.node_address.clone(),
+            headers,
+        };
+        let data = bincode::serialize(&Message::Headers(data))?;
         self.send_data(addr, &data)
     }
 
@@ -425,96 +1263,234 @@ impl Server {
             kind: kind.to_string(),
             id: id.to_string(),
         };
-        let data = bincode::serialize(&(Server::cmd_to_bytes("getdata"), data))?;
+        let data = bincode::serialize(&Message::GetData(data))?;
         self.send_data(addr, &data)
     }
 
     // send data to the address
     fn send_data(&self, addr: &str, data: &[u8]) -> Result<()> {
-        if addr == &self.node_address {
+        if addr == self.node_address {
             return Ok(());
         }
-        let mut stream = match TcpStream::connect(addr) {
+        let mut stream = match connect(addr) {
             Ok(s) => s,
             Err(_) => {
-                self.remove_node(addr);
+                self.remove_node(addr)?;
                 return Ok(());
             }
         };
-        stream.write(data)?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        write_frame(&mut stream, &self.params.magic, data)?;
         Ok(())
     }
 
-    fn add_nodes(&self, addr: &str) {
-        self.inner
-            .lock()
-            .unwrap()
-            .known_nodes
-            .insert(addr.to_string());
+    // once max_peers nodes are known, a new one takes the place of the node
+    // heard from least recently, the seeds are never evicted. the nodes we
+    // were only told about go first, and such a node never takes the place of
+    // one we `connected` to, so an addr flood cannot push out the honest ones
+    fn add_nodes(&self, addr: &str, connected: bool) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        if let Some(peer) = inner.known_nodes.get_mut(addr) {
+            peer.connected |= connected;
+        } else {
+            if inner.known_nodes.len() >= self.max_peers {
+                let evicted = inner
+                    .known_nodes
+                    .iter()
+                    .filter(|(node, _)| !inner.seeds.contains(node))
+                    .filter(|(_, peer)| connected || !peer.connected)
+                    .min_by_key(|(_, peer)| (peer.connected, peer.last_seen))
+                    .map(|(node, _)| node.clone());
+                let evicted = match evicted {
+                    Some(node) => node,
+                    None => {
+                        info!("too many peers, drop {}", addr);
+                        return Ok(());
+                    }
+                };
+                info!("too many peers, evict {} for {}", evicted, addr);
+                inner.known_nodes.remove(&evicted);
+                inner.peers.remove(&evicted)?;
+            }
+            let peer = Peer {
+                connected,
+                ..Peer::new()
+            };
+            inner.known_nodes.insert(addr.to_string(), peer);
+        }
+        inner.peers.insert(addr, &[])?;
+        inner.peers.flush()?;
+        Ok(())
+    }
+
+    fn get_seeds(&self) -> Vec<String> {
+        self.inner.read().unwrap().seeds.clone()
     }
 
     fn get_known_nodes(&self) -> HashSet<String> {
-        self.inner.lock().unwrap().known_nodes.clone()
+        self.inner.read().unwrap().known_nodes.keys().cloned().collect()
     }
 
-    fn remove_node(&self, addr: &str) {
-        let mut inner = self.inner.lock().unwrap();
+    fn remove_node(&self, addr: &str) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
         inner.known_nodes.remove(addr);
+        inner.peers.remove(addr)?;
+        inner.peers.flush()?;
+        Ok(())
     }
 
-    fn get_in_transit(&self) -> Vec<String> {
-        self.inner.lock().unwrap().blocks_in_transit.clone()
+    // add to the ban score of a peer, the address it announced is forgotten
+    // once it's banned
+    fn misbehave(&self, peer: &PeerId, score: u32) {
+        {
+            let mut inner = self.inner.write().unwrap();
+            let total = inner.ban_scores.entry(peer.clone()).or_default();
+            *total += score;
+            info!("ban score of {} is {}", peer, total);
+            if *total < BAN_THRESHOLD {
+                return;
+            }
+            inner.ban_scores.remove(peer);
+            inner.banned.insert(peer.clone(), Instant::now() + BAN_TIME);
+        }
+        info!("ban {} for {:?}", peer, BAN_TIME);
+        if let Some(addr) = &peer.addr {
+            if let Err(e) = self.remove_node(addr) {
+                info!("cannot remove {}: {}", addr, e);
+            }
+        }
+    }
+
+    fn is_banned(&self, peer: &PeerId) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        match inner.banned.get(peer) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                inner.banned.remove(peer);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // keep a block until its parent arrives, false if it was already kept or
+    // there is no room left
+    fn add_orphan(&self, block: Block) -> bool {
+        let orphans = &mut self.inner.write().unwrap().orphans;
+        if orphans.values().map(Vec::len).sum::<usize>() >= MAX_ORPHAN_BLOCKS {
+            info!("too many orphan blocks, drop {}", block.get_hash());
+            return false;
+        }
+        let siblings = orphans.entry(block.get_prev_hash()).or_default();
+        if siblings.iter().any(|b| b.get_hash() == block.get_hash()) {
+            return false;
+        }
+        info!(
+            "keep orphan block {} until {} arrives",
+            block.get_hash(),
+            block.get_prev_hash()
+        );
+        siblings.push(block);
+        true
     }
 
-    fn replace_in_transit(&self, blocks_in_transit: Vec<String>) {
-        let bit = &mut self.inner.lock().unwrap().blocks_in_transit;
-        bit.clone_from(&blocks_in_transit);
+    // the orphans whose parent is `hash`
+    fn take_orphans(&self, hash: &str) -> Vec<Block> {
+        self.inner
+            .write()
+            .unwrap()
+            .orphans
+            .remove(hash)
+            .unwrap_or_default()
     }
 
     fn node_is_known(&self, addr: &str) -> bool {
-        self.inner.lock().unwrap().known_nodes.contains(addr)
+        self.inner.read().unwrap().known_nodes.contains_key(addr)
+    }
+
+    fn is_header_pending(&self, hash: &str) -> bool {
+        self.inner.read().unwrap().pending_headers.contains(hash)
     }
 
-    fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
+    fn get_block_locator(&self) -> Result<Vec<String>> {
+        self.inner.read().unwrap().utxo.blockchain.get_block_locator()
     }
 
     fn get_best_height(&self) -> i32 {
         self.inner
-            .lock()
+            .read()
             .unwrap()
             .utxo
             .blockchain
             .get_best_height()
-            .unwrap() as i32
+            .unwrap()
+    }
+
+    fn insert_mempool(&self, tx: Transaction) -> Result<()> {
+        self.inner.write().unwrap().mempool.insert(tx)
     }
 
-    // convert str command to bytes
-    fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
-        let mut data = [0; CMD_LEN];
-        for (i, b) in cmd.as_bytes().iter().enumerate() {
-            data[i] = *b;
+    fn remove_mempool(&self, txid: &str) -> Result<()> {
+        self.inner.write().unwrap().mempool.remove(txid)?;
+        Ok(())
+    }
+
+    // drop the transactions the new blocks confirm and bring back those of
+    // the blocks that left the best chain
+    fn update_mempool(&self, change: &TipChange) -> Result<()> {
+        let confirmed: HashSet<String> = change
+            .connected
+            .iter()
+            .flat_map(|block| block.get_transactions().iter().map(|tx| tx.id.clone()))
+            .collect();
+
+        let mempool = &mut self.inner.write().unwrap().mempool;
+        for txid in &confirmed {
+            mempool.remove(txid)?;
+        }
+        for tx in change.disconnected_transactions() {
+            if confirmed.contains(&tx.id) {
+                continue;
+            }
+            let txid = tx.id.clone();
+            match mempool.insert(tx) {
+                Ok(()) => info!("transaction {} is back in the mempool", txid),
+                Err(e) => info!("cannot return {} to the mempool: {}", txid, e),
+            }
         }
-        data
+        Ok(())
     }
 
-    fn insert_mempool(&self, tx: Transaction) {
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(), tx);
+    fn record_mined(&self, stats: MiningStats) {
+        let recent = &mut self.inner.write().unwrap().recent_mined;
+        recent.push_back(stats);
+        if recent.len() > RECENT_MINED_BLOCKS {
+            recent.pop_front();
+        }
     }
 
-    fn clear_mempool(&self) {
-        self.inner.lock().unwrap().mempool.clear();
+    fn mempool_is_empty(&self) -> bool {
+        self.inner.read().unwrap().mempool.is_empty()
     }
 
     fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
-        match self.inner.lock().unwrap().mempool.get(addr) {
-            Some(tx) => Some(tx.clone()),
-            None => None,
-        }
+        self.inner.read().unwrap().mempool.get(addr).cloned()
     }
 
     fn get_mempool(&self) -> HashMap<String, Transaction> {
-        self.inner.lock().unwrap().mempool.clone()
+        let inner = self.inner.read().unwrap();
+        inner
+            .mempool
+            .transactions()
+            .map(|tx| (tx.id.clone(), tx.clone()))
+            .collect()
+    }
+
+    fn announce_version(&self) -> Result<()> {
+        for node in self.get_known_nodes() {
+            self.send_version(&node)?;
+        }
+        Ok(())
     }
 
     fn request_blocks(&self) -> Result<()> {
@@ -524,68 +1500,1368 @@ impl Server {
         Ok(())
     }
 
-    fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+    // the utxo set moves along with the chain
+    fn add_block(&self, block: Block) -> Result<TipChange> {
+        let change = self.inner.write().unwrap().utxo.add_block(block)?;
+        if !change.connected.is_empty() {
+            self.interrupt_mining.store(true, Ordering::SeqCst);
+        }
+        Ok(change)
     }
 
-    fn get_block(&self, id: &str) -> Result<Block> {
-        self.inner.lock().unwrap().utxo.blockchain.get_block(id)
+    pub fn get_difficulty(&self) -> usize {
+        self.inner.read().unwrap().utxo.blockchain.get_difficulty()
     }
 
-    fn utxo_reindex(&self) -> Result<()> {
-        self.inner.lock().unwrap().utxo.reindex()
+    fn get_tip_hash(&self) -> String {
+        self.inner.read().unwrap().utxo.blockchain.get_tip_hash()
+    }
+
+    fn get_block(&self, id: &str) -> Result<Block> {
+        self.inner.read().unwrap().utxo.blockchain.get_block(id)
     }
 
     fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
         self.inner
-            .lock()
+            .read()
             .unwrap()
             .utxo
             .blockchain
             .verify_transaction(tx)
     }
 
-    pub fn send_transaction(tx: &Transaction, utxoset: Utxoset) -> Result<()> {
-        let server = Server::new("7000", "", utxoset)?;
-        server.send_tx(KNOWN_NODE_1, tx)?;
+    // events for the blocks the node adds to its best chain
+    pub fn subscribe_blocks(&self) -> Receiver<BlockEvent> {
+        self.inner.read().unwrap().utxo.blockchain.subscribe_blocks()
+    }
+
+    pub(crate) fn get_balance(&self, address: &str) -> Result<u64> {
+        let pub_key_hash = self.params.decode_address(address)?;
+        let utxos = self.inner.read().unwrap().utxo.find_utxo(&pub_key_hash)?;
+        Ok(utxos.iter().map(|out| out.value).sum())
+    }
+
+    pub(crate) fn get_block_count(&self) -> Result<i32> {
+        self.inner.read().unwrap().utxo.blockchain.get_best_height()
+    }
+
+    pub(crate) fn find_block(&self, hash: &str) -> Result<Block> {
+        self.get_block(hash)
+    }
+
+    pub(crate) fn get_confirmations(&self, txid: &str) -> Result<i32> {
+        self.inner.read().unwrap().utxo.blockchain.get_confirmations(txid)
+    }
+
+    pub fn get_chain_info(&self) -> Result<ChainInfo> {
+        let inner = self.inner.read().unwrap();
+        let mut info = inner.utxo.chain_info()?;
+        info.mempool_size = Some(inner.mempool.len());
+        Ok(info)
+    }
+
+    // a node introduced by the operator, the handshake starts right away
+    pub(crate) fn add_peer(&self, addr: &str) -> Result<()> {
+        if addr == self.node_address {
+            return Err(format_err!("{} is this node", addr));
+        }
+        self.add_nodes(addr, true)?;
+        self.send_version(addr)?;
+        // dropped again when it can't be reached
+        if !self.node_is_known(addr) {
+            return Err(format_err!("cannot reach {}", addr));
+        }
         Ok(())
     }
+
+    pub fn get_peer_info(&self) -> Vec<PeerInfo> {
+        let inner = self.inner.read().unwrap();
+        let mut peers: Vec<PeerInfo> = inner
+            .known_nodes
+            .iter()
+            .map(|(address, peer)| PeerInfo {
+                address: address.clone(),
+                last_seen: peer
+                    .last_seen
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+                seed: inner.seeds.contains(address),
+                responsive: peer.ping.is_none(),
+            })
+            .collect();
+        peers.sort_by(|a, b| a.address.cmp(&b.address));
+        peers
+    }
+
+    // the next block for a miner outside the node, paying `miner`, the
+    // mined block comes back through submit_block
+    pub(crate) fn get_block_template(&self, miner: &str) -> Result<BlockTemplate> {
+        self.params.decode_address(miner)?;
+        let txs = self.get_minable_txs()?;
+        self.inner
+            .read()
+            .unwrap()
+            .utxo
+            .blockchain
+            .block_template(miner, txs)
+    }
+
+    pub fn get_mining_info(&self) -> MiningInfo {
+        let inner = self.inner.read().unwrap();
+        let last = inner.recent_mined.back();
+        let attempts: u64 = inner.recent_mined.iter().map(|stats| stats.attempts).sum();
+        let elapsed: Duration = inner.recent_mined.iter().map(|stats| stats.elapsed).sum();
+        let mining = !self.mining_address.is_empty();
+        MiningInfo {
+            difficulty: inner.utxo.blockchain.get_difficulty(),
+            mining,
+            mining_address: Some(self.mining_address.clone()).filter(|_| mining),
+            mempool_size: inner.mempool.len(),
+            last_block: last.map(|stats| stats.hash.clone()),
+            last_attempts: last.map(|stats| stats.attempts),
+            last_time_ms: last.map(|stats| stats.elapsed.as_millis()),
+            // the recent blocks taken together
+            hashrate: last.map(|last| {
+                MiningStats {
+                    attempts,
+                    elapsed,
+                    ..last.clone()
+                }
+                .hashrate()
+            }),
+        }
+    }
+
+    // a block mined outside the node on top of the tip, it is validated like
+    // a block from a peer and announced to the known nodes once it is the tip
+    pub fn submit_block(&self, block: Block) -> Result<()> {
+        let hash = block.get_hash();
+        if self.has_block(&hash)? {
+            return Err(format_err!("block {} is already known", hash));
+        }
+        let tip = self.get_tip_hash();
+        if block.get_prev_hash() != tip {
+            return Err(format_err!(
+                "block {} doesn't build on the tip {}, it is stale",
+                hash,
+                tip
+            ));
+        }
+
+        let change = self.add_block(block)?;
+        if change.connected.is_empty() {
+            // the tip moved since it was checked
+            return Err(format_err!("block {} didn't become the tip", hash));
+        }
+        info!("accept submitted block {}", hash);
+        self.update_mempool(&change)?;
+        self.announce_block(&hash)
+    }
+
+    // mine `count` blocks paying `miner` at once, each one takes what it can
+    // of the mempool. only on regtest, where blocks are found at once
+    pub fn generate(&self, count: usize, miner: &str) -> Result<Vec<String>> {
+        if self.params != REGTEST {
+            return Err(format_err!("generate is only available on regtest"));
+        }
+        let mut hashes = Vec::new();
+        while hashes.len() < count {
+            let template = self.get_block_template(miner)?;
+            let _span = info_span!("block", height = template.height).entered();
+            let block = template.mine()?;
+            let hash = block.get_hash();
+            let change = self.add_block(block)?;
+            if change.connected.is_empty() {
+                info!("tip moved while generating {}, try again", hash);
+                continue;
+            }
+            self.update_mempool(&change)?;
+            self.announce_block(&hash)?;
+            hashes.push(hash);
+        }
+        Ok(hashes)
+    }
+
+    // what is waiting to be mined
+    pub fn get_mempool_entries(&self) -> Result<Vec<MempoolEntry>> {
+        let inner = self.inner.read().unwrap();
+        inner.mempool.entries(&inner.utxo.blockchain)
+    }
+
+    // a transaction created outside the peer protocol, it is announced to the
+    // known nodes once accepted
+    pub(crate) fn submit_transaction(&self, tx: Transaction) -> Result<()> {
+        if !matches!(self.verify_tx(&tx), Ok(true)) {
+            return Err(format_err!("invalid transaction {}", tx.id));
+        }
+        self.accept_tx(tx.clone(), &self.node_address)?;
+        // seed nodes already relayed it
+        if !self.get_seeds().contains(&self.node_address) {
+            for node in self.get_known_nodes() {
+                if node != self.node_address {
+                    self.send_inv(&node, "tx", vec![tx.id.clone()])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // hand a transaction to the seeds of `config` without running a node, it
+    // is sent to each seed that can be reached
+    pub fn send_transaction(tx: &Transaction, config: &ServerConfig) -> Result<()> {
+        let seeds = config.seeds();
+        if seeds.is_empty() {
+            return Err(format_err!("no seed to send transaction {} to", tx.id));
+        }
+        let data = bincode::serialize(&Message::Tx(TxMsg {
+            addr_from: config.node_address(),
+            transaction: tx.clone(),
+        }))?;
+        let mut sent = false;
+        for seed in &seeds {
+            info!("send transaction to: {} tx id:{}", seed, tx.id);
+            let sending = connect(seed).and_then(|mut stream| {
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                write_frame(&mut stream, &config.params.magic, &data)
+            });
+            match sending {
+                Ok(()) => sent = true,
+                Err(e) => info!("cannot send transaction to {}: {}", seed, e),
+            }
+        }
+        if !sent {
+            return Err(format_err!("no seed of {} could be reached", seeds.join(", ")));
+        }
+        Ok(())
+    }
+}
+
+// each message is framed as the network magic, a 4-byte big-endian length,
+// the checksum and the payload
+fn write_frame(stream: &mut impl Write, magic: &[u8; 4], data: &[u8]) -> Result<()> {
+    let len = u32::try_from(data.len())?;
+    stream.write_all(magic)?;
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(&checksum(data))?;
+    stream.write_all(data)?;
+    stream.flush()?;
+    Ok(())
 }
 
-// convert bytes to command
-fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
-    let mut cmd = Vec::new();
-    let cmd_bytes = &bytes[..CMD_LEN];
-    let data = &bytes[CMD_LEN..];
-    for b in cmd_bytes {
-        // check if the byte is not zero
-        if 0 as u8 != *b {
-            cmd.push(*b);
-        }
-    }
-    info!("cmd:{}", String::from_utf8(cmd.clone())?);
-    if cmd == "addr".as_bytes() {
-        let data: Vec<String> = bincode::deserialize(data)?;
-        return Ok(Message::Addr(data));
-    } else if cmd == "block".as_bytes() {
-        let data: BlockMsg = bincode::deserialize(data)?;
-        return Ok(Message::Block(data));
-    } else if cmd == "getblock".as_bytes() {
-        let data: GetBlockMsg = bincode::deserialize(data)?;
-        return Ok(Message::GetBlock(data));
-    } else if cmd == "getdata".as_bytes() {
-        let data: GetDataMsg = bincode::deserialize(data)?;
-        return Ok(Message::GetData(data));
-    } else if cmd == "inv".as_bytes() {
-        let data: InvMsg = bincode::deserialize(data)?;
-        return Ok(Message::Inv(data));
-    } else if cmd == "tx".as_bytes() {
-        let data: TxMsg = bincode::deserialize(data)?;
-        return Ok(Message::Tx(data));
-    } else if cmd == "version".as_bytes() {
-        let data: VersionMsg = bincode::deserialize(data)?;
-        return Ok(Message::Version(data));
+// read exactly one framed message from the stream, refusing messages of
+// another network and payloads larger than max_size
+fn read_frame(stream: &mut impl Read, magic: &[u8; 4], max_size: usize) -> Result<Vec<u8>> {
+    let mut received = [0; 4];
+    stream.read_exact(&mut received)?;
+    if &received != magic {
+        return Err(format_err!(
+            "message for another network, magic {:02x?}",
+            received
+        ));
+    }
+
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_size {
+        return Err(format_err!(
+            "message of {} bytes exceeds the limit of {} bytes",
+            len,
+            max_size
+        ));
+    }
+    let mut sum = [0; 4];
+    stream.read_exact(&mut sum)?;
+    let mut buffer = vec![0; len];
+    stream.read_exact(&mut buffer)?;
+    if sum != checksum(&buffer) {
+        return Err(format_err!("checksum mismatch, the message is corrupted"));
+    }
+    Ok(buffer)
+}
+
+// first 4 bytes of the double sha256 of the payload
+fn checksum(data: &[u8]) -> [u8; 4] {
+    let mut first = [0; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result(&mut first);
+
+    let mut second = [0; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(&first);
+    hasher.result(&mut second);
+
+    let mut sum = [0; 4];
+    sum.copy_from_slice(&second[..4]);
+    sum
+}
+
+// host and port as one address, ipv6 literals are put in brackets
+fn host_port(host: &str, port: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
     } else {
-        Err(format_err!("Unknown command in the server"))
+        format!("{}:{}", host, port)
+    }
+}
+
+// like TcpStream::connect, but every address `addr` resolves to is given up
+// after IO_TIMEOUT
+fn connect(addr: &str) -> Result<TcpStream> {
+    let mut last_err = format_err!("cannot resolve {}", addr);
+    for socket_addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_addr, IO_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e.into(),
+        }
+    }
+    Err(last_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::ED25519;
+    use crate::blockchain::BlockChain;
+    use crate::network::MAINNET;
+    use crate::test_utils::{self, free_port, mine, node_chain, wallet};
+    use crate::tx::{TXInput, TXOutput};
+    use std::net::Ipv4Addr;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+
+    // the spans opened while it is the subscriber, with their fields and
+    // whether they were entered
+    type Span = (String, Vec<(String, String)>, bool);
+
+    #[derive(Clone, Default)]
+    struct Spans(Arc<Mutex<HashMap<u64, Span>>>);
+
+    struct Fields<'a>(&'a mut Vec<(String, String)>);
+
+    impl Visit for Fields<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.push((field.name().to_string(), value.to_string()));
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+
+    impl<S: tracing::Subscriber> Layer<S> for Spans {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _: Context<'_, S>) {
+            let mut fields = Vec::new();
+            attrs.record(&mut Fields(&mut fields));
+            let name = attrs.metadata().name().to_string();
+            self.0.lock().unwrap().insert(id.into_u64(), (name, fields, false));
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, _: Context<'_, S>) {
+            if let Some((_, fields, _)) = self.0.lock().unwrap().get_mut(&id.into_u64()) {
+                values.record(&mut Fields(fields));
+            }
+        }
+
+        fn on_enter(&self, id: &Id, _: Context<'_, S>) {
+            if let Some((_, _, entered)) = self.0.lock().unwrap().get_mut(&id.into_u64()) {
+                *entered = true;
+            }
+        }
+    }
+
+    fn node(dir: &TempDir, port: &str) -> Result<Server> {
+        Server::new(ServerConfig::new(port, &REGTEST), node_chain(dir)?)
+    }
+
+    // the next message sent to `listener`
+    fn receive(listener: &TcpListener) -> Result<Message> {
+        let (mut stream, _) = listener.accept()?;
+        let buffer = read_frame(&mut stream, &REGTEST.magic, MAX_MESSAGE_SIZE)?;
+        Ok(bincode::deserialize(&buffer)?)
+    }
+
+    // nothing was sent to `listener`
+    fn is_quiet(listener: &TcpListener) -> Result<bool> {
+        listener.set_nonblocking(true)?;
+        let quiet = matches!(listener.accept(), Err(e) if e.kind() == ErrorKind::WouldBlock);
+        listener.set_nonblocking(false)?;
+        Ok(quiet)
+    }
+
+    // poll `done` for up to `timeout`, whether it came true
+    fn wait_for(timeout: Duration, done: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !done() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        true
+    }
+
+    // the node of `dir` started again, from what the last one left there
+    fn reopen(dir: &TempDir) -> Result<Server> {
+        let bc = BlockChain::new(dir.path().to_str().unwrap())?;
+        Server::new(ServerConfig::new("0", &REGTEST), Utxoset::open(bc)?)
+    }
+
+    fn local_peer(addr: &str) -> PeerId {
+        PeerId {
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            addr: Some(addr.to_string()),
+        }
+    }
+
+    // spends the genesis coinbase with a signature that doesn't verify
+    fn forged_tx(node: &Server) -> Result<Transaction> {
+        let genesis = node.get_block(&node.get_tip_hash())?;
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: genesis.get_transactions()[0].id.clone(),
+                vout: 0,
+                signature: vec![0; 64],
+                pub_key: wallet(1).public_key,
+                multisig_signatures: Vec::new(),
+                scheme: ED25519,
+            }],
+            vout: vec![TXOutput::new(100, wallet(2).get_address(&REGTEST), &REGTEST)?],
+            lock_time: 0,
+        };
+        tx.id = tx.unsigned_hash()?;
+        Ok(tx)
+    }
+
+    // one message of each kind
+    fn messages() -> Result<Vec<Message>> {
+        let address = wallet(1).get_address(&REGTEST);
+        let bc = test_utils::chain(&address)?;
+        let genesis = bc.get_block(&bc.get_tip_hash())?;
+        let from = "localhost:3001".to_string();
+        let ping = PingMsg {
+            addr_from: from.clone(),
+            nonce: 7,
+        };
+        Ok(vec![
+            Message::Addr(vec![from.clone(), "localhost:3002".to_string()]),
+            Message::Version(VersionMsg {
+                addr_from: from.clone(),
+                version: VERSION,
+                best_height: 3,
+            }),
+            Message::Tx(TxMsg {
+                addr_from: from.clone(),
+                transaction: genesis.get_transactions()[0].clone(),
+            }),
+            Message::GetData(GetDataMsg {
+                addr_from: from.clone(),
+                kind: "block".to_string(),
+                id: genesis.get_hash(),
+            }),
+            Message::GetBlock(GetBlockMsg {
+                addr_from: from.clone(),
+            }),
+            Message::Inv(InvMsg {
+                addr_from: from.clone(),
+                kind: "tx".to_string(),
+                items: vec![genesis.get_transactions()[0].id.clone()],
+            }),
+            Message::Block(BlockMsg {
+                addr_from: from.clone(),
+                block: genesis.clone(),
+            }),
+            Message::Ping(ping.clone()),
+            Message::Pong(ping),
+            Message::GetHeaders(GetHeadersMsg {
+                addr_from: from.clone(),
+                locator: bc.get_block_locator()?,
+            }),
+            Message::Headers(HeadersMsg {
+                addr_from: from,
+                headers: vec![genesis.get_header()],
+            }),
+        ])
+    }
+
+    #[test]
+    fn every_message_survives_the_wire() -> Result<()> {
+        let messages = messages()?;
+        let kinds: HashSet<&str> = messages.iter().map(Message::kind).collect();
+        assert_eq!(kinds.len(), messages.len());
+        for msg in messages {
+            let data = bincode::serialize(&msg)?;
+            let received: Message = bincode::deserialize(&data)?;
+            assert_eq!(received.kind(), msg.kind());
+            assert_eq!(format!("{:?}", received), format!("{:?}", msg));
+            assert_eq!(bincode::serialize(&received)?, data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn a_block_of_megabytes_crosses_a_tcp_connection_whole() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let bc = test_utils::chain(&address)?;
+        let genesis = bc.get_block(&bc.get_tip_hash())?;
+        let coinbase = Transaction::new_coinbase(address, "x".repeat(2 << 20), 1, &REGTEST)?;
+        let block = test_utils::mine_at(vec![coinbase], genesis.get_hash(), 1, REGTEST.difficulty)?;
+        let msg = Message::Block(BlockMsg {
+            addr_from: "localhost:3001".to_string(),
+            block: block.clone(),
+        });
+        let data = bincode::serialize(&msg)?;
+        assert!(data.len() > 2 << 20);
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut sender = TcpStream::connect(listener.local_addr()?)?;
+        let writer = thread::spawn(move || write_frame(&mut sender, &REGTEST.magic, &data));
+        let (mut receiver, _) = listener.accept()?;
+        let buffer = read_frame(&mut receiver, &REGTEST.magic, MAX_MESSAGE_SIZE)?;
+        writer.join().unwrap()?;
+
+        match bincode::deserialize(&buffer)? {
+            Message::Block(received) => {
+                assert_eq!(received.block.get_hash(), block.get_hash());
+                assert!(received.block.verify_merkle_root()?);
+            }
+            other => panic!("expected a block, got {}", other.kind()),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn a_node_syncs_a_chain_of_50_blocks_from_its_seed() -> Result<()> {
+        let (leader_dir, follower_dir) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        let utxo = node_chain(&leader_dir)?;
+        // the follower starts from the same genesis block
+        let mut genesis = Vec::new();
+        utxo.blockchain.export_json(&mut genesis)?;
+        let follower_path = follower_dir.path().to_str().unwrap();
+        let chain = BlockChain::import_json(&genesis[..], follower_path, &REGTEST)?;
+
+        let leader_port = free_port()?;
+        let leader = Server::new(ServerConfig::new(&leader_port, &REGTEST), utxo)?;
+        leader.generate(50, &wallet(2).get_address(&REGTEST))?;
+        let mut config = ServerConfig::new(&free_port()?, &REGTEST);
+        config.seeds = vec![format!("localhost:{}", leader_port)];
+        let follower = Server::new(config, Utxoset::open(chain)?)?;
+
+        let running: Vec<_> = [leader.clone(), follower.clone()]
+            .into_iter()
+            .map(|node| thread::spawn(move || node.start()))
+            .collect();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while follower.get_tip_hash() != leader.get_tip_hash() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+        }
+        leader.stop();
+        follower.stop();
+        for node in running {
+            node.join().unwrap()?;
+        }
+        assert_eq!(follower.get_tip_hash(), leader.get_tip_hash());
+        assert_eq!(follower.get_block_count()?, 50);
+        Ok(())
+    }
+
+    #[test]
+    fn a_connection_is_handled_in_a_span_of_its_peer() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut sender = TcpStream::connect(listener.local_addr()?)?;
+        let ping = Message::Ping(PingMsg {
+            addr_from: "localhost:3001".to_string(),
+            nonce: 7,
+        });
+        write_frame(&mut sender, &REGTEST.magic, &bincode::serialize(&ping)?)?;
+        let (stream, peer) = listener.accept()?;
+
+        let spans = Spans::default();
+        let subscriber = tracing_subscriber::registry().with(spans.clone());
+        tracing::subscriber::with_default(subscriber, || node.serve_connection(stream, peer));
+
+        let spans = spans.0.lock().unwrap();
+        let connections: Vec<_> =
+            spans.values().filter(|(name, _, _)| name == "connection").collect();
+        assert_eq!(connections.len(), 1);
+        let (_, fields, entered) = connections[0];
+        assert!(entered);
+        assert!(fields.contains(&("peer".to_string(), peer.to_string())));
+        assert!(fields.contains(&("kind".to_string(), "ping".to_string())));
+        Ok(())
+    }
+
+    #[test]
+    fn json_rpc_listens_on_the_loopback_by_default() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut config = ServerConfig::new("0", &REGTEST);
+        config.rpc_port = Some("8332".to_string());
+        let node = Server::new(config, node_chain(&dir)?)?;
+        assert_eq!(node.bind_address, "0.0.0.0:0");
+        assert_eq!(node.rpc_address.as_deref(), Some("127.0.0.1:8332"));
+        Ok(())
+    }
+
+    #[test]
+    fn an_addr_flood_does_not_evict_the_nodes_we_connected_to() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut config = ServerConfig::new("0", &REGTEST);
+        config.seeds = vec!["localhost:3000".to_string()];
+        config.max_peers = 5;
+        let flooded = Server::new(config, node_chain(&dir)?)?;
+        flooded.add_nodes("localhost:3001", true)?;
+        flooded.add_nodes("localhost:3002", true)?;
+
+        for round in 0..10 {
+            let flood = (0..50).map(|i| format!("10.0.{}.{}:3000", round, i)).collect();
+            flooded.handle_addr(flood)?;
+            let known = flooded.get_known_nodes();
+            assert_eq!(known.len(), 5);
+            for kept in ["localhost:3000", "localhost:3001", "localhost:3002"] {
+                assert!(known.contains(kept), "{} was evicted", kept);
+            }
+        }
+
+        // the others are taken in a few at a time
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        node.handle_addr((0..50).map(|i| format!("10.0.0.{}:3000", i)).collect())?;
+        assert_eq!(node.get_known_nodes().len(), MAX_ADDR_PER_MESSAGE);
+        Ok(())
+    }
+
+    #[test]
+    fn a_peer_sending_invalid_blocks_is_banned() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        let genesis = node.get_block(&node.get_tip_hash())?;
+        let address = wallet(3).get_address(&REGTEST);
+        // mined properly, the transaction is what makes it invalid
+        let block = mine(&address, vec![forged_tx(&node)?], &genesis)?;
+        let sender = local_peer("localhost:3001");
+        let neighbour = local_peer("localhost:3002");
+
+        for _ in 0..2 {
+            assert!(!node.is_banned(&sender));
+            let msg = BlockMsg {
+                addr_from: "localhost:3001".to_string(),
+                block: block.clone(),
+            };
+            node.handle_block(msg, &sender)?;
+        }
+        assert!(node.is_banned(&sender));
+        // another node on the same host keeps its score
+        assert!(!node.is_banned(&neighbour));
+        assert_eq!(node.get_tip_hash(), genesis.get_hash());
+        Ok(())
+    }
+
+    #[test]
+    fn a_transaction_spending_unknown_outputs_is_not_accepted() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        let peer = local_peer("localhost:3001");
+
+        let mut unknown = forged_tx(&node)?;
+        unknown.vin[0].txid = "00unknown".to_string();
+        unknown.id = unknown.unsigned_hash()?;
+        let msg = TxMsg {
+            addr_from: "localhost:3001".to_string(),
+            transaction: unknown,
+        };
+        node.handle_tx(msg, &peer)?;
+        assert!(node.mempool_is_empty());
+        assert!(!node.inner.read().unwrap().ban_scores.contains_key(&peer));
+
+        let msg = TxMsg {
+            addr_from: "localhost:3001".to_string(),
+            transaction: forged_tx(&node)?,
+        };
+        node.handle_tx(msg, &peer)?;
+        assert!(node.mempool_is_empty());
+        assert_eq!(node.inner.read().unwrap().ban_scores[&peer], INVALID_TX_SCORE);
+        Ok(())
+    }
+
+    #[test]
+    fn a_block_mined_from_the_template_becomes_the_tip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let utxo = node_chain(&dir)?;
+        let payee = wallet(2).get_address(&REGTEST);
+        let tx = Transaction::new_utxo(&wallet(1), &payee, 10, &utxo)?;
+        let node = Server::new(ServerConfig::new("0", &REGTEST), utxo)?;
+        node.submit_transaction(tx.clone())?;
+
+        let miner = wallet(3).get_address(&REGTEST);
+        let template = node.get_block_template(&miner)?;
+        assert_eq!(template.transactions.len(), 2);
+        assert_eq!(template.transactions[1].id, tx.id);
+
+        // the proof of work is run outside the node
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let mut nonce = 0;
+        let block = loop {
+            let block = Block::assemble(
+                template.transactions.clone(),
+                template.prev_block_hash.clone(),
+                template.height,
+                template.difficulty,
+                timestamp,
+                nonce,
+            )?;
+            if block.verify_proof_of_work()? {
+                break block;
+            }
+            nonce += 1;
+        };
+        assert_eq!(block.get_merkle_root(), &template.merkle_root[..]);
+        node.submit_block(block.clone())?;
+        assert_eq!(node.get_tip_hash(), block.get_hash());
+        assert!(node.mempool_is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn transactions_the_chain_refuses_leave_the_mempool_one_by_one() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut utxo = node_chain(&dir)?;
+        let (owner, payee) = (wallet(1), wallet(2).get_address(&REGTEST));
+        utxo.mine_block(&owner.get_address(&REGTEST), Vec::new())?;
+        // both spend the same output, the first one is mined
+        let mined = Transaction::new_utxo(&owner, &payee, 10, &utxo)?;
+        let conflict = Transaction::new_utxo(&owner, &payee, 20, &utxo)?;
+        assert_eq!(mined.vin[0].txid, conflict.vin[0].txid);
+        utxo.mine_block(&owner.get_address(&REGTEST), vec![mined])?;
+        let valid = Transaction::new_utxo(&owner, &payee, 30, &utxo)?;
+        let node = Server::new(ServerConfig::new("0", &REGTEST), utxo)?;
+        {
+            // as if they were left in the mempool by the last run
+            let mempool = &mut node.inner.write().unwrap().mempool;
+            mempool.insert(conflict.clone())?;
+            mempool.insert(valid.clone())?;
+        }
+
+        let txs = node.get_minable_txs()?;
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].id, valid.id);
+        assert!(node.get_mempool_tx(&conflict.id).is_none());
+        assert!(node.get_mempool_tx(&valid.id).is_some());
+
+        let miner = wallet(3).get_address(&REGTEST);
+        node.generate(1, &miner)?;
+        assert!(node.mempool_is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn the_seeds_given_are_the_first_known_nodes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut config = ServerConfig::new("0", &REGTEST);
+        let seeds = vec!["10.0.0.2:23000".to_string(), "10.0.0.3:23000".to_string()];
+        config.seeds = seeds.clone();
+        let node = Server::new(config, node_chain(&dir)?)?;
+        assert_eq!(node.get_seeds(), seeds);
+        assert_eq!(node.get_known_nodes(), seeds.into_iter().collect());
+        Ok(())
+    }
+
+    #[test]
+    fn a_transaction_is_sent_to_the_seeds_of_the_config() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let bc = test_utils::chain(&address)?;
+        let tx = bc.get_block(&bc.get_tip_hash())?.get_transactions()[0].clone();
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut config = ServerConfig::new("7000", &REGTEST);
+        config.seeds = vec![listener.local_addr()?.to_string()];
+
+        Server::send_transaction(&tx, &config)?;
+        match receive(&listener)? {
+            Message::Tx(msg) => {
+                assert_eq!(msg.transaction.id, tx.id);
+                assert_eq!(msg.addr_from, "localhost:7000");
+            }
+            other => panic!("expected a transaction, got {}", other.kind()),
+        }
+
+        config.seeds = vec![format!("127.0.0.1:{}", free_port()?)];
+        let e = Server::send_transaction(&tx, &config).unwrap_err();
+        assert!(e.to_string().contains("could be reached"), "{}", e);
+        // regtest has no seeds of its own
+        config.seeds = Vec::new();
+        assert!(Server::send_transaction(&tx, &config).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn a_stalled_send_does_not_block_the_node() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        // the peer never reads, more than the socket buffers hold stalls the send
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let peer = listener.local_addr()?.to_string();
+        let data = vec![0; 64 << 20];
+        let sender = node.clone();
+        let sending = thread::spawn(move || sender.send_data(&peer, &data));
+        let (stalled, _) = listener.accept()?;
+        // the frame is being written
+        stalled.peek(&mut [0; 4])?;
+        thread::sleep(Duration::from_millis(200));
+
+        let started = Instant::now();
+        assert_eq!(node.get_best_height(), 0);
+        node.generate(1, &wallet(2).get_address(&REGTEST))?;
+        assert_eq!(node.get_best_height(), 1);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(!sending.is_finished());
+
+        drop(stalled);
+        assert!(sending.join().unwrap().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn the_mempool_is_restored_when_the_node_starts_again() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let utxo = node_chain(&dir)?;
+        let payee = wallet(2).get_address(&REGTEST);
+        let tx = Transaction::new_utxo(&wallet(1), &payee, 10, &utxo)?;
+        let node = Server::new(ServerConfig::new("0", &REGTEST), utxo)?;
+        node.submit_transaction(tx.clone())?;
+        drop(node);
+
+        let node = reopen(&dir)?;
+        assert_eq!(node.get_mempool_tx(&tx.id).map(|tx| tx.id), Some(tx.id));
+        assert_eq!(node.get_mempool_entries()?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn an_inv_is_answered_with_a_getdata_per_item() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let peer = listener.local_addr()?.to_string();
+        let inv = |kind: &str, items: &[&str]| InvMsg {
+            addr_from: peer.clone(),
+            kind: kind.to_string(),
+            items: items.iter().map(|item| item.to_string()).collect(),
+        };
+
+        node.handle_inv(inv("tx", &["a1", "b2", "c3"]))?;
+        // blocks are listed from the tip down and fetched parents first
+        node.handle_inv(inv("block", &["f6", "e5", "d4"]))?;
+        let mut requested = Vec::new();
+        for _ in 0..6 {
+            match receive(&listener)? {
+                Message::GetData(msg) => requested.push((msg.kind, msg.id)),
+                other => panic!("expected a getdata, got {}", other.kind()),
+            }
+        }
+        let expected = [
+            ("tx", "a1"),
+            ("tx", "b2"),
+            ("tx", "c3"),
+            ("block", "d4"),
+            ("block", "e5"),
+            ("block", "f6"),
+        ];
+        let requested: Vec<_> = requested.iter().map(|(k, id)| (k.as_str(), id.as_str())).collect();
+        assert_eq!(requested, expected);
+
+        node.handle_inv(inv("tx", &[]))?;
+        node.handle_inv(inv("block", &[]))?;
+        assert!(is_quiet(&listener)?);
+        Ok(())
+    }
+
+    #[test]
+    fn getdata_for_unknown_items_is_ignored() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let peer = listener.local_addr()?.to_string();
+        for kind in ["tx", "block"] {
+            node.handle_get_data(GetDataMsg {
+                addr_from: peer.clone(),
+                kind: kind.to_string(),
+                id: "0".repeat(64),
+            })?;
+        }
+        assert!(is_quiet(&listener)?);
+
+        // a known block is still served
+        node.handle_get_data(GetDataMsg {
+            addr_from: peer,
+            kind: "block".to_string(),
+            id: node.get_tip_hash(),
+        })?;
+        match receive(&listener)? {
+            Message::Block(msg) => assert_eq!(msg.block.get_hash(), node.get_tip_hash()),
+            other => panic!("expected a block, got {}", other.kind()),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn a_peer_that_stops_writing_times_out() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut slow = TcpStream::connect(listener.local_addr()?)?;
+        // the start of a frame, and then nothing
+        slow.write_all(&REGTEST.magic)?;
+        slow.write_all(&[0, 0])?;
+        let (stream, _) = listener.accept()?;
+
+        let started = Instant::now();
+        let e = node.handle_connection(stream).unwrap_err();
+        assert!(e.downcast_ref::<std::io::Error>().is_some(), "{}", e);
+        assert!(started.elapsed() >= IO_TIMEOUT);
+        assert!(started.elapsed() < IO_TIMEOUT + Duration::from_secs(5));
+        // being slow is no misbehavior
+        assert!(node.inner.read().unwrap().ban_scores.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn an_oversized_frame_is_refused_before_its_payload() -> Result<()> {
+        let mut header = REGTEST.magic.to_vec();
+        header.extend((MAX_MESSAGE_SIZE as u32 + 1).to_be_bytes());
+        // no payload follows, it is never read
+        let e = read_frame(&mut &header[..], &REGTEST.magic, MAX_MESSAGE_SIZE).unwrap_err();
+        assert!(e.to_string().contains("exceeds the limit"), "{}", e);
+
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut sender = TcpStream::connect(listener.local_addr()?)?;
+        sender.write_all(&header)?;
+        let (stream, _) = listener.accept()?;
+        let e = node.handle_connection(stream).unwrap_err();
+        assert!(e.to_string().contains("exceeds the limit"), "{}", e);
+        let peer = PeerId {
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            addr: None,
+        };
+        assert_eq!(node.inner.read().unwrap().ban_scores[&peer], MALFORMED_MESSAGE_SCORE);
+        Ok(())
+    }
+
+    #[test]
+    fn the_peers_are_restored_when_the_node_starts_again() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        node.add_nodes("localhost:23001", true)?;
+        node.add_nodes("localhost:23002", false)?;
+        node.remove_node("localhost:23002")?;
+        drop(node);
+
+        let node = reopen(&dir)?;
+        let expected: HashSet<String> = ["localhost:23001".to_string()].into();
+        assert_eq!(node.get_known_nodes(), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn a_node_bound_to_the_loopback_accepts_connections() -> Result<()> {
+        for host in ["127.0.0.1", "::1"] {
+            let dir = tempfile::tempdir()?;
+            let mut config = ServerConfig::new(&free_port()?, &REGTEST);
+            config.bind = host.to_string();
+            let node = Server::new(config, node_chain(&dir)?)?;
+            let address: SocketAddr = node.bind_address.parse()?;
+            assert_eq!(address.ip(), host.parse::<IpAddr>()?);
+
+            let server = node.clone();
+            let running = thread::spawn(move || server.start());
+            // the node takes in the address it is told about
+            let addr = bincode::serialize(&Message::Addr(vec!["localhost:23001".to_string()]))?;
+            let connected = wait_for(Duration::from_secs(5), || {
+                match TcpStream::connect(address) {
+                    Ok(mut stream) => write_frame(&mut stream, &REGTEST.magic, &addr).is_ok(),
+                    Err(_) => false,
+                }
+            });
+            let known = wait_for(Duration::from_secs(5), || {
+                node.get_known_nodes().contains("localhost:23001")
+            });
+            node.stop();
+            running.join().unwrap()?;
+            assert!(connected, "nothing listens on {}", address);
+            assert!(known);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn a_message_of_another_network_is_refused() -> Result<()> {
+        let data = bincode::serialize(&Message::Addr(vec!["localhost:3001".to_string()]))?;
+        let mut frame = Vec::new();
+        write_frame(&mut frame, &MAINNET.magic, &data)?;
+        assert_eq!(read_frame(&mut &frame[..], &MAINNET.magic, MAX_MESSAGE_SIZE)?, data);
+        let e = read_frame(&mut &frame[..], &REGTEST.magic, MAX_MESSAGE_SIZE).unwrap_err();
+        assert!(e.to_string().contains("another network"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn a_corrupted_payload_fails_the_checksum() -> Result<()> {
+        let data = bincode::serialize(&Message::Addr(vec!["localhost:3001".to_string()]))?;
+        let mut frame = Vec::new();
+        write_frame(&mut frame, &REGTEST.magic, &data)?;
+        // the payload follows the magic, the length and the checksum
+        let last = frame.len() - 1;
+        frame[last] ^= 1;
+        let e = read_frame(&mut &frame[..], &REGTEST.magic, MAX_MESSAGE_SIZE).unwrap_err();
+        assert!(e.to_string().contains("checksum mismatch"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn many_readers_run_alongside_a_writer() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        let miner = wallet(2).get_address(&REGTEST);
+        let (done, finished) = mpsc::channel();
+
+        let writer = node.clone();
+        let sender = done.clone();
+        thread::spawn(move || sender.send(writer.generate(10, &miner).map(|_| 10)));
+        for _ in 0..8 {
+            let reader = node.clone();
+            let sender = done.clone();
+            thread::spawn(move || {
+                // every reader sees the chain grow, never a height going back
+                let mut last = 0;
+                while last < 10 {
+                    let height = reader.get_best_height();
+                    let info = reader.get_chain_info().unwrap();
+                    assert!(height >= last && info.height >= height);
+                    assert_eq!(reader.get_balance(&wallet(1).get_address(&REGTEST)).unwrap(), 100);
+                    last = height;
+                }
+                sender.send(Ok(last)).unwrap();
+            });
+        }
+        drop(done);
+        for _ in 0..9 {
+            match finished.recv_timeout(Duration::from_secs(60)) {
+                Ok(height) => assert_eq!(height?, 10),
+                Err(e) => panic!("a reader or the writer is stuck: {}", e),
+            }
+        }
+        assert_eq!(node.get_block_count()?, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn stop_ends_the_node_with_its_data_written() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, &free_port()?)?;
+        let server = node.clone();
+        let (done, stopped) = mpsc::channel();
+        thread::spawn(move || done.send(server.start()));
+        node.generate(2, &wallet(2).get_address(&REGTEST))?;
+        let tip = node.get_tip_hash();
+
+        node.stop();
+        match stopped.recv_timeout(Duration::from_secs(10)) {
+            Ok(result) => result?,
+            Err(e) => panic!("the accept loop did not exit: {}", e),
+        }
+        drop(node);
+        let bc = BlockChain::new(dir.path().to_str().unwrap())?;
+        assert_eq!(bc.get_tip_hash(), tip);
+        assert_eq!(bc.get_best_height()?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn a_subscriber_hears_of_every_mined_block() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let utxo = node_chain(&dir)?;
+        let tx = Transaction::new_utxo(&wallet(1), &wallet(2).get_address(&REGTEST), 10, &utxo)?;
+        let node = Server::new(ServerConfig::new("0", &REGTEST), utxo)?;
+        let blocks = node.subscribe_blocks();
+        node.submit_transaction(tx)?;
+
+        let hashes = node.generate(1, &wallet(3).get_address(&REGTEST))?;
+        let event = blocks.recv_timeout(Duration::from_secs(5))?;
+        assert_eq!(event.height, 1);
+        assert_eq!(event.hash, hashes[0]);
+        assert_eq!(event.tx_count, 2);
+        assert!(blocks.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn a_child_arriving_before_its_parent_waits_for_it() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        let genesis = node.get_block(&node.get_tip_hash())?;
+        let address = wallet(3).get_address(&REGTEST);
+        let parent = mine(&address, Vec::new(), &genesis)?;
+        let child = mine(&address, Vec::new(), &parent)?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let from = listener.local_addr()?.to_string();
+        let peer = local_peer(&from);
+
+        let msg = BlockMsg {
+            addr_from: from.clone(),
+            block: child.clone(),
+        };
+        node.handle_block(msg, &peer)?;
+        assert_eq!(node.get_tip_hash(), genesis.get_hash());
+        assert!(!node.has_block(&child.get_hash())?);
+        // the parent is asked for from the peer that sent the child
+        match receive(&listener)? {
+            Message::GetData(msg) => {
+                assert_eq!((msg.kind.as_str(), msg.id), ("block", parent.get_hash()))
+            }
+            other => panic!("expected a getdata, got {:?}", other),
+        }
+
+        let msg = BlockMsg {
+            addr_from: from,
+            block: parent.clone(),
+        };
+        node.handle_block(msg, &peer)?;
+        assert_eq!(node.get_tip_hash(), child.get_hash());
+        assert_eq!(node.get_best_height(), 2);
+        assert!(node.inner.read().unwrap().orphans.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn a_transaction_of_the_losing_fork_returns_to_the_mempool() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        let genesis = node.get_block(&node.get_tip_hash())?;
+        let tx = {
+            let utxo = &node.inner.read().unwrap().utxo;
+            Transaction::new_utxo(&wallet(1), &wallet(2).get_address(&REGTEST), 10, utxo)?
+        };
+        let address = wallet(3).get_address(&REGTEST);
+        let losing = mine(&address, vec![tx.clone()], &genesis)?;
+        let first = mine(&address, Vec::new(), &genesis)?;
+        let second = mine(&address, Vec::new(), &first)?;
+        let peer = local_peer("localhost:3001");
+
+        for block in [losing, first, second.clone()] {
+            let msg = BlockMsg {
+                addr_from: "localhost:3001".to_string(),
+                block,
+            };
+            node.handle_block(msg, &peer)?;
+            if node.get_best_height() == 1 {
+                assert!(node.get_mempool_tx(&tx.id).is_none());
+            }
+        }
+        assert_eq!(node.get_tip_hash(), second.get_hash());
+        assert!(node.get_mempool_tx(&tx.id).is_some());
+        assert_eq!(node.get_confirmations(&tx.id)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn a_peer_that_never_pongs_is_dropped_after_the_timeout() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut config = ServerConfig::new("0", &REGTEST);
+        config.ping_timeout = Duration::from_millis(300);
+        let node = Server::new(config, node_chain(&dir)?)?;
+        let silent = TcpListener::bind("127.0.0.1:0")?;
+        let alive = TcpListener::bind("127.0.0.1:0")?;
+        let silent_addr = silent.local_addr()?.to_string();
+        let alive_addr = alive.local_addr()?.to_string();
+        for addr in [&silent_addr, &alive_addr] {
+            node.add_nodes(addr, true)?;
+            node.send_ping(addr)?;
+        }
+        match receive(&alive)? {
+            Message::Ping(ping) => node.handle_pong(ping),
+            other => panic!("expected a ping, got {:?}", other),
+        }
+
+        node.drop_silent_nodes()?;
+        assert!(node.get_known_nodes().contains(&silent_addr));
+        thread::sleep(Duration::from_millis(400));
+        node.drop_silent_nodes()?;
+        let known = node.get_known_nodes();
+        assert!(!known.contains(&silent_addr));
+        assert!(known.contains(&alive_addr));
+        Ok(())
+    }
+
+    #[test]
+    fn an_added_node_is_known_and_greeted() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        let peer = TcpListener::bind("127.0.0.1:0")?;
+        let addr = peer.local_addr()?.to_string();
+
+        node.add_peer(&addr)?;
+        assert!(node.get_known_nodes().contains(&addr));
+        match receive(&peer)? {
+            Message::Version(msg) => assert_eq!(msg.best_height, node.get_best_height()),
+            other => panic!("expected a version, got {:?}", other),
+        }
+
+        // a node that can't be reached is not kept
+        let gone = format!("127.0.0.1:{}", free_port()?);
+        assert!(node.add_peer(&gone).is_err());
+        assert!(!node.get_known_nodes().contains(&gone));
+        Ok(())
+    }
+
+    #[test]
+    fn a_window_of_blocks_is_requested_at_once_during_sync() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut config = ServerConfig::new("0", &REGTEST);
+        config.download_window = 4;
+        let node = Server::new(config, node_chain(&dir)?)?;
+        let mut blocks = vec![node.get_block(&node.get_tip_hash())?];
+        let address = wallet(3).get_address(&REGTEST);
+        for _ in 0..6 {
+            let block = mine(&address, Vec::new(), blocks.last().unwrap())?;
+            blocks.push(block);
+        }
+        let blocks = blocks.split_off(1);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let from = listener.local_addr()?.to_string();
+        let peer = local_peer(&from);
+        let requested = || -> Result<String> {
+            match receive(&listener)? {
+                Message::GetData(msg) => Ok(msg.id),
+                other => panic!("expected a getdata, got {}", other.kind()),
+            }
+        };
+
+        let msg = HeadersMsg {
+            addr_from: from.clone(),
+            headers: blocks.iter().map(Block::get_header).collect(),
+        };
+        node.handle_headers(msg, &peer)?;
+        // the first four are on their way together, before any arrives
+        for block in &blocks[..4] {
+            assert_eq!(requested()?, block.get_hash());
+        }
+        assert!(is_quiet(&listener)?);
+        assert_eq!(node.inner.read().unwrap().blocks_requested.len(), 4);
+
+        // each block arriving, even out of order, makes room for the next
+        for (block, next) in [(&blocks[1], &blocks[4]), (&blocks[0], &blocks[5])] {
+            let msg = BlockMsg {
+                addr_from: from.clone(),
+                block: block.clone(),
+            };
+            node.handle_block(msg, &peer)?;
+            assert_eq!(requested()?, next.get_hash());
+        }
+        for block in &blocks[2..] {
+            let msg = BlockMsg {
+                addr_from: from.clone(),
+                block: block.clone(),
+            };
+            node.handle_block(msg, &peer)?;
+        }
+        assert_eq!(node.get_tip_hash(), blocks[5].get_hash());
+        assert!(is_quiet(&listener)?);
+        Ok(())
+    }
+
+    #[test]
+    fn a_submitted_block_is_checked_then_clears_the_mempool_and_is_announced() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let utxo = node_chain(&dir)?;
+        let tx = Transaction::new_utxo(&wallet(1), &wallet(2).get_address(&REGTEST), 10, &utxo)?;
+        let node = Server::new(ServerConfig::new("0", &REGTEST), utxo)?;
+        node.submit_transaction(tx.clone())?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        node.add_nodes(&listener.local_addr()?.to_string(), true)?;
+        let block = node.get_block_template(&wallet(3).get_address(&REGTEST))?.mine()?;
+
+        let mut value = serde_json::to_value(&block)?;
+        value["nonce"] = (block.get_nonce() + 1).into();
+        let e = node.submit_block(serde_json::from_value(value)?).unwrap_err();
+        assert!(e.to_string().contains("invalid proof of work"), "{}", e);
+        assert_eq!(node.get_best_height(), 0);
+        assert!(node.get_mempool_tx(&tx.id).is_some());
+        assert!(is_quiet(&listener)?);
+
+        node.submit_block(block.clone())?;
+        assert_eq!(node.get_tip_hash(), block.get_hash());
+        assert!(node.mempool_is_empty());
+        match receive(&listener)? {
+            Message::Inv(inv) => {
+                assert_eq!((inv.kind.as_str(), inv.items), ("block", vec![block.get_hash()]))
+            }
+            other => panic!("expected an inv, got {}", other.kind()),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn the_miner_mines_a_seeded_mempool_without_new_transactions() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let utxo = node_chain(&dir)?;
+        let tx = Transaction::new_utxo(&wallet(1), &wallet(2).get_address(&REGTEST), 10, &utxo)?;
+        let mut config = ServerConfig::new(&free_port()?, &REGTEST);
+        config.mining_address = wallet(3).get_address(&REGTEST);
+        let node = Server::new(config, utxo)?;
+        // as if left pending by the last run, no transaction comes in
+        node.inner.write().unwrap().mempool.insert(tx.clone())?;
+        let blocks = node.subscribe_blocks();
+
+        let server = node.clone();
+        let started = thread::spawn(move || server.start());
+        let event = blocks.recv_timeout(MINE_INTERVAL + Duration::from_secs(10));
+        node.stop();
+        started.join().unwrap()?;
+        let event = event?;
+        assert_eq!((event.height, event.tx_count), (1, 2));
+        assert!(node.mempool_is_empty());
+        assert_eq!(node.get_confirmations(&tx.id)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn a_block_against_a_checkpoint_counts_as_invalid() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let node = node(&dir, "0")?;
+        let genesis = node.get_block(&node.get_tip_hash())?;
+        let main = mine(&wallet(3).get_address(&REGTEST), Vec::new(), &genesis)?;
+        let honest = local_peer("localhost:3001");
+        let msg = BlockMsg {
+            addr_from: "localhost:3001".to_string(),
+            block: main.clone(),
+        };
+        node.handle_block(msg, &honest)?;
+        node.inner
+            .write()
+            .unwrap()
+            .utxo
+            .blockchain
+            .set_checkpoints(vec![(1, main.get_hash())])?;
+
+        // properly mined, but not the block of the checkpoint
+        let conflicting = mine(&wallet(4).get_address(&REGTEST), Vec::new(), &genesis)?;
+        let sender = local_peer("localhost:3002");
+        let msg = BlockMsg {
+            addr_from: "localhost:3002".to_string(),
+            block: conflicting.clone(),
+        };
+        node.handle_block(msg, &sender)?;
+        assert!(!node.has_block(&conflicting.get_hash())?);
+        assert_eq!(node.inner.read().unwrap().ban_scores[&sender], INVALID_BLOCK_SCORE);
+        assert!(!node.inner.read().unwrap().ban_scores.contains_key(&honest));
+        Ok(())
     }
 }
@@ -0,0 +1,108 @@
+use crate::errors::Result;
+use crypto::ed25519;
+use failure::format_err;
+
+// the id inputs and wallets store to tell which scheme their keys belong to
+pub const ED25519: u8 = 0;
+
+// a public key signature algorithm keys and signatures of transactions and
+// messages are made with
+pub trait SignatureScheme {
+    fn id(&self) -> u8;
+
+    fn derive_public_key(&self, secret_key: &[u8]) -> Result<Vec<u8>>;
+
+    fn sign(&self, secret_key: &[u8], msg: &[u8]) -> Result<Vec<u8>>;
+
+    // false rather than an error for keys or signatures of the wrong size
+    fn verify(&self, pub_key: &[u8], msg: &[u8], signature: &[u8]) -> bool;
+}
+
+// the scheme wallets and transactions have always used. a secret key is the
+// 64 bytes of the 32 byte seed followed by the public key
+pub struct Ed25519Scheme;
+
+impl Ed25519Scheme {
+    pub fn secret_key_from_seed(&self, seed: &[u8]) -> Vec<u8> {
+        ed25519::keypair(seed).0.to_vec()
+    }
+}
+
+impl SignatureScheme for Ed25519Scheme {
+    fn id(&self) -> u8 {
+        ED25519
+    }
+
+    fn derive_public_key(&self, secret_key: &[u8]) -> Result<Vec<u8>> {
+        if secret_key.len() != 64 {
+            return Err(format_err!("an ed25519 secret key has 64 bytes"));
+        }
+        Ok(secret_key[32..].to_vec())
+    }
+
+    fn sign(&self, secret_key: &[u8], msg: &[u8]) -> Result<Vec<u8>> {
+        if secret_key.len() != 64 {
+            return Err(format_err!("an ed25519 secret key has 64 bytes"));
+        }
+        Ok(ed25519::signature(msg, secret_key).to_vec())
+    }
+
+    fn verify(&self, pub_key: &[u8], msg: &[u8], signature: &[u8]) -> bool {
+        // ed25519::verify panics on keys or signatures of the wrong size
+        if pub_key.len() != 32 || signature.len() != 64 {
+            return false;
+        }
+        ed25519::verify(msg, pub_key, signature)
+    }
+}
+
+// the scheme stored as `id`, none for one this version doesn't know
+pub fn scheme_by_id(id: u8) -> Option<&'static dyn SignatureScheme> {
+    match id {
+        ED25519 => Some(&Ed25519Scheme),
+        #[cfg(test)]
+        STUB => Some(&StubScheme),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub const STUB: u8 = u8::MAX;
+
+// a second scheme for the tests. a signature is the hash of the public key
+// and the message, anyone can make one
+#[cfg(test)]
+pub struct StubScheme;
+
+#[cfg(test)]
+impl StubScheme {
+    fn digest(data: &[&[u8]]) -> Vec<u8> {
+        use crypto::digest::Digest;
+        let mut hasher = crypto::sha2::Sha256::new();
+        for part in data {
+            hasher.input(part);
+        }
+        let mut out = vec![0; 32];
+        hasher.result(&mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+impl SignatureScheme for StubScheme {
+    fn id(&self) -> u8 {
+        STUB
+    }
+
+    fn derive_public_key(&self, secret_key: &[u8]) -> Result<Vec<u8>> {
+        Ok(StubScheme::digest(&[secret_key]))
+    }
+
+    fn sign(&self, secret_key: &[u8], msg: &[u8]) -> Result<Vec<u8>> {
+        Ok(StubScheme::digest(&[&self.derive_public_key(secret_key)?, msg]))
+    }
+
+    fn verify(&self, pub_key: &[u8], msg: &[u8], signature: &[u8]) -> bool {
+        StubScheme::digest(&[pub_key, msg]) == signature
+    }
+}
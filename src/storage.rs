@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use failure::format_err;
+use sled::Transactional;
+
+use crate::errors::Result;
+
+// how long open waits for a database this process just closed
+const LOCK_WAIT: Duration = Duration::from_secs(1);
+
+pub type StorageIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+// key-value store the blocks and the utxos are kept in, a handle is cheap to
+// clone and every clone sees the same data
+pub trait Storage: Clone {
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>>;
+    fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()>;
+    fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<()>;
+    // every pair in key order
+    fn iter(&self) -> StorageIter<'_>;
+    fn flush(&self) -> Result<()>;
+
+    fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    fn clear(&self) -> Result<()> {
+        let keys = self
+            .iter()
+            .map(|kv| kv.map(|(k, _)| k))
+            .collect::<Result<Vec<_>>>()?;
+        for key in keys {
+            self.remove(key)?;
+        }
+        Ok(())
+    }
+
+    // a separate keyspace of the same store, opened again by the same name
+    fn open_tree(&self, name: &str) -> Result<Self>;
+
+    // apply every write of the batch or none of them
+    fn commit(batch: Batch<Self>) -> Result<()>;
+}
+
+// writes to trees of one store, see Storage::commit
+pub struct Batch<S> {
+    writes: Vec<(S, Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl<S: Storage> Batch<S> {
+    pub fn new() -> Batch<S> {
+        Batch { writes: Vec::new() }
+    }
+
+    pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, tree: &S, key: K, value: V) {
+        self.writes
+            .push((tree.clone(), key.as_ref().to_vec(), Some(value.as_ref().to_vec())));
+    }
+
+    pub fn remove<K: AsRef<[u8]>>(&mut self, tree: &S, key: K) {
+        self.writes.push((tree.clone(), key.as_ref().to_vec(), None));
+    }
+
+    // the writes of `other` come after the ones already there
+    pub fn append(&mut self, other: Batch<S>) {
+        self.writes.extend(other.writes);
+    }
+}
+
+impl<S: Storage> Default for Batch<S> {
+    fn default() -> Batch<S> {
+        Batch::new()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SledStorage {
+    db: sled::Db,
+    tree: sled::Tree,
+}
+
+impl SledStorage {
+    pub fn open(path: &str) -> Result<SledStorage> {
+        let db = open_sled(path)?;
+        Ok(SledStorage {
+            tree: (*db).clone(),
+            db,
+        })
+    }
+}
+
+impl Storage for SledStorage {
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        Ok(self.tree.get(key.as_ref())?.map(|v| v.to_vec()))
+    }
+
+    fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()> {
+        self.tree.insert(key.as_ref(), value.as_ref())?;
+        Ok(())
+    }
+
+    fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        self.tree.remove(key.as_ref())?;
+        Ok(())
+    }
+
+    fn iter(&self) -> StorageIter<'_> {
+        Box::new(self.tree.iter().map(|kv| {
+            let (k, v) = kv?;
+            Ok((k.to_vec(), v.to_vec()))
+        }))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        Ok(self.tree.contains_key(key.as_ref())?)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.tree.clear()?;
+        Ok(())
+    }
+
+    fn open_tree(&self, name: &str) -> Result<SledStorage> {
+        Ok(SledStorage {
+            db: self.db.clone(),
+            tree: self.db.open_tree(name)?,
+        })
+    }
+
+    // one sled transaction over all the trees written to
+    fn commit(batch: Batch<SledStorage>) -> Result<()> {
+        let mut trees: Vec<sled::Tree> = Vec::new();
+        let mut batches: Vec<sled::Batch> = Vec::new();
+        for (storage, key, value) in batch.writes {
+            let index = match trees.iter().position(|t| t.name() == storage.tree.name()) {
+                Some(index) => index,
+                None => {
+                    trees.push(storage.tree);
+                    batches.push(sled::Batch::default());
+                    trees.len() - 1
+                }
+            };
+            match value {
+                Some(value) => batches[index].insert(key, value),
+                None => batches[index].remove(key),
+            }
+        }
+        if trees.is_empty() {
+            return Ok(());
+        }
+
+        trees[..]
+            .transaction(|views| {
+                for (view, batch) in views.iter().zip(&batches) {
+                    view.apply_batch(batch)?;
+                }
+                Ok::<(), sled::transaction::ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e| format_err!("cannot commit to the database: {:?}", e))
+    }
+}
+
+// sled lets go of the lock of a dropped database once the work it deferred
+// has run, a moment later, so reopening a database of this process waits for
+// it rather than failing
+pub fn open_sled(path: &str) -> Result<sled::Db> {
+    let mut waited = Duration::ZERO;
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(e))
+                if e.to_string().contains("could not acquire lock") && waited < LOCK_WAIT =>
+            {
+                thread::sleep(Duration::from_millis(10));
+                waited += Duration::from_millis(10);
+            }
+            db => return Ok(db?),
+        }
+    }
+}
+
+type MemoryTree = Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>;
+
+// keeps everything in memory and loses it with the last handle, for tests
+// and throwaway chains
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    trees: Arc<Mutex<HashMap<String, MemoryTree>>>,
+    tree: MemoryTree,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    fn read(&self) -> Result<std::sync::RwLockReadGuard<'_, BTreeMap<Vec<u8>, Vec<u8>>>> {
+        self.tree
+            .read()
+            .map_err(|_| format_err!("memory storage poisoned"))
+    }
+
+    fn write(&self) -> Result<std::sync::RwLockWriteGuard<'_, BTreeMap<Vec<u8>, Vec<u8>>>> {
+        self.tree
+            .write()
+            .map_err(|_| format_err!("memory storage poisoned"))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        Ok(self.read()?.get(key.as_ref()).cloned())
+    }
+
+    fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()> {
+        self.write()?.insert(key.as_ref().to_vec(), value.as_ref().to_vec());
+        Ok(())
+    }
+
+    fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        self.write()?.remove(key.as_ref());
+        Ok(())
+    }
+
+    fn iter(&self) -> StorageIter<'_> {
+        // a snapshot, the lock is not held while iterating
+        match self.read() {
+            Ok(tree) => {
+                let pairs: Vec<_> = tree
+                    .iter()
+                    .map(|(k, v)| Ok((k.clone(), v.clone())))
+                    .collect();
+                Box::new(pairs.into_iter())
+            }
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.write()?.clear();
+        Ok(())
+    }
+
+    fn open_tree(&self, name: &str) -> Result<MemoryStorage> {
+        let mut trees = self
+            .trees
+            .lock()
+            .map_err(|_| format_err!("memory storage poisoned"))?;
+        Ok(MemoryStorage {
+            trees: self.trees.clone(),
+            tree: trees.entry(name.to_string()).or_default().clone(),
+        })
+    }
+
+    fn commit(batch: Batch<MemoryStorage>) -> Result<()> {
+        // every tree written to is locked before the first write, always in
+        // the same order so that two commits cannot deadlock
+        let mut trees: Vec<MemoryTree> = Vec::new();
+        for (storage, _, _) in &batch.writes {
+            if !trees.iter().any(|t| Arc::ptr_eq(t, &storage.tree)) {
+                trees.push(storage.tree.clone());
+            }
+        }
+        trees.sort_by_key(|t| Arc::as_ptr(t) as usize);
+        let mut guards = trees
+            .iter()
+            .map(|t| t.write().map_err(|_| format_err!("memory storage poisoned")))
+            .collect::<Result<Vec<_>>>()?;
+
+        for (storage, key, value) in batch.writes {
+            let index = trees
+                .iter()
+                .position(|t| Arc::ptr_eq(t, &storage.tree))
+                .unwrap();
+            match value {
+                Some(value) => guards[index].insert(key, value),
+                None => guards[index].remove(&key),
+            };
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+// chains, wallets and blocks of regtest the unit tests build on
+
+use std::net::TcpListener;
+use std::sync::atomic::AtomicBool;
+
+use tempfile::TempDir;
+
+use crate::block::Block;
+use crate::blockchain::BlockChain;
+use crate::errors::Result;
+use crate::network::REGTEST;
+use crate::signature::Ed25519Scheme;
+use crate::storage::MemoryStorage;
+use crate::transaction::{hash_pub_key, Transaction};
+use crate::utxoset::Utxoset;
+use crate::wallet::Wallet;
+
+// the same seed always gives the same wallet
+pub fn wallet(seed: u8) -> Wallet {
+    let secret_key = Ed25519Scheme.secret_key_from_seed(&[seed; 32]);
+    Wallet::from_secret_key(&Ed25519Scheme, secret_key).unwrap()
+}
+
+pub fn pub_key_hash(wallet: &Wallet) -> Vec<u8> {
+    let mut pub_key_hash = wallet.public_key.clone();
+    hash_pub_key(&mut pub_key_hash);
+    pub_key_hash
+}
+
+pub fn chain(address: &str) -> Result<BlockChain<MemoryStorage>> {
+    BlockChain::create_in_memory(address.to_string(), &REGTEST)
+}
+
+// a regtest chain of a node in `dir` whose genesis coinbase pays wallet(1),
+// the coinbases can be spent at once
+pub fn node_chain(dir: &TempDir) -> Result<Utxoset> {
+    let data_dir = dir.path().to_str().unwrap();
+    let address = wallet(1).get_address(&REGTEST);
+    let mut utxo = Utxoset::open(BlockChain::create_blockchain(address, data_dir, &REGTEST)?)?;
+    utxo.set_coinbase_maturity(0);
+    Ok(utxo)
+}
+
+// a port nothing listens on right now
+pub fn free_port() -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.port().to_string())
+}
+
+// a chain whose coinbases can be spent at once
+pub fn utxo_chain(address: &str) -> Result<Utxoset<MemoryStorage>> {
+    let mut utxo = Utxoset::in_memory(chain(address)?)?;
+    utxo.set_coinbase_maturity(0);
+    Ok(utxo)
+}
+
+pub fn coinbase(address: &str, height: i32) -> Result<Transaction> {
+    Transaction::new_coinbase(address.to_string(), String::new(), height, &REGTEST)
+}
+
+// mine `transactions`, the coinbase first, on top of `prev_hash`
+pub fn mine_at(
+    transactions: Vec<Transaction>,
+    prev_hash: String,
+    height: i32,
+    difficulty: usize,
+) -> Result<Block> {
+    let never = AtomicBool::new(false);
+    let mined =
+        Block::new_block_with_difficulty(transactions, prev_hash, height, difficulty, &never)?;
+    Ok(mined.unwrap().0)
+}
+
+// mine `txs` after a coinbase paying the subsidy to `address` on top of `prev`
+pub fn mine(address: &str, txs: Vec<Transaction>, prev: &Block) -> Result<Block> {
+    let height = prev.get_height() + 1;
+    let mut transactions = vec![coinbase(address, height)?];
+    transactions.extend(txs);
+    mine_at(transactions, prev.get_hash(), height, REGTEST.difficulty)
+}
@@ -1,28 +1,207 @@
 use std::collections::HashMap;
+use std::fmt;
 
 use crate::{
-    errors::Result, tx::{TXInput, TXOutput}, utxoset::Utxoset, wallet::{Wallet}
+    errors::Result, network::NetworkParams, signature::{scheme_by_id, SignatureScheme, ED25519}, storage::Storage, tx::{Multisig, TXInput, TXOutput}, utxoset::{CoinSelection, Utxoset}, wallet::Wallet
 };
 
-use crypto::{digest::Digest, ed25519, ripemd160::Ripemd160, sha2::Sha256};
+use crypto::{digest::Digest, ripemd160::Ripemd160, sha2::Sha256};
 use failure::format_err;
-use log::error;
+use tracing::error;
+use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 
+// coins minted by the coinbase of the first blocks
+pub const INITIAL_SUBSIDY: u64 = 100;
+// number of blocks after which the subsidy is halved
+pub const HALVING_INTERVAL: i32 = 210_000;
+
+// coins minted by the coinbase of the block at `height`, halved every
+// HALVING_INTERVAL blocks until nothing is left
+pub fn block_subsidy(height: i32) -> u64 {
+    let halvings = height / HALVING_INTERVAL;
+    if halvings >= 64 {
+        return 0;
+    }
+    INITIAL_SUBSIDY >> halvings
+}
+
+// coins minted by the blocks up to `height` included when each coinbase
+// claims its whole subsidy
+pub fn expected_supply(height: i32) -> u64 {
+    let mut supply = 0;
+    let mut start = 0;
+    while start <= height {
+        let end = height.min(start.saturating_add(HALVING_INTERVAL - 1));
+        supply += block_subsidy(start) * (end - start + 1) as u64;
+        if end == height {
+            break;
+        }
+        start = end + 1;
+    }
+    supply
+}
+
+// lock times below are block heights, from there on unix times in seconds
+pub const LOCK_TIME_THRESHOLD: u64 = 500_000_000;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Transaction {
     pub id: String,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>,
+    // the transaction can't be mined in a block below this height or before
+    // this time, see LOCK_TIME_THRESHOLD, 0 for no lock
+    pub lock_time: u64,
 }
 
 impl Transaction {
-    pub fn new_utxo(wallet: &Wallet, to: &str, amount: i32, ut: &Utxoset) -> Result<Transaction> {
-        let mut vin = Vec::<TXInput>::new();
+    pub fn new_utxo<S: Storage>(wallet: &Wallet, to: &str, amount: u64, ut: &Utxoset<S>) -> Result<Transaction> {
+        Transaction::new_utxo_with_fee(wallet, to, amount, 0, ut)
+    }
+
+    // like new_utxo, but the change is reduced by `fee` which is left to the miner
+    pub fn new_utxo_with_fee<S: Storage>(
+        wallet: &Wallet,
+        to: &str,
+        amount: u64,
+        fee: u64,
+        ut: &Utxoset<S>,
+    ) -> Result<Transaction> {
+        let outputs = [(to.to_string(), amount)];
+        Transaction::new_payment(wallet, &outputs, fee, CoinSelection::default(), ut)
+    }
+
+    // one transaction paying every (address, amount) of `outputs`
+    pub fn new_utxo_many<S: Storage>(
+        wallet: &Wallet,
+        outputs: &[(String, u64)],
+        ut: &Utxoset<S>,
+    ) -> Result<Transaction> {
+        Transaction::new_payment(wallet, outputs, 0, CoinSelection::default(), ut)
+    }
+
+    // pay `outputs` and leave `fee` to the miner, spending the outputs picked
+    // by `selection`
+    pub fn new_payment<S: Storage>(
+        wallet: &Wallet,
+        outputs: &[(String, u64)],
+        fee: u64,
+        selection: CoinSelection,
+        ut: &Utxoset<S>,
+    ) -> Result<Transaction> {
+        let mut tx =
+            Transaction::create_raw_transaction(&wallet.public_key, outputs, fee, selection, ut)?;
+        ut.blockchain
+            .sign_transaction(&mut tx, wallet.scheme()?, &wallet.secret_key)?;
+        Ok(tx)
+    }
+
+    // pay `amount` into an output locked to `multisig`, its cosigners spend
+    // it with create_multisig_raw_transaction
+    pub fn new_multisig_payment<S: Storage>(
+        wallet: &Wallet,
+        multisig: Multisig,
+        amount: u64,
+        fee: u64,
+        ut: &Utxoset<S>,
+    ) -> Result<Transaction> {
         let mut pub_key_hash = wallet.public_key.clone();
         hash_pub_key(&mut pub_key_hash);
-        let acc_v = ut.find_spendable_outputs(&pub_key_hash, amount);
-        if acc_v.0 < amount {
+        let params = ut.blockchain.get_params();
+        let lock = TXOutput::new(0, params.encode_address(&pub_key_hash), params)?;
+        let vout = vec![TXOutput::new_multisig(amount, multisig)?];
+        let mut tx = Transaction::build_raw_transaction(
+            &lock,
+            &wallet.public_key,
+            vout,
+            fee,
+            CoinSelection::default(),
+            ut,
+        )?;
+        ut.blockchain
+            .sign_transaction(&mut tx, wallet.scheme()?, &wallet.secret_key)?;
+        Ok(tx)
+    }
+
+    // like new_payment, but only the public key of the spender is needed and
+    // the inputs are left unsigned, see sign_raw_transaction
+    pub fn create_raw_transaction<S: Storage>(
+        public_key: &[u8],
+        outputs: &[(String, u64)],
+        fee: u64,
+        selection: CoinSelection,
+        ut: &Utxoset<S>,
+    ) -> Result<Transaction> {
+        let mut pub_key_hash = public_key.to_vec();
+        hash_pub_key(&mut pub_key_hash);
+        let params = ut.blockchain.get_params();
+        let lock = TXOutput::new(0, params.encode_address(&pub_key_hash), params)?;
+        let vout = Transaction::recipient_outputs(outputs, params)?;
+        Transaction::build_raw_transaction(&lock, public_key, vout, fee, selection, ut)
+    }
+
+    // spend outputs locked to `multisig`, the change goes back to it, each
+    // cosigner then adds a signature with sign_raw_transaction
+    pub fn create_multisig_raw_transaction<S: Storage>(
+        multisig: Multisig,
+        outputs: &[(String, u64)],
+        fee: u64,
+        selection: CoinSelection,
+        ut: &Utxoset<S>,
+    ) -> Result<Transaction> {
+        let lock = TXOutput::new_multisig(0, multisig)?;
+        let vout = Transaction::recipient_outputs(outputs, ut.blockchain.get_params())?;
+        Transaction::build_raw_transaction(&lock, &[], vout, fee, selection, ut)
+    }
+
+    fn recipient_outputs(
+        outputs: &[(String, u64)],
+        params: &NetworkParams,
+    ) -> Result<Vec<TXOutput>> {
+        if outputs.is_empty() {
+            return Err(format_err!("transaction has no recipient"));
+        }
+        let mut vout = Vec::new();
+        for (to, amount) in outputs {
+            vout.push(TXOutput::new(*amount, to.clone(), params)?);
+        }
+        Ok(vout)
+    }
+
+    // spend the outputs locked like `lock` to pay `vout` and `fee`, the
+    // change is locked like `lock` too, the inputs carry `public_key`
+    fn build_raw_transaction<S: Storage>(
+        lock: &TXOutput,
+        public_key: &[u8],
+        mut vout: Vec<TXOutput>,
+        fee: u64,
+        selection: CoinSelection,
+        ut: &Utxoset<S>,
+    ) -> Result<Transaction> {
+        if let Some(out) = vout.iter().find(|out| out.value == 0) {
+            return Err(format_err!(
+                "invalid amount {} for {}",
+                out.value,
+                ut.blockchain.get_params().encode_address(&out.pub_key_hash)
+            ));
+        }
+        if let Some(out) = vout.iter().find(|out| out.value < ut.get_dust_threshold()) {
+            return Err(format_err!(
+                "amount {} for {} is below the dust threshold {}",
+                out.value,
+                ut.blockchain.get_params().encode_address(&out.pub_key_hash),
+                ut.get_dust_threshold()
+            ));
+        }
+        let total = vout
+            .iter()
+            .try_fold(fee, |total, out| total.checked_add(out.value))
+            .ok_or_else(|| format_err!("the amounts and fee add up to more than {}", u64::MAX))?;
+
+        let mut vin = Vec::<TXInput>::new();
+        let acc_v = ut.find_spendable_outputs(&lock.pub_key_hash, total, selection)?;
+        if acc_v.0 < total {
             error!("Not Enough Balance");
             return Err(format_err!(
                 "No Enough Balance: Current Balance {}",
@@ -36,16 +215,21 @@ impl Transaction {
                     txid: tx.0.clone(),
                     vout: out,
                     signature: Vec::new(),
-                    pub_key: wallet.public_key.clone(),
+                    pub_key: public_key.to_vec(),
+                    multisig_signatures: Vec::new(),
+                    scheme: ED25519,
                 };
                 vin.push(input);
             }
         }
-        let mut vout = vec![TXOutput::new(amount, to.to_string())?];
 
-        // create change output
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, wallet.get_address())?);
+        // create change output, dust is left to the miner
+        let change = acc_v.0 - total;
+        if change > 0 && change >= ut.get_dust_threshold() {
+            vout.push(TXOutput {
+                value: change,
+                ..lock.clone()
+            });
         }
 
         // create transaction
@@ -53,42 +237,111 @@ impl Transaction {
             id: String::new(),
             vin,
             vout,
+            lock_time: 0,
         };
 
         tx.id = tx.hash()?;
+        Ok(tx)
+    }
 
-
-        // 
-        ut.blockchain
-            .sign_transaction(&mut tx, &wallet.secret_key)?;
+    // sign a transaction built by create_raw_transaction, `prev_txs` must hold
+    // every transaction it spends from
+    pub fn sign_raw_transaction(
+        mut tx: Transaction,
+        scheme: &dyn SignatureScheme,
+        private_key: &[u8],
+        prev_txs: HashMap<String, Transaction>,
+    ) -> Result<Transaction> {
+        for vin in &tx.vin {
+            if !tx.is_coinbase() && !prev_txs.contains_key(&vin.txid) {
+                return Err(format_err!("previous transaction {} is missing", vin.txid));
+            }
+        }
+        tx.sign(scheme, private_key, prev_txs)?;
         Ok(tx)
     }
 
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
-        if data == String::from("") {
+    pub fn new_coinbase(
+        to: String,
+        data: String,
+        height: i32,
+        params: &NetworkParams,
+    ) -> Result<Transaction> {
+        Transaction::new_coinbase_with_reward(to, data, block_subsidy(height), params)
+    }
+
+    // coinbase paying the subsidy plus the fees of the block's transactions
+    pub fn new_coinbase_with_reward(
+        to: String,
+        mut data: String,
+        reward: u64,
+        params: &NetworkParams,
+    ) -> Result<Transaction> {
+        if data.is_empty() {
             data += &format!("Reward to {}", to);
         }
 
+        // a random nonce keeps the ids of coinbases with the same reward apart
+        let mut pub_key = Vec::from(data.as_bytes());
+        let mut nonce = [0; 8];
+        OsRng.fill_bytes(&mut nonce);
+        pub_key.extend_from_slice(&nonce);
+
         let mut tx = Transaction {
             id: String::new(),
             vin: vec![TXInput {
                 txid: String::new(),
                 vout: -1,
                 signature: Vec::new(),
-                pub_key: Vec::from(data.as_bytes()),
+                pub_key,
+                multisig_signatures: Vec::new(),
+                scheme: ED25519,
             }],
-            vout: vec![TXOutput::new(100, to)?],
+            vout: vec![TXOutput::new(reward, to, params)?],
+            lock_time: 0,
         };
         tx.id = tx.hash()?;
         Ok(tx)
     }
 
+    // the bincode encoding of the transaction as hex, to move it around
+    // outside of the peer protocol
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(hex::encode(bincode::serialize(self)?))
+    }
+
+    pub fn from_hex(data: &str) -> Result<Transaction> {
+        let bytes = hex::decode(data.trim()).map_err(|e| format_err!("invalid hex: {}", e))?;
+        bincode::deserialize(&bytes).map_err(|e| format_err!("invalid transaction: {}", e))
+    }
+
+    // lock an unsigned transaction, the id changes and the signatures cover it
+    pub fn set_lock_time(&mut self, lock_time: u64) -> Result<()> {
+        self.lock_time = lock_time;
+        self.id = self.unsigned_hash()?;
+        Ok(())
+    }
+
+    // whether the transaction can go into a block at `height` with a
+    // timestamp of `time` seconds
+    pub fn is_final(&self, height: i32, time: u64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+        if self.lock_time < LOCK_TIME_THRESHOLD {
+            self.lock_time <= height.max(0) as u64
+        } else {
+            self.lock_time <= time
+        }
+    }
+
     pub fn is_coinbase(&self) -> bool {
-        return self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1;
+        self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
 
     pub fn sign(
         &mut self,
+        scheme: &dyn SignatureScheme,
         private_key: &[u8],
         prev_txs: HashMap<String, Transaction>,
     ) -> Result<()> {
@@ -101,23 +354,44 @@ impl Transaction {
                 return Err(format_err!("Transaction not found"));
             }
         }
-        let mut tx_copy = self.trim_copy();
+        let public_key = scheme.derive_public_key(private_key)?;
+        let mut signer = public_key.clone();
+        hash_pub_key(&mut signer);
 
         for in_id in 0..self.vin.len() {
-            // --- Two-step signing ---
-            // step a: inject the prev_hash_key for hash computation. Bind this input with previous output
-            let prev_tx = prev_txs.get(&tx_copy.vin[in_id].txid).unwrap();
-            tx_copy.vin[in_id].signature.clear();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[tx_copy.vin[in_id].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-
-            // step b: remove the pubkey, sign the hash. Sign this input
-            tx_copy.vin[in_id].pub_key = Vec::new();
-            let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
-            self.vin[in_id].signature = signature.to_vec();
+            let prev_tx = prev_txs.get(&self.vin[in_id].txid).unwrap();
+            let prev_out = &prev_tx.vout[self.vin[in_id].vout as usize];
+            // an input locked to other keys is left to their owners
+            if prev_out.multisig.is_none() && prev_out.pub_key_hash != signer {
+                continue;
+            }
+            if let Some(multisig) = &prev_out.multisig {
+                if !multisig.pub_key_hashes.contains(&signer) {
+                    continue;
+                }
+                let vin = &self.vin[in_id];
+                if !vin.multisig_signatures.is_empty() && vin.scheme != scheme.id() {
+                    return Err(format_err!(
+                        "input {}:{} is signed with another scheme",
+                        vin.txid,
+                        vin.vout
+                    ));
+                }
+            }
+            // the scheme is part of what is signed
+            self.vin[in_id].scheme = scheme.id();
+            let hash = self.signing_hash(in_id, &prev_out.pub_key_hash)?;
+            let signature = scheme.sign(private_key, hash.as_bytes())?;
+            if prev_out.multisig.is_some() {
+                let signatures = &mut self.vin[in_id].multisig_signatures;
+                signatures.retain(|(key, _)| *key != public_key);
+                signatures.push((public_key.clone(), signature));
+            } else {
+                self.vin[in_id].signature = signature;
+            }
         }
+        // the schemes may have changed
+        self.id = self.unsigned_hash()?;
         Ok(())
     }
 
@@ -129,35 +403,119 @@ impl Transaction {
         Ok(hasher.result_str())
     }
 
+    // what input `in_id` signs: the transaction without its signatures and
+    // keys, the input holding the key hash of the output it spends instead.
+    // the schemes of the other inputs are left out, their owners may sign
+    // them with another one afterwards
+    fn signing_hash(&self, in_id: usize, pub_key_hash: &[u8]) -> Result<String> {
+        let mut tx_copy = self.trim_copy();
+        for (i, vin) in tx_copy.vin.iter_mut().enumerate() {
+            if i != in_id {
+                vin.scheme = ED25519;
+            }
+        }
+        tx_copy.vin[in_id].pub_key = pub_key_hash.to_vec();
+        tx_copy.hash()
+    }
+
+    // the id of a transaction, signing doesn't change it
+    pub fn unsigned_hash(&self) -> Result<String> {
+        let mut tx = self.clone();
+        for vin in &mut tx.vin {
+            vin.signature.clear();
+            vin.multisig_signatures.clear();
+        }
+        tx.hash()
+    }
+
     pub fn verify(&self, prev_txs: HashMap<String, Transaction>) -> Result<bool> {
         if self.is_coinbase() {
             return Ok(true);
         }
+        let mut prev_outs = Vec::new();
+        for vin in &self.vin {
+            match prev_txs
+                .get(&vin.txid)
+                .and_then(|prev_tx| prev_tx.vout.get(vin.vout as usize))
+            {
+                Some(out) => prev_outs.push(out.clone()),
+                None => return Err(format_err!("Error: Previous transaction is not correct")),
+            }
+        }
+        self.verify_spends(&prev_outs)
+    }
+
+    // like verify, `prev_outs` holds the output each input spends in order
+    pub fn verify_spends(&self, prev_outs: &[TXOutput]) -> Result<bool> {
+        if self.id != self.unsigned_hash()? {
+            return Ok(false);
+        }
+        if self.is_coinbase() {
+            return Ok(true);
+        }
+        if prev_outs.len() != self.vin.len() {
+            return Err(format_err!("Error: Previous transaction is not correct"));
+        }
 
-        for tx_input in &self.vin {
-            if prev_txs.get(&tx_input.txid).unwrap().id.is_empty() {
-                return Err(format_err!("Error: Previous transaction is not correct"));
+        // an output claiming to be multisig must be indexed by its hash
+        for out in &self.vout {
+            if let Some(multisig) = &out.multisig {
+                if multisig.check().is_err() || multisig.hash()? != out.pub_key_hash {
+                    return Ok(false);
+                }
             }
-            let mut tx_copy = self.trim_copy();
-
-            for in_id in 0..tx_copy.vin.len() {
-                let prev_tx = prev_txs.get(&tx_copy.vin[in_id].txid).unwrap();
-                let idx = tx_copy.vin[in_id].vout;
-                tx_copy.vin[in_id].pub_key = prev_tx.vout[idx as usize].pub_key_hash.clone();
-                tx_copy.vin[in_id].signature.clear();
-                tx_copy.id = tx_copy.hash()?;
-                if !ed25519::verify(
-                    &tx_copy.id.as_bytes(),
-                    &self.vin[in_id].pub_key,
-                    &self.vin[in_id].signature,
-                ) {
+        }
+
+        for (in_id, (vin, prev_out)) in self.vin.iter().zip(prev_outs).enumerate() {
+            let scheme = match scheme_by_id(vin.scheme) {
+                Some(scheme) => scheme,
+                None => return Ok(false),
+            };
+
+            let hash = self.signing_hash(in_id, &prev_out.pub_key_hash)?;
+            if let Some(multisig) = &prev_out.multisig {
+                let data = hash.as_bytes();
+                if !verify_multisig(scheme, multisig, &vin.multisig_signatures, data) {
                     return Ok(false);
                 }
+                continue;
+            }
+
+            // the key must be the one the spent output is locked to, not
+            // just any key with a matching signature
+            let mut pub_key_hash = vin.pub_key.clone();
+            hash_pub_key(&mut pub_key_hash);
+            if pub_key_hash != prev_out.pub_key_hash {
+                return Ok(false);
+            }
+
+            if !scheme.verify(&vin.pub_key, hash.as_bytes(), &vin.signature) {
+                return Ok(false);
             }
         }
         Ok(true)
     }
 
+    // value of the inputs not claimed by the outputs, `prev_outs` holds the
+    // output each input spends
+    pub fn fee(&self, prev_outs: &[TXOutput]) -> Result<u64> {
+        if self.is_coinbase() {
+            return Ok(0);
+        }
+        let input = prev_outs
+            .iter()
+            .try_fold(0u64, |input, out| input.checked_add(out.value))
+            .ok_or_else(|| format_err!("inputs of {} overflow", self.id))?;
+        let output = self
+            .vout
+            .iter()
+            .try_fold(0u64, |output, out| output.checked_add(out.value));
+        match output {
+            Some(output) if output <= input => Ok(input - output),
+            _ => Err(format_err!("transaction {} spends more than its inputs", self.id)),
+        }
+    }
+
     pub fn trim_copy(&self) -> Transaction {
         let mut vin = Vec::<TXInput>::new();
         let mut vout = Vec::<TXOutput>::new();
@@ -168,22 +526,107 @@ impl Transaction {
                 vout: i.vout,
                 signature: Vec::new(),
                 pub_key: Vec::new(),
+                multisig_signatures: Vec::new(),
+                scheme: i.scheme,
             });
         }
         for i in &self.vout {
-            vout.push(TXOutput {
-                value: i.value,
-                pub_key_hash: i.pub_key_hash.clone(),
-            });
+            vout.push(i.clone());
         }
         Transaction {
             id: self.id.clone(),
             vin,
             vout,
+            lock_time: self.lock_time,
         }
     }
 }
 
+// a transaction with the addresses of a network, see Transaction::display
+pub struct TransactionDisplay<'a> {
+    tx: &'a Transaction,
+    params: &'a NetworkParams,
+}
+
+impl Transaction {
+    // the inputs and outputs with the addresses of `params` they belong to,
+    // Debug shows the raw key hashes
+    pub fn display<'a>(&'a self, params: &'a NetworkParams) -> TransactionDisplay<'a> {
+        TransactionDisplay { tx: self, params }
+    }
+}
+
+impl fmt::Display for TransactionDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tx = self.tx;
+        writeln!(f, "transaction {}", tx.id)?;
+        if tx.lock_time != 0 {
+            writeln!(f, "  locked until {}", tx.lock_time)?;
+        }
+        if tx.is_coinbase() {
+            writeln!(f, "  in  coinbase")?;
+        } else {
+            for vin in &tx.vin {
+                if vin.pub_key.is_empty() {
+                    writeln!(
+                        f,
+                        "  in  {}:{} with {} multisig signatures",
+                        vin.txid,
+                        vin.vout,
+                        vin.multisig_signatures.len()
+                    )?;
+                    continue;
+                }
+                let mut pub_key_hash = vin.pub_key.clone();
+                hash_pub_key(&mut pub_key_hash);
+                writeln!(
+                    f,
+                    "  in  {}:{} from {}",
+                    vin.txid,
+                    vin.vout,
+                    self.params.encode_address(&pub_key_hash)
+                )?;
+            }
+        }
+        for (index, out) in tx.vout.iter().enumerate() {
+            let address = self.params.encode_address(&out.pub_key_hash);
+            write!(f, "  out {}: {} to {}", index, out.value, address)?;
+            if let Some(multisig) = &out.multisig {
+                write!(f, " ({} of", multisig.threshold)?;
+                for pub_key_hash in &multisig.pub_key_hashes {
+                    write!(f, " {}", self.params.encode_address(pub_key_hash))?;
+                }
+                write!(f, ")")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// at least `threshold` distinct cosigners signed `data`, a signature that
+// doesn't verify fails the whole input
+fn verify_multisig(
+    scheme: &dyn SignatureScheme,
+    multisig: &Multisig,
+    signatures: &[(Vec<u8>, Vec<u8>)],
+    data: &[u8],
+) -> bool {
+    let mut signers: Vec<Vec<u8>> = Vec::new();
+    for (pub_key, signature) in signatures {
+        let mut pub_key_hash = pub_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+        if !multisig.pub_key_hashes.contains(&pub_key_hash) || signers.contains(&pub_key_hash) {
+            return false;
+        }
+        if !scheme.verify(pub_key, data, signature) {
+            return false;
+        }
+        signers.push(pub_key_hash);
+    }
+    signers.len() >= multisig.threshold as usize
+}
+
 pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     let mut hasher1 = Sha256::new();
     hasher1.input(pub_key);
@@ -195,3 +638,304 @@ pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     pub_key.resize(20, 0);
     hasher2.result(pub_key);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::REGTEST;
+    use crate::signature::{Ed25519Scheme, StubScheme, STUB};
+    use crate::test_utils::{pub_key_hash, utxo_chain, wallet};
+    use crate::utxoset::DUST_THRESHOLD;
+
+    #[test]
+    fn a_transaction_is_known_by_its_unsigned_hash() -> Result<()> {
+        let miner = wallet(1);
+        let utxo = utxo_chain(&miner.get_address(&REGTEST))?;
+        let payee = wallet(2).get_address(&REGTEST);
+        let tx = Transaction::new_utxo(&miner, &payee, 10, &utxo)?;
+        assert_eq!(tx.id, tx.unsigned_hash()?);
+        assert!(utxo.blockchain.verify_transaction(&tx)?);
+
+        // the same transaction under the hash of its signed form
+        let mut renamed = tx.clone();
+        renamed.id = renamed.clone().hash()?;
+        assert_ne!(renamed.id, tx.id);
+        assert!(!utxo.blockchain.verify_transaction(&renamed)?);
+        Ok(())
+    }
+
+    #[test]
+    fn inputs_signed_with_different_schemes_verify() -> Result<()> {
+        let miner = wallet(1);
+        let address = miner.get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        let stub = Wallet::from_secret_key(&StubScheme, vec![7; 32])?;
+        let paid = Transaction::new_utxo(&miner, &stub.get_address(&REGTEST), 10, &utxo)?;
+        utxo.mine_block(&address, vec![paid.clone()])?;
+
+        // one input for each owner, they sign one after the other
+        let mut vin = Vec::new();
+        for (vout, out) in paid.vout.iter().enumerate() {
+            let owner = if out.pub_key_hash == pub_key_hash(&stub) { &stub } else { &miner };
+            vin.push(TXInput {
+                txid: paid.id.clone(),
+                vout: vout as i32,
+                signature: Vec::new(),
+                pub_key: owner.public_key.clone(),
+                multisig_signatures: Vec::new(),
+                scheme: ED25519,
+            });
+        }
+        let value = paid.vout.iter().map(|out| out.value).sum();
+        let payee = wallet(2).get_address(&REGTEST);
+        let mut tx = Transaction {
+            id: String::new(),
+            vin,
+            vout: vec![TXOutput::new(value, payee, &REGTEST)?],
+            lock_time: 0,
+        };
+        tx.id = tx.unsigned_hash()?;
+        let prev_outs = paid.vout.clone();
+        let prev_txs = HashMap::from([(paid.id.clone(), paid)]);
+        tx.sign(&Ed25519Scheme, &miner.secret_key, prev_txs.clone())?;
+        tx.sign(&StubScheme, &stub.secret_key, prev_txs)?;
+
+        assert_eq!(tx.vin.iter().filter(|vin| vin.scheme == STUB).count(), 1);
+        assert!(utxo.blockchain.verify_transaction(&tx)?);
+        utxo.mine_block(&address, vec![tx.clone()])?;
+
+        // a signature doesn't hold for its input under another scheme
+        for vin in &mut tx.vin {
+            vin.scheme = if vin.scheme == STUB { ED25519 } else { STUB };
+        }
+        tx.id = tx.unsigned_hash()?;
+        assert!(!tx.verify_spends(&prev_outs)?);
+        Ok(())
+    }
+
+    #[test]
+    fn a_2_of_3_multisig_output_needs_two_signatures() -> Result<()> {
+        let miner = wallet(1);
+        let address = miner.get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        let cosigners = [wallet(2), wallet(3), wallet(4)];
+        let multisig = Multisig::new(2, cosigners.iter().map(pub_key_hash).collect())?;
+        let funding = Transaction::new_multisig_payment(&miner, multisig.clone(), 50, 0, &utxo)?;
+        utxo.mine_block(&address, vec![funding.clone()])?;
+
+        let payee = wallet(5).get_address(&REGTEST);
+        let outputs = [(payee, 50)];
+        let selection = CoinSelection::default();
+        let raw =
+            Transaction::create_multisig_raw_transaction(multisig, &outputs, 0, selection, &utxo)?;
+        let prev_txs = HashMap::from([(funding.id.clone(), funding)]);
+        let sign = |tx: Transaction, cosigner: &Wallet| {
+            let key = &cosigner.secret_key;
+            Transaction::sign_raw_transaction(tx, &Ed25519Scheme, key, prev_txs.clone())
+        };
+
+        let once = sign(raw, &cosigners[0])?;
+        assert!(!utxo.blockchain.verify_transaction(&once)?);
+        // the same key again doesn't count twice
+        let again = sign(once.clone(), &cosigners[0])?;
+        assert!(!utxo.blockchain.verify_transaction(&again)?);
+        let twice = sign(once, &cosigners[2])?;
+        assert!(utxo.blockchain.verify_transaction(&twice)?);
+        utxo.mine_block(&address, vec![twice])?;
+        Ok(())
+    }
+
+    #[test]
+    fn coinbases_to_one_address_have_distinct_ids() -> Result<()> {
+        let address = wallet(1).get_address(&REGTEST);
+        let first = Transaction::new_coinbase(address.clone(), String::new(), 1, &REGTEST)?;
+        let second = Transaction::new_coinbase(address.clone(), String::new(), 2, &REGTEST)?;
+        assert_eq!(first.vout[0].value, second.vout[0].value);
+        assert_ne!(first.id, second.id);
+
+        // each one stays spendable under its own id
+        let mut utxo = utxo_chain(&address)?;
+        let ids = [
+            utxo.mine_block(&address, Vec::new())?.get_transactions()[0].id.clone(),
+            utxo.mine_block(&address, Vec::new())?.get_transactions()[0].id.clone(),
+        ];
+        assert_ne!(ids[0], ids[1]);
+        for id in &ids {
+            assert!(utxo.get_txout(id, 0)?.is_some());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn the_fee_goes_to_the_coinbase() -> Result<()> {
+        let miner = wallet(1);
+        let mut utxo = utxo_chain(&miner.get_address(&REGTEST))?;
+        let payee = wallet(2).get_address(&REGTEST);
+        let tx = Transaction::new_utxo_with_fee(&miner, &payee, 10, 5, &utxo)?;
+        assert_eq!(utxo.blockchain.get_transaction_fee(&tx)?, 5);
+
+        let other = wallet(3).get_address(&REGTEST);
+        let block = utxo.mine_block(&other, vec![tx])?;
+        let coinbase = &block.get_transactions()[0];
+        assert!(coinbase.is_coinbase());
+        assert_eq!(coinbase.vout[0].value, block_subsidy(1) + 5);
+        Ok(())
+    }
+
+    #[test]
+    fn the_subsidy_halves_until_nothing_is_left() -> Result<()> {
+        assert_eq!(block_subsidy(0), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(HALVING_INTERVAL - 1), INITIAL_SUBSIDY);
+        assert_eq!(block_subsidy(HALVING_INTERVAL), INITIAL_SUBSIDY / 2);
+        assert_eq!(block_subsidy(2 * HALVING_INTERVAL - 1), INITIAL_SUBSIDY / 2);
+        assert_eq!(block_subsidy(2 * HALVING_INTERVAL), INITIAL_SUBSIDY / 4);
+        // 100 is gone after 7 halvings
+        assert_eq!(block_subsidy(6 * HALVING_INTERVAL), 1);
+        assert_eq!(block_subsidy(7 * HALVING_INTERVAL), 0);
+        assert_eq!(block_subsidy(i32::MAX), 0);
+
+        assert_eq!(expected_supply(HALVING_INTERVAL), 100 * HALVING_INTERVAL as u64 + 50);
+        assert_eq!(expected_supply(i32::MAX), expected_supply(7 * HALVING_INTERVAL));
+        Ok(())
+    }
+
+    #[test]
+    fn a_transaction_pays_two_recipients_and_the_change() -> Result<()> {
+        let miner = wallet(1);
+        let mut utxo = utxo_chain(&miner.get_address(&REGTEST))?;
+        let (first, second) = (wallet(2), wallet(3));
+        let outputs = [
+            (first.get_address(&REGTEST), 10),
+            (second.get_address(&REGTEST), 20),
+        ];
+        let tx = Transaction::new_utxo_many(&miner, &outputs, &utxo)?;
+        assert_eq!(tx.vout.len(), 3);
+        let paid: Vec<_> = tx.vout.iter().map(|o| (o.pub_key_hash.clone(), o.value)).collect();
+        assert_eq!(
+            paid,
+            vec![
+                (pub_key_hash(&first), 10),
+                (pub_key_hash(&second), 20),
+                (pub_key_hash(&miner), 70),
+            ]
+        );
+        assert!(utxo.blockchain.verify_transaction(&tx)?);
+        utxo.mine_block(&wallet(4).get_address(&REGTEST), vec![tx.clone()])?;
+        assert_eq!(utxo.get_txout(&tx.id, 2)?.map(|out| out.value), Some(70));
+        Ok(())
+    }
+
+    #[test]
+    fn a_transaction_survives_a_hex_round_trip() -> Result<()> {
+        let miner = wallet(1);
+        let utxo = utxo_chain(&miner.get_address(&REGTEST))?;
+        let tx = Transaction::new_utxo(&miner, &wallet(2).get_address(&REGTEST), 10, &utxo)?;
+
+        let hex = tx.to_hex()?;
+        let decoded = Transaction::from_hex(&format!("{}\n", hex))?;
+        assert_eq!(decoded.id, tx.id);
+        assert_eq!(decoded.to_hex()?, hex);
+        assert!(utxo.blockchain.verify_transaction(&decoded)?);
+
+        let e = Transaction::from_hex("zz").unwrap_err();
+        assert!(e.to_string().contains("invalid hex"), "{}", e);
+        let e = Transaction::from_hex(&hex[..20]).unwrap_err();
+        assert!(e.to_string().contains("invalid transaction"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn a_raw_transaction_verifies_once_signed() -> Result<()> {
+        let owner = wallet(1);
+        let utxo = utxo_chain(&owner.get_address(&REGTEST))?;
+        let outputs = [(wallet(2).get_address(&REGTEST), 10)];
+        let selection = CoinSelection::default();
+        let raw =
+            Transaction::create_raw_transaction(&owner.public_key, &outputs, 0, selection, &utxo)?;
+        assert!(raw.vin.iter().all(|vin| vin.signature.is_empty()));
+        assert!(!utxo.blockchain.verify_transaction(&raw)?);
+
+        // the signer only needs the raw transaction and what it spends
+        let hex = raw.to_hex()?;
+        let prev_txs = utxo.blockchain.get_prev_txs(&raw)?;
+        let e = Transaction::sign_raw_transaction(
+            Transaction::from_hex(&hex)?,
+            &Ed25519Scheme,
+            &owner.secret_key,
+            HashMap::new(),
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("is missing"), "{}", e);
+        let tx = Transaction::sign_raw_transaction(
+            Transaction::from_hex(&hex)?,
+            &Ed25519Scheme,
+            &owner.secret_key,
+            prev_txs,
+        )?;
+        assert_eq!(tx.id, raw.id);
+        assert!(utxo.blockchain.verify_transaction(&tx)?);
+        Ok(())
+    }
+
+    #[test]
+    fn dust_is_refused_as_an_output_and_left_to_the_miner_as_change() -> Result<()> {
+        let miner = wallet(1);
+        let address = miner.get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        let payee = wallet(2).get_address(&REGTEST);
+        assert_eq!(utxo.get_dust_threshold(), DUST_THRESHOLD);
+        let e = Transaction::new_utxo(&miner, &payee, DUST_THRESHOLD - 1, &utxo).unwrap_err();
+        assert!(e.to_string().contains("below the dust threshold"), "{}", e);
+
+        // 3 left over from the genesis coinbase is not worth an output
+        let tx = Transaction::new_utxo(&miner, &payee, 97, &utxo)?;
+        assert_eq!(tx.vout.len(), 1);
+        assert_eq!(utxo.blockchain.get_transaction_fee(&tx)?, 3);
+        let block = utxo.mine_block(&address, vec![tx])?;
+        assert_eq!(block.get_transactions()[0].vout[0].value, 103);
+        Ok(())
+    }
+
+    #[test]
+    fn a_signature_by_a_key_the_output_is_not_locked_to_fails() -> Result<()> {
+        let owner = wallet(1);
+        let utxo = utxo_chain(&owner.get_address(&REGTEST))?;
+        let thief = wallet(5);
+        let mut tx = Transaction::new_utxo(&owner, &thief.get_address(&REGTEST), 100, &utxo)?;
+        assert!(utxo.blockchain.verify_transaction(&tx)?);
+
+        // the thief signs the input with their own key, the signature is sound
+        let prev_outs: Vec<TXOutput> = tx
+            .vin
+            .iter()
+            .map(|vin| utxo.get_txout(&vin.txid, vin.vout).map(Option::unwrap))
+            .collect::<Result<_>>()?;
+        tx.vin[0].pub_key = thief.public_key.clone();
+        let hash = tx.signing_hash(0, &prev_outs[0].pub_key_hash)?;
+        tx.vin[0].signature = Ed25519Scheme.sign(&thief.secret_key, hash.as_bytes())?;
+        tx.id = tx.unsigned_hash()?;
+        assert!(Ed25519Scheme.verify(&thief.public_key, hash.as_bytes(), &tx.vin[0].signature));
+
+        assert!(!tx.verify_spends(&prev_outs)?);
+        assert!(!utxo.blockchain.verify_transaction(&tx)?);
+        Ok(())
+    }
+
+    #[test]
+    fn a_transaction_with_ten_inputs_verifies() -> Result<()> {
+        let miner = wallet(1);
+        let address = miner.get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        for _ in 0..9 {
+            utxo.mine_block(&address, Vec::new())?;
+        }
+
+        let tx = Transaction::new_utxo(&miner, &wallet(2).get_address(&REGTEST), 1000, &utxo)?;
+        assert_eq!(tx.vin.len(), 10);
+        assert!(tx.vin.iter().all(|vin| !vin.signature.is_empty()));
+        assert!(utxo.blockchain.verify_transaction(&tx)?);
+        utxo.mine_block(&address, vec![tx.clone()])?;
+        assert_eq!(utxo.get_txout(&tx.id, 0)?.map(|out| out.value), Some(1000));
+        Ok(())
+    }
+}
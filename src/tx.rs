@@ -1,25 +1,92 @@
-use crate::{errors::Result, transaction::hash_pub_key};
-use bitcoincash_addr::Address;
-use log::debug;
+use crate::{errors::Result, network::NetworkParams, transaction::hash_pub_key};
+use failure::format_err;
+use tracing::debug;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+    // unspent outputs of a transaction keyed by their index in `vout`
+    pub outputs: HashMap<i32, TXOutput>,
+    // height of the block that created the outputs
+    pub height: i32,
+    pub is_coinbase: bool,
 }
 
+// keys a multisig output can be locked to at most
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TXInput {
     pub txid: String,
     pub vout: i32,
     pub signature: Vec<u8>,
     pub pub_key: Vec<u8>,
+    // (public key, signature) of each cosigner when the spent output is a
+    // multisig one, signature and pub_key are left empty then
+    pub multisig_signatures: Vec<(Vec<u8>, Vec<u8>)>,
+    // id of the signature scheme of the keys and signatures above, the
+    // cosigners of a multisig input all sign with the same one
+    pub scheme: u8,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TXOutput {
-    pub value: i32,
+    // u64 so that no output can be negative, bincode writes it in 8 bytes
+    pub value: u64,
+    // the hash of `multisig` for a multisig output, so it has an address too
     pub pub_key_hash: Vec<u8>,
+    pub multisig: Option<Multisig>,
+}
+
+// an m-of-n lock, `threshold` of the keys hashed in `pub_key_hashes` must
+// sign to spend the output
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Multisig {
+    pub threshold: u32,
+    pub pub_key_hashes: Vec<Vec<u8>>,
+}
+
+impl Multisig {
+    pub fn new(threshold: u32, pub_key_hashes: Vec<Vec<u8>>) -> Result<Multisig> {
+        let multisig = Multisig {
+            threshold,
+            pub_key_hashes,
+        };
+        multisig.check()?;
+        Ok(multisig)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        let keys = self.pub_key_hashes.len();
+        if keys == 0 || keys > MAX_MULTISIG_KEYS {
+            return Err(format_err!(
+                "a multisig takes 1 to {} keys, not {}",
+                MAX_MULTISIG_KEYS,
+                keys
+            ));
+        }
+        if self.threshold == 0 || self.threshold as usize > keys {
+            return Err(format_err!(
+                "threshold {} is not between 1 and the {} keys",
+                self.threshold,
+                keys
+            ));
+        }
+        for (i, key) in self.pub_key_hashes.iter().enumerate() {
+            if self.pub_key_hashes[..i].contains(key) {
+                return Err(format_err!("a multisig key is listed twice"));
+            }
+        }
+        Ok(())
+    }
+
+    // what the output is indexed by, the multisig address encodes it
+    pub fn hash(&self) -> Result<Vec<u8>> {
+        let mut hash = bincode::serialize(self)?;
+        hash_pub_key(&mut hash);
+        Ok(hash)
+    }
 }
 
 impl TXInput {
@@ -35,20 +102,31 @@ impl TXOutput {
         self.pub_key_hash == unlocking_data
     }
 
-    fn lock(&mut self, address: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
+    // `address` must be one of the network of `params`
+    fn lock(&mut self, address: &str, params: &NetworkParams) -> Result<()> {
+        let pub_key_hash = params.decode_address(address)?;
         debug!("lock: {}", address);
         self.pub_key_hash = pub_key_hash;
         Ok(())
     }
 
-    pub fn new(value: i32, address: String) -> Result<Self> {
+    pub fn new(value: u64, address: String, params: &NetworkParams) -> Result<Self> {
         let mut txo = TXOutput {
             value,
             pub_key_hash: Vec::new(),
+            multisig: None,
         };
 
-        txo.lock(&address)?;
+        txo.lock(&address, params)?;
         Ok(txo)
     }
+
+    pub fn new_multisig(value: u64, multisig: Multisig) -> Result<Self> {
+        multisig.check()?;
+        Ok(TXOutput {
+            value,
+            pub_key_hash: multisig.hash()?,
+            multisig: Some(multisig),
+        })
+    }
 }
@@ -1,71 +1,392 @@
-use crate::{block::Block, errors::Result, tx::TXOutputs};
-use std::{collections::HashMap, fs::remove_dir_all};
+use crate::{
+    block::Block,
+    blockchain::{check_coinbase_value, invalid_block, TipChange},
+    errors::Result,
+    transaction::Transaction,
+    tx::{TXOutput, TXOutputs},
+};
+use failure::format_err;
+use tracing::info;
+use std::collections::HashMap;
 
-use crate::blockchain::BlockChain;
+use crate::blockchain::{BlockChain, ChainInfo};
+use crate::storage::{Batch, MemoryStorage, SledStorage, Storage};
 
-pub struct Utxoset {
+// number of blocks a coinbase output must be buried under before it can be spent
+pub const COINBASE_MATURITY: i32 = 100;
+// payments refuse outputs worth less, and leave smaller change to the miner
+pub const DUST_THRESHOLD: u64 = 5;
+// blocks closer to the tip keep all their transactions so reorganizations
+// of that depth can be undone
+pub const MIN_PRUNE_DEPTH: i32 = 10;
+
+// how find_spendable_outputs picks the outputs to spend
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CoinSelection {
+    // in database order until the amount is reached
+    #[default]
+    Unordered,
+    // fewest inputs
+    LargestFirst,
+    // consolidates dust
+    SmallestFirst,
+    // an exact match needing no change if there is one, largest first otherwise
+    BranchAndBound,
+}
+
+// give up looking for an exact match after that many steps
+const BNB_MAX_TRIES: usize = 100_000;
+
+// outputs of a transaction written by a pending update, None once they are all spent
+type Staged = HashMap<String, Option<TXOutputs>>;
+
+pub struct Utxoset<S: Storage = SledStorage> {
     // allow us to access the data that are connected to the blockchain
     // we can create a new layer inside of the database
-    pub blockchain: BlockChain,
+    pub blockchain: BlockChain<S>,
+    // handle of the utxo database, opened once and shared by all queries
+    db: S,
+    // hash of the block the set is up to date with
+    tip: S,
+    // block hash -> (txid, height) of the transactions whose last unspent
+    // output the block spends, they are dropped when the chain is pruned
+    spent: S,
+    coinbase_maturity: i32,
+    dust_threshold: u64,
+    // spent transactions of deeper blocks are dropped when set
+    prune_depth: Option<i32>,
 }
 
-impl Utxoset {
+impl Utxoset<MemoryStorage> {
+    // the utxos of an in-memory chain, kept in the same store as its blocks
+    pub fn in_memory(blockchain: BlockChain<MemoryStorage>) -> Result<Utxoset<MemoryStorage>> {
+        Utxoset::open(blockchain)
+    }
+}
+
+impl<S: Storage> Utxoset<S> {
+    pub fn new(blockchain: BlockChain<S>, db: S) -> Result<Utxoset<S>> {
+        Ok(Utxoset {
+            blockchain,
+            tip: db.open_tree("utxo_tip")?,
+            spent: db.open_tree("spent")?,
+            db,
+            coinbase_maturity: COINBASE_MATURITY,
+            dust_threshold: DUST_THRESHOLD,
+            prune_depth: None,
+        })
+    }
+
+    // the utxos stored next to the blocks of the chain, rebuilt when they
+    // don't match its tip, e.g. after the chain was imported
+    pub fn open(blockchain: BlockChain<S>) -> Result<Utxoset<S>> {
+        let db = blockchain.get_storage().open_tree("utxos")?;
+        let utxo_set = Utxoset::new(blockchain, db)?;
+        let tip = utxo_set.tip.get("LAST")?;
+        if tip.as_deref() != Some(utxo_set.blockchain.get_tip_hash().as_bytes()) {
+            info!("utxo set is behind the chain, reindex");
+            utxo_set.reindex()?;
+        }
+        Ok(utxo_set)
+    }
+
+    pub fn set_coinbase_maturity(&mut self, blocks: i32) {
+        self.coinbase_maturity = blocks;
+    }
+
+    pub fn set_dust_threshold(&mut self, value: u64) {
+        self.dust_threshold = value;
+    }
+
+    pub fn get_dust_threshold(&self) -> u64 {
+        self.dust_threshold
+    }
+
+    // prune the chain every time a block is added, a pruned chain cannot be
+    // reindexed or exported anymore
+    pub fn set_prune_depth(&mut self, depth: Option<i32>) -> Result<()> {
+        if let Some(depth) = depth {
+            if depth < MIN_PRUNE_DEPTH {
+                return Err(format_err!(
+                    "prune depth {} is below the minimum of {}",
+                    depth,
+                    MIN_PRUNE_DEPTH
+                ));
+            }
+        }
+        self.prune_depth = depth;
+        Ok(())
+    }
+
     // store into database
     pub fn reindex(&self) -> Result<()> {
-        // reset the db files
-        if std::path::Path::new("data/utxos").exists() {
-            remove_dir_all("data/utxos")?;
+        // the spends recorded by the dropped transactions are gone
+        if self.blockchain.is_pruned()? {
+            return Err(format_err!("cannot reindex a pruned chain"));
+        }
+        let mut batch = Batch::new();
+        // reset the stored utxos
+        for tree in [&self.db, &self.spent] {
+            for kv in tree.iter() {
+                let (k, _) = kv?;
+                batch.remove(tree, k);
+            }
         }
-        let db = sled::open("data/utxos")?;
 
         let utxos = self.blockchain.find_utxo();
 
         for (txid, tx_outputs) in utxos {
-            db.insert(txid.as_bytes(), bincode::serialize(&tx_outputs)?)?;
+            batch.insert(&self.db, txid, bincode::serialize(&tx_outputs)?);
         }
-        Ok(())
+
+        // count down the unspent outputs of every transaction from the genesis
+        // block on to find where each one is spent completely
+        let mut blocks: Vec<Block> = self.blockchain.iter().collect();
+        blocks.reverse();
+        let mut unspent: HashMap<String, (i32, usize)> = HashMap::new();
+        for block in &blocks {
+            let mut spent = Vec::new();
+            for tx in block.get_transactions() {
+                for vin in tx.vin.iter().filter(|_| !tx.is_coinbase()) {
+                    if let Some((height, count)) = unspent.get_mut(&vin.txid) {
+                        *count -= 1;
+                        if *count == 0 {
+                            spent.push((vin.txid.clone(), *height));
+                        }
+                    }
+                }
+                unspent.insert(tx.id.clone(), (block.get_height(), tx.vout.len()));
+            }
+            batch.insert(&self.spent, block.get_hash(), bincode::serialize(&spent)?);
+        }
+        batch.insert(&self.tip, "LAST", self.blockchain.get_tip_hash());
+        S::commit(batch)?;
+        self.db.flush()
+    }
+
+    // add a block to the chain and move the set along in the same commit, a
+    // crash cannot leave one without the other
+    pub fn add_block(&mut self, block: Block) -> Result<TipChange> {
+        let mut batch = Batch::new();
+        let change = self.blockchain.stage_block(block, &mut batch)?;
+        self.commit(batch, change)
+    }
+
+    // like BlockChain::mine_block, the set spends the transactions of the block
+    pub fn mine_block(&mut self, miner: &str, txs: Vec<Transaction>) -> Result<Block> {
+        let new_block = self.blockchain.block_template(miner, txs)?.mine()?;
+        let mut batch = Batch::new();
+        let change = self
+            .blockchain
+            .stage_new_block(new_block.clone(), &mut batch)?;
+        self.commit(batch, change)?;
+        Ok(new_block)
     }
 
-    pub fn update(&self, block: &Block) -> Result<()> {
-        let db = sled::open("data/utxos")?;
+    fn commit(&mut self, mut batch: Batch<S>, change: TipChange) -> Result<TipChange> {
+        // a block the set cannot apply is refused and the tip stays, a set
+        // out of sync with the chain is only rebuilt by open
+        let mut staged = Batch::new();
+        self.stage_change(&change, &mut staged)?;
+        batch.append(staged);
+        self.blockchain.commit_tip(batch, &change)?;
+        if let Some(depth) = self.prune_depth {
+            self.blockchain.prune(depth, |block, batch| {
+                batch.remove(&self.spent, block.get_hash());
+                match self.spent.get(block.get_hash())? {
+                    Some(data) => Ok(bincode::deserialize(&data)?),
+                    None => Ok(Vec::new()),
+                }
+            })?;
+        }
+        Ok(change)
+    }
+
+    // the writes moving the set along a change of the best chain: undo the
+    // disconnected blocks from the old tip down, then apply the new branch
+    fn stage_change(&self, change: &TipChange, batch: &mut Batch<S>) -> Result<()> {
+        let tip = match change.connected.last() {
+            Some(tip) => tip.get_hash(),
+            None => return Ok(()),
+        };
+
+        // the blocks of another branch had no outputs to check their
+        // transactions against when they came, they are checked on the way
+        let check = !change.disconnected.is_empty();
+        let mut staged = Staged::new();
+        for block in &change.disconnected {
+            self.rollback(block, &change.disconnected, &mut staged)?;
+        }
+        for block in &change.connected {
+            let spent = self.update(block, check, &mut staged)?;
+            batch.insert(&self.spent, block.get_hash(), bincode::serialize(&spent)?);
+        }
+
+        for (txid, outs) in staged {
+            match outs {
+                Some(outs) if !outs.outputs.is_empty() => {
+                    batch.insert(&self.db, txid, bincode::serialize(&outs)?)
+                }
+                _ => batch.remove(&self.db, txid),
+            }
+        }
+        batch.insert(&self.tip, "LAST", tip);
+        Ok(())
+    }
 
+    // apply the block, returning the transactions it spends the last output of.
+    // with `check` the signatures and the coinbase value are verified against
+    // the outputs the block spends
+    fn update(
+        &self,
+        block: &Block,
+        check: bool,
+        staged: &mut Staged,
+    ) -> Result<Vec<(String, i32)>> {
+        let mut spent = Vec::new();
+        let mut fees: u64 = 0;
         for tx in block.get_transactions() {
             if !tx.is_coinbase() {
+                let mut prev_outs = Vec::new();
                 for tx_i in &tx.vin {
-                    let db_data = db.get(&tx_i.txid)?.unwrap();
-                    let outs: TXOutputs = bincode::deserialize(&db_data)?;
-
-                    let mut update_outs = TXOutputs { outputs: vec![] };
-
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != tx_i.vout as usize {
-                            update_outs.outputs.push(outs.outputs[out_idx].clone());
+                    // the spent outputs must be in the set, the block spends
+                    // one that doesn't exist or was spent before otherwise
+                    let mut outs = match self.get_staged(&tx_i.txid, staged)? {
+                        Some(outs) if outs.outputs.contains_key(&tx_i.vout) => outs,
+                        _ => {
+                            return Err(invalid_block(format!(
+                                "utxo set has no output {} for transaction {}",
+                                tx_i.vout,
+                                tx_i.txid
+                            )))
                         }
+                    };
+                    prev_outs.extend(outs.outputs.remove(&tx_i.vout));
+                    if outs.outputs.is_empty() {
+                        spent.push((tx_i.txid.clone(), outs.height));
                     }
-
-                    if update_outs.outputs.is_empty() {
-                        db.remove(&tx_i.txid)?;
-                    } else {
-                        db.insert(&tx_i.txid, bincode::serialize(&update_outs)?)?;
+                    staged.insert(tx_i.txid.clone(), Some(outs));
+                }
+                if check {
+                    if !tx.verify_spends(&prev_outs)? {
+                        return Err(invalid_block(format!(
+                            "transaction {} in block {} has an invalid signature",
+                            tx.id,
+                            block.get_hash()
+                        )));
                     }
+                    let fee = tx
+                        .fee(&prev_outs)
+                        .map_err(|e| invalid_block(format!("block {}: {}", block.get_hash(), e)))?;
+                    fees = fees.checked_add(fee).ok_or_else(|| {
+                        invalid_block(format!("fees of block {} overflow", block.get_hash()))
+                    })?;
                 }
             }
 
-            let mut new_output = TXOutputs { outputs: vec![] };
+            let mut new_output = TXOutputs {
+                outputs: HashMap::new(),
+                height: block.get_height(),
+                is_coinbase: tx.is_coinbase(),
+            };
 
-            for out in &tx.vout {
-                new_output.outputs.push(out.clone());
+            for (index, out) in tx.vout.iter().enumerate() {
+                new_output.outputs.insert(index as i32, out.clone());
+            }
+            staged.insert(tx.id.clone(), Some(new_output));
+        }
+        if check {
+            check_coinbase_value(block, fees)?;
+        }
+        Ok(spent)
+    }
+
+    // undo the changes of a block that is no longer on the best chain
+    fn rollback(&self, block: &Block, orphans: &[Block], staged: &mut Staged) -> Result<()> {
+        for tx in block.get_transactions().iter().rev() {
+            // the outputs created by the block are gone
+            staged.insert(tx.id.clone(), None);
+
+            // the outputs it spent become unspent again
+            if !tx.is_coinbase() {
+                for tx_i in &tx.vin {
+                    let (prev_tx, height) = self.find_prev_transaction(&tx_i.txid, orphans)?;
+                    let out = match prev_tx.vout.get(tx_i.vout as usize) {
+                        Some(out) => out.clone(),
+                        None => {
+                            return Err(format_err!(
+                                "transaction {} has no output {}",
+                                tx_i.txid,
+                                tx_i.vout
+                            ))
+                        }
+                    };
+                    let mut outs = self.get_staged(&tx_i.txid, staged)?.unwrap_or(TXOutputs {
+                        outputs: HashMap::new(),
+                        height,
+                        is_coinbase: prev_tx.is_coinbase(),
+                    });
+                    outs.outputs.insert(tx_i.vout, out);
+                    staged.insert(tx_i.txid.clone(), Some(outs));
+                }
             }
-            db.insert(tx.id.as_bytes(), bincode::serialize(&new_output)?)?;
         }
         Ok(())
     }
 
+    // the spent transaction and its block height, the block may be one that
+    // was just disconnected
+    fn find_prev_transaction(&self, txid: &str, orphans: &[Block]) -> Result<(Transaction, i32)> {
+        for block in orphans {
+            for tx in block.get_transactions() {
+                if tx.id == txid {
+                    return Ok((tx.clone(), block.get_height()));
+                }
+            }
+        }
+        let (tx, block) = self.blockchain.find_transaction_block(txid)?;
+        Ok((tx, block.get_height()))
+    }
+
+    // the output `vout` of `txid` if it is unspent
+    pub fn get_txout(&self, txid: &str, vout: i32) -> Result<Option<TXOutput>> {
+        Ok(self
+            .get_outputs(txid)?
+            .and_then(|mut outs| outs.outputs.remove(&vout)))
+    }
+
+    fn get_outputs(&self, txid: &str) -> Result<Option<TXOutputs>> {
+        match self.db.get(txid)? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    // the outputs as a pending update left them
+    fn get_staged(&self, txid: &str, staged: &Staged) -> Result<Option<TXOutputs>> {
+        match staged.get(txid) {
+            Some(outs) => Ok(outs.clone().filter(|outs| !outs.outputs.is_empty())),
+            None => self.get_outputs(txid),
+        }
+    }
+
+    // flush the utxo and block databases
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        self.blockchain.flush()
+    }
+
+    // the chain info with the number of transactions holding unspent outputs
+    pub fn chain_info(&self) -> Result<ChainInfo> {
+        let mut info = self.blockchain.chain_info()?;
+        info.utxo_count = Some(self.count_transaction()?);
+        Ok(info)
+    }
+
     pub fn count_transaction(&self) -> Result<i32> {
         let mut counter = 0;
-        let db = sled::open("data/utxos")?;
 
-        for kv in db.iter() {
+        for kv in self.db.iter() {
             kv?;
             counter += 1;
         }
@@ -75,49 +396,500 @@ impl Utxoset {
     pub fn find_spendable_outputs(
         &self,
         address: &[u8],
-        amount: i32,
-    ) -> (i32, HashMap<String, Vec<i32>>) {
-        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
+        amount: u64,
+        selection: CoinSelection,
+    ) -> Result<(u64, HashMap<String, Vec<i32>>)> {
+        // outputs spent now go into the block after the tip
+        let next_height = self.blockchain.get_best_height()? + 1;
 
-        let mut accumulated: i32 = 0;
-        let db = sled::open("data/utxos").unwrap();
-        for kv in db.iter() {
-            let (k, v) = kv.unwrap();
-            let txid = String::from_utf8(k.to_vec()).unwrap();
-            let outs: TXOutputs = bincode::deserialize(&v.to_vec()).unwrap();
-
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].can_be_unlock_with(address) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
-                    match unspent_outputs.get_mut(&txid) {
-                        Some(e) => {
-                            e.push(out_idx as i32);
-                        }
-                        None => {
-                            unspent_outputs.insert(txid.clone(), vec![out_idx as i32]);
-                        }
-                    }
+        let mut candidates: Vec<(String, i32, u64)> = Vec::new();
+        for kv in self.db.iter() {
+            let (k, v) = kv?;
+            let txid = String::from_utf8(k)?;
+            let outs: TXOutputs = bincode::deserialize(&v)?;
+            if outs.is_coinbase && next_height - outs.height < self.coinbase_maturity {
+                continue;
+            }
+
+            for (out_idx, out) in outs.outputs {
+                if out.can_be_unlock_with(address) {
+                    candidates.push((txid.clone(), out_idx, out.value));
                 }
             }
         }
-        (accumulated, unspent_outputs)
-    }
 
-    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs { outputs: vec![] };
+        let selected = match selection {
+            CoinSelection::Unordered => accumulate(candidates, amount),
+            CoinSelection::LargestFirst => {
+                candidates.sort_by_key(|c| std::cmp::Reverse(c.2));
+                accumulate(candidates, amount)
+            }
+            CoinSelection::SmallestFirst => {
+                candidates.sort_by_key(|c| c.2);
+                accumulate(candidates, amount)
+            }
+            CoinSelection::BranchAndBound => {
+                candidates.sort_by_key(|c| std::cmp::Reverse(c.2));
+                match branch_and_bound(&candidates, amount) {
+                    Some(selected) => selected,
+                    None => accumulate(candidates, amount),
+                }
+            }
+        };
 
-        let db = sled::open("data/utxos")?;
+        let mut accumulated: u64 = 0;
+        let mut unspent_outputs: HashMap<String, Vec<i32>> = HashMap::new();
+        for (txid, out_idx, value) in selected {
+            accumulated = accumulated
+                .checked_add(value)
+                .ok_or_else(|| format_err!("selected outputs add up to more than {}", u64::MAX))?;
+            unspent_outputs.entry(txid).or_default().push(out_idx);
+        }
+        Ok((accumulated, unspent_outputs))
+    }
 
-        for kv in db.iter() {
+    // the value of the unspent outputs locked to each pub key hash, this
+    // reads the whole set
+    pub fn balances_by_address(&self) -> Result<HashMap<Vec<u8>, u64>> {
+        let mut balances: HashMap<Vec<u8>, u64> = HashMap::new();
+        for kv in self.db.iter() {
             let (_, v) = kv?;
-            let outs: TXOutputs = bincode::deserialize(&v.to_vec())?;
+            let outs: TXOutputs = bincode::deserialize(&v)?;
+            for out in outs.outputs.into_values() {
+                *balances.entry(out.pub_key_hash).or_default() += out.value;
+            }
+        }
+        Ok(balances)
+    }
+
+    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<Vec<TXOutput>> {
+        let utxos = self.list_unspent(pub_key_hash)?;
+        Ok(utxos.into_iter().map(|(_, _, out)| out).collect())
+    }
+
+    // the unspent outputs locked to `pub_key_hash` with the txid and index
+    // they are spent by
+    pub fn list_unspent(&self, pub_key_hash: &[u8]) -> Result<Vec<(String, i32, TXOutput)>> {
+        let mut utxos = Vec::new();
+
+        for kv in self.db.iter() {
+            let (k, v) = kv?;
+            let txid = String::from_utf8(k)?;
+            let outs: TXOutputs = bincode::deserialize(&v)?;
 
-            for out in outs.outputs {
+            for (out_idx, out) in outs.outputs {
                 if out.can_be_unlock_with(pub_key_hash) {
-                    utxos.outputs.push(out.clone());
+                    utxos.push((txid.clone(), out_idx, out));
                 }
             }
         }
+        utxos.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
         Ok(utxos)
     }
 }
+
+fn accumulate(candidates: Vec<(String, i32, u64)>, amount: u64) -> Vec<(String, i32, u64)> {
+    let mut accumulated = 0;
+    let mut selected = Vec::new();
+    for candidate in candidates {
+        if accumulated >= amount {
+            break;
+        }
+        accumulated = accumulated.saturating_add(candidate.2);
+        selected.push(candidate);
+    }
+    selected
+}
+
+// depth first search for a subset of the candidates, sorted largest first,
+// that adds up to exactly `amount`
+fn branch_and_bound(candidates: &[(String, i32, u64)], amount: u64) -> Option<Vec<(String, i32, u64)>> {
+    // what is left in the candidates after each position, to prune early
+    let mut remaining = vec![0u64; candidates.len() + 1];
+    for i in (0..candidates.len()).rev() {
+        remaining[i] = remaining[i + 1].saturating_add(candidates[i].2);
+    }
+
+    let mut picked = Vec::new();
+    let mut tries = 0;
+    if search(candidates, &remaining, 0, amount, &mut picked, &mut tries) {
+        return Some(picked.into_iter().map(|i| candidates[i].clone()).collect());
+    }
+    None
+}
+
+fn search(
+    candidates: &[(String, i32, u64)],
+    remaining: &[u64],
+    pos: usize,
+    target: u64,
+    picked: &mut Vec<usize>,
+    tries: &mut usize,
+) -> bool {
+    if target == 0 {
+        return true;
+    }
+    *tries += 1;
+    if pos == candidates.len() || remaining[pos] < target || *tries > BNB_MAX_TRIES {
+        return false;
+    }
+
+    if candidates[pos].2 <= target {
+        picked.push(pos);
+        if search(candidates, remaining, pos + 1, target - candidates[pos].2, picked, tries) {
+            return true;
+        }
+        picked.pop();
+    }
+    search(candidates, remaining, pos + 1, target, picked, tries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::REGTEST;
+    use crate::test_utils::{chain, mine, mine_at, pub_key_hash, utxo_chain, wallet};
+    use crate::wallet::Wallet;
+    use std::collections::BTreeMap;
+
+    fn balance<S: Storage>(utxo: &Utxoset<S>, wallet: &Wallet) -> Result<u64> {
+        Ok(utxo.find_utxo(&pub_key_hash(wallet))?.iter().map(|out| out.value).sum())
+    }
+
+    // the outputs and the spends the set holds, in an order that doesn't
+    // depend on how they were written
+    type Outputs = BTreeMap<String, (i32, bool, BTreeMap<i32, String>)>;
+    type Spends = Vec<(String, String, i32)>;
+
+    fn contents<S: Storage>(utxo: &Utxoset<S>) -> Result<(Outputs, Spends)> {
+        let mut outputs = BTreeMap::new();
+        for kv in utxo.db.iter() {
+            let (k, v) = kv?;
+            let outs: TXOutputs = bincode::deserialize(&v)?;
+            let by_index = outs.outputs.iter().map(|(i, out)| (*i, format!("{:?}", out)));
+            let entry = (outs.height, outs.is_coinbase, by_index.collect());
+            outputs.insert(String::from_utf8(k)?, entry);
+        }
+        let mut spends = Vec::new();
+        for kv in utxo.spent.iter() {
+            let (k, v) = kv?;
+            let spent: Vec<(String, i32)> = bincode::deserialize(&v)?;
+            let block = String::from_utf8(k)?;
+            spends.extend(spent.into_iter().map(|(txid, height)| (block.clone(), txid, height)));
+        }
+        spends.sort();
+        Ok((outputs, spends))
+    }
+
+    #[test]
+    fn blocks_applied_one_by_one_match_a_reindex() -> Result<()> {
+        let miner = wallet(1);
+        let address = miner.get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        let payee = wallet(2);
+        for amount in [10, 20, 30, 40] {
+            let tx = Transaction::new_utxo(&miner, &payee.get_address(&REGTEST), amount, &utxo)?;
+            utxo.mine_block(&address, vec![tx])?;
+        }
+        // the payee spends what it got too
+        let back = Transaction::new_utxo(&payee, &address, 55, &utxo)?;
+        utxo.mine_block(&address, vec![back])?;
+        utxo.mine_block(&address, Vec::new())?;
+
+        let updated = contents(&utxo)?;
+        utxo.reindex()?;
+        assert_eq!(contents(&utxo)?, updated);
+        assert_eq!(balance(&utxo, &payee)?, 45);
+        Ok(())
+    }
+
+    #[test]
+    fn open_reindexes_a_set_left_behind_the_chain() -> Result<()> {
+        let miner = wallet(1);
+        let address = miner.get_address(&REGTEST);
+        let mut utxo = Utxoset::in_memory(chain(&address)?)?;
+        utxo.mine_block(&address, Vec::new())?;
+
+        // the block is written but the node stops before the set follows it
+        let mut blockchain = utxo.blockchain.clone();
+        blockchain.mine_block(&address, Vec::new())?;
+        assert_eq!(balance(&utxo, &miner)?, 200);
+
+        let utxo = Utxoset::open(blockchain)?;
+        assert_eq!(balance(&utxo, &miner)?, 300);
+        assert_eq!(utxo.count_transaction()?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn a_block_spending_a_spent_output_is_refused() -> Result<()> {
+        let miner = wallet(1);
+        let address = miner.get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        let payee = wallet(2).get_address(&REGTEST);
+        let tx = Transaction::new_utxo(&miner, &payee, 10, &utxo)?;
+        utxo.mine_block(&address, vec![tx.clone()])?;
+        let tip = utxo.blockchain.get_tip_hash();
+
+        // each block is valid on its own, the second one spends the same output
+        let block = utxo.blockchain.block_template(&address, vec![tx])?.mine()?;
+        assert!(utxo.add_block(block.clone()).is_err());
+        assert_eq!(utxo.blockchain.get_tip_hash(), tip);
+        // nothing of it is written, the block is refused as a whole
+        assert!(!utxo.blockchain.has_block(&block.get_hash())?);
+        assert_eq!(utxo.blockchain.get_best_height()?, 1);
+        assert_eq!(balance(&utxo, &miner)?, 190);
+        Ok(())
+    }
+
+    // a chain where the genesis coinbase of `miner` pays 10 to `payee` in
+    // the block on top of it, with the transaction and the genesis block
+    fn paid_chain(
+        miner: &Wallet,
+        payee: &Wallet,
+    ) -> Result<(Utxoset<MemoryStorage>, Transaction, Block)> {
+        let address = miner.get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        let genesis = utxo.blockchain.get_block(&utxo.blockchain.get_tip_hash())?;
+        let tx = Transaction::new_utxo(miner, &payee.get_address(&REGTEST), 10, &utxo)?;
+        utxo.mine_block(&address, vec![tx.clone()])?;
+        Ok((utxo, tx, genesis))
+    }
+
+    #[test]
+    fn the_heavier_branch_becomes_the_best_chain() -> Result<()> {
+        let (miner, payee) = (wallet(1), wallet(2));
+        let (mut utxo, tx, genesis) = paid_chain(&miner, &payee)?;
+        let other = wallet(3).get_address(&REGTEST);
+
+        // as much work as the best chain, the tip stays
+        let fork = mine(&other, Vec::new(), &genesis)?;
+        let change = utxo.add_block(fork.clone())?;
+        assert!(change.connected.is_empty());
+        assert_eq!(balance(&utxo, &payee)?, 10);
+
+        let tip = mine(&other, Vec::new(), &fork)?;
+        let change = utxo.add_block(tip.clone())?;
+        assert_eq!(utxo.blockchain.get_tip_hash(), tip.get_hash());
+        assert_eq!(change.connected.len(), 2);
+        assert_eq!(change.disconnected.len(), 1);
+        let orphaned: Vec<String> =
+            change.disconnected_transactions().iter().map(|tx| tx.id.clone()).collect();
+        assert_eq!(orphaned, vec![tx.id]);
+
+        // the payment is undone and matches a set rebuilt from the new chain
+        assert_eq!(balance(&utxo, &payee)?, 0);
+        assert_eq!(balance(&utxo, &miner)?, 100);
+        let count = utxo.count_transaction()?;
+        utxo.reindex()?;
+        assert_eq!(utxo.count_transaction()?, count);
+        assert_eq!(balance(&utxo, &miner)?, 100);
+        Ok(())
+    }
+
+    #[test]
+    fn a_branch_with_a_bad_signature_is_refused() -> Result<()> {
+        let (miner, payee) = (wallet(1), wallet(2));
+        let (mut utxo, tx, genesis) = paid_chain(&miner, &payee)?;
+        let tip = utxo.blockchain.get_tip_hash();
+        let other = wallet(3).get_address(&REGTEST);
+
+        // the output is unspent on the branch, but the signature is broken
+        let mut forged = tx;
+        forged.vin[0].signature[0] ^= 1;
+        let fork = mine(&other, vec![forged], &genesis)?;
+        utxo.add_block(fork.clone())?;
+        let e = utxo.add_block(mine(&other, Vec::new(), &fork)?).unwrap_err();
+        assert!(e.to_string().contains("invalid signature"), "{}", e);
+        assert_eq!(utxo.blockchain.get_tip_hash(), tip);
+        assert_eq!(balance(&utxo, &payee)?, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn a_branch_with_an_inflated_coinbase_is_refused() -> Result<()> {
+        let (miner, payee) = (wallet(1), wallet(2));
+        let (mut utxo, _, genesis) = paid_chain(&miner, &payee)?;
+        let tip = utxo.blockchain.get_tip_hash();
+        let other = wallet(3).get_address(&REGTEST);
+
+        let fork = mine(&other, Vec::new(), &genesis)?;
+        utxo.add_block(fork.clone())?;
+        let coinbase = Transaction::new_coinbase_with_reward(other, String::new(), 150, &REGTEST)?;
+        let greedy = mine_at(vec![coinbase], fork.get_hash(), 2, REGTEST.difficulty)?;
+        let e = utxo.add_block(greedy).unwrap_err();
+        assert!(e.to_string().contains("pays 150, expected 100"), "{}", e);
+        assert_eq!(utxo.blockchain.get_tip_hash(), tip);
+        Ok(())
+    }
+
+    #[test]
+    fn a_branch_spending_a_missing_output_is_refused() -> Result<()> {
+        let (miner, payee) = (wallet(1), wallet(2));
+        let (mut utxo, tx, genesis) = paid_chain(&miner, &payee)?;
+        let tip = utxo.blockchain.get_tip_hash();
+        let other = wallet(3).get_address(&REGTEST);
+
+        // the payment twice on the branch, in two blocks
+        let fork = mine(&other, vec![tx.clone()], &genesis)?;
+        utxo.add_block(fork.clone())?;
+        let e = utxo.add_block(mine(&other, vec![tx], &fork)?).unwrap_err();
+        assert!(e.to_string().contains("has no output"), "{}", e);
+        assert_eq!(utxo.blockchain.get_tip_hash(), tip);
+        assert_eq!(balance(&utxo, &payee)?, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn a_coinbase_is_spent_once_buried_deep_enough() -> Result<()> {
+        let miner = wallet(1);
+        let mut utxo = Utxoset::in_memory(chain(&miner.get_address(&REGTEST))?)?;
+        let (payee, other) = (wallet(2).get_address(&REGTEST), wallet(3).get_address(&REGTEST));
+        // the genesis coinbase goes into block COINBASE_MATURITY at the earliest
+        for _ in 0..COINBASE_MATURITY - 2 {
+            utxo.mine_block(&other, Vec::new())?;
+        }
+        let e = Transaction::new_utxo(&miner, &payee, 10, &utxo).unwrap_err();
+        assert!(e.to_string().contains("No Enough Balance"), "{}", e);
+
+        utxo.mine_block(&other, Vec::new())?;
+        let tx = Transaction::new_utxo(&miner, &payee, 10, &utxo)?;
+        let block = utxo.mine_block(&other, vec![tx])?;
+        assert_eq!(block.get_height(), COINBASE_MATURITY);
+        assert_eq!(balance(&utxo, &wallet(2))?, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn each_coin_selection_picks_its_outputs() -> Result<()> {
+        let miner = wallet(1);
+        let address = miner.get_address(&REGTEST);
+        let mut utxo = utxo_chain(&address)?;
+        let payee = wallet(2);
+        let outputs: Vec<_> = [5, 10, 20, 40]
+            .iter()
+            .map(|value| (payee.get_address(&REGTEST), *value))
+            .collect();
+        let tx = Transaction::new_utxo_many(&miner, &outputs, &utxo)?;
+        utxo.mine_block(&address, vec![tx])?;
+
+        let select = |selection| -> Result<(u64, Vec<u64>)> {
+            let (accumulated, picked) =
+                utxo.find_spendable_outputs(&pub_key_hash(&payee), 25, selection)?;
+            let mut values = Vec::new();
+            for (txid, indexes) in picked {
+                for index in indexes {
+                    values.push(utxo.get_txout(&txid, index)?.unwrap().value);
+                }
+            }
+            values.sort();
+            assert_eq!(values.iter().sum::<u64>(), accumulated);
+            Ok((accumulated, values))
+        };
+        assert_eq!(select(CoinSelection::LargestFirst)?, (40, vec![40]));
+        assert_eq!(select(CoinSelection::SmallestFirst)?, (35, vec![5, 10, 20]));
+        assert_eq!(select(CoinSelection::BranchAndBound)?, (25, vec![5, 20]));
+        // in whatever order the set holds them, enough is picked
+        let (accumulated, _) = select(CoinSelection::Unordered)?;
+        assert!(accumulated >= 25);
+        Ok(())
+    }
+
+    #[test]
+    fn list_unspent_gives_each_output_of_an_address() -> Result<()> {
+        let mut utxo = utxo_chain(&wallet(1).get_address(&REGTEST))?;
+        let payee = wallet(2).get_address(&REGTEST);
+        let tx = Transaction::new_utxo(&wallet(1), &payee, 30, &utxo)?;
+        let block = utxo.mine_block(&payee, vec![tx.clone()])?;
+        let coinbase = &block.get_transactions()[0];
+
+        let listed = |wallet: &Wallet| -> Result<Vec<(String, i32, u64)>> {
+            let utxos = utxo.list_unspent(&pub_key_hash(wallet))?;
+            Ok(utxos.into_iter().map(|(txid, vout, out)| (txid, vout, out.value)).collect())
+        };
+        let mut expected = vec![(tx.id.clone(), 0, 30), (coinbase.id.clone(), 0, 100)];
+        expected.sort();
+        assert_eq!(listed(&wallet(2))?, expected);
+        // the genesis coinbase is spent, only the change is left
+        assert_eq!(listed(&wallet(1))?, vec![(tx.id.clone(), 1, 70)]);
+        assert!(listed(&wallet(3))?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn an_output_is_found_until_it_is_spent() -> Result<()> {
+        let mut utxo = utxo_chain(&wallet(1).get_address(&REGTEST))?;
+        let genesis = utxo.blockchain.get_block(&utxo.blockchain.get_tip_hash())?;
+        let coinbase = &genesis.get_transactions()[0];
+        let out = utxo.get_txout(&coinbase.id, 0)?.unwrap();
+        assert_eq!(out.value, 100);
+        assert!(out.can_be_unlock_with(&pub_key_hash(&wallet(1))));
+        assert!(utxo.get_txout(&coinbase.id, 1)?.is_none());
+
+        let tx = Transaction::new_utxo(&wallet(1), &wallet(2).get_address(&REGTEST), 30, &utxo)?;
+        utxo.mine_block(&wallet(3).get_address(&REGTEST), vec![tx.clone()])?;
+        assert!(utxo.get_txout(&coinbase.id, 0)?.is_none());
+        assert_eq!(utxo.get_txout(&tx.id, 0)?.map(|out| out.value), Some(30));
+        Ok(())
+    }
+
+    #[test]
+    fn a_balance_above_i32_max_adds_up() -> Result<()> {
+        let utxo = utxo_chain(&wallet(1).get_address(&REGTEST))?;
+        let rich = wallet(2);
+        let values = [3_000_000_000, 2_500_000_000];
+        for (i, value) in values.into_iter().enumerate() {
+            let out = TXOutput::new(value, rich.get_address(&REGTEST), &REGTEST)?;
+            let outs = TXOutputs {
+                outputs: HashMap::from([(0, out)]),
+                height: 0,
+                is_coinbase: false,
+            };
+            utxo.db.insert(format!("{:064x}", i), bincode::serialize(&outs)?)?;
+        }
+
+        assert_eq!(balance(&utxo, &rich)?, 5_500_000_000);
+        let selection = CoinSelection::default();
+        let (found, spent) =
+            utxo.find_spendable_outputs(&pub_key_hash(&rich), 4_000_000_000, selection)?;
+        assert_eq!(found, 5_500_000_000);
+        assert_eq!(spent.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn a_pruned_chain_keeps_its_tip_and_utxos() -> Result<()> {
+        let miner = wallet(1);
+        let mut utxo = utxo_chain(&miner.get_address(&REGTEST))?;
+        assert!(utxo.set_prune_depth(Some(MIN_PRUNE_DEPTH - 1)).is_err());
+        utxo.set_prune_depth(Some(MIN_PRUNE_DEPTH))?;
+        let genesis = utxo.blockchain.get_tip_hash();
+        let payee = wallet(2);
+        let tx = Transaction::new_utxo(&miner, &payee.get_address(&REGTEST), 30, &utxo)?;
+        let other = wallet(3).get_address(&REGTEST);
+        let spending = utxo.mine_block(&other, vec![tx.clone()])?;
+        for _ in 0..MIN_PRUNE_DEPTH + 4 {
+            utxo.mine_block(&other, Vec::new())?;
+        }
+        let tip = utxo.blockchain.get_tip_hash();
+        assert_eq!(utxo.blockchain.get_pruned_height()?, Some(5));
+
+        // the old bodies are gone, only their headers are left
+        for hash in [&genesis, &spending.get_hash()] {
+            let e = utxo.blockchain.get_block(hash).unwrap_err();
+            assert!(e.to_string().contains("is pruned"), "{}", e);
+        }
+        assert!(utxo.blockchain.get_block_by_height(6).is_ok());
+        assert!(utxo.reindex().is_err());
+
+        assert_eq!(balance(&utxo, &payee)?, 30);
+        assert_eq!(balance(&utxo, &miner)?, 70);
+        utxo.blockchain.validate_chain()?;
+        // the outputs of pruned blocks can still be spent
+        let tx = Transaction::new_utxo(&payee, &other, 30, &utxo)?;
+        let block = utxo.mine_block(&other, vec![tx])?;
+        assert_eq!(block.get_prev_hash(), tip);
+        assert_eq!(balance(&utxo, &payee)?, 0);
+        Ok(())
+    }
+}
@@ -1,43 +1,162 @@
 use crate::errors::Result;
-use std::collections::HashMap;
+use crate::network::NetworkParams;
+use crate::signature::{scheme_by_id, Ed25519Scheme, SignatureScheme, ED25519};
+use crate::storage::open_sled;
+use failure::format_err;
+use std::collections::{HashMap, HashSet};
 
-use bitcoincash_addr::{Address, HashType, Scheme};
-use crypto::{digest::Digest, ed25519, ripemd160::Ripemd160, sha2::Sha256};
-use log::info;
+use bip39::Mnemonic;
+use crypto::{
+    aead::{AeadDecryptor, AeadEncryptor},
+    aes::KeySize,
+    aes_gcm::AesGcm,
+    digest::Digest,
+    hmac::Hmac,
+    pbkdf2::pbkdf2,
+    mac::Mac,
+    ripemd160::Ripemd160,
+    sha2::{Sha256, Sha512},
+};
+use tracing::info;
 use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 
+// first byte of an exported private key
+const PRIVATE_KEY_VERSION: u8 = 0x80;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 
 pub struct Wallet {
     pub secret_key: Vec<u8>,
     pub public_key: Vec<u8>,
+    // id of the signature scheme of the keys
+    pub scheme: u8,
+}
+
+// how wallets were stored before they had a scheme, all of them ed25519
+#[derive(Deserialize)]
+struct LegacyWallet {
+    secret_key: Vec<u8>,
+    public_key: Vec<u8>,
 }
 
 impl Wallet {
     fn new() -> Self {
         let mut key: [u8; 32] = [0; 32];
         OsRng.fill_bytes(&mut key);
-        let (secret_key, public_key) = ed25519::keypair(&key);
-        Wallet {
-            secret_key: secret_key.to_vec(),
-            public_key: public_key.to_vec(),
+        Wallet::from_key(&key)
+    }
+
+    // the ed25519 wallet of a 32 byte seed
+    fn from_key(key: &[u8]) -> Self {
+        Wallet::from_secret_key(&Ed25519Scheme, Ed25519Scheme.secret_key_from_seed(key))
+            .expect("an ed25519 secret key derived from a seed is valid")
+    }
+
+    // the wallet of a secret key of `scheme`
+    pub fn from_secret_key(scheme: &dyn SignatureScheme, secret_key: Vec<u8>) -> Result<Wallet> {
+        Ok(Wallet {
+            public_key: scheme.derive_public_key(&secret_key)?,
+            secret_key,
+            scheme: scheme.id(),
+        })
+    }
+
+    pub fn scheme(&self) -> Result<&'static dyn SignatureScheme> {
+        scheme_by_id(self.scheme)
+            .ok_or_else(|| format_err!("unknown signature scheme {}", self.scheme))
+    }
+
+    // the wallet as written by save_all or by a version without schemes
+    fn deserialize(data: &[u8]) -> Result<Wallet> {
+        if let Ok(wallet) = bincode::deserialize::<Wallet>(data) {
+            return Ok(wallet);
+        }
+        let legacy: LegacyWallet = bincode::deserialize(data)?;
+        Ok(Wallet {
+            secret_key: legacy.secret_key,
+            public_key: legacy.public_key,
+            scheme: ED25519,
+        })
+    }
+
+    // a random wallet together with the 24 words it can be recovered from
+    pub fn new_with_mnemonic() -> Result<(Wallet, String)> {
+        let mut entropy = [0; 32];
+        OsRng.fill_bytes(&mut entropy);
+        let phrase = Mnemonic::from_entropy(&entropy)?.to_string();
+        let wallet = Wallet::from_mnemonic(&phrase, "")?;
+        Ok((wallet, phrase))
+    }
+
+    // the wallet of a bip39 mnemonic, its key is the slip-10 ed25519 master
+    // key of the bip39 seed
+    pub fn from_mnemonic(phrase: &str, passphrase: &str) -> Result<Wallet> {
+        let mnemonic = Mnemonic::parse(phrase)?;
+        let seed = mnemonic.to_seed(passphrase);
+        let mut mac = Hmac::new(Sha512::new(), b"ed25519 seed");
+        mac.input(&seed);
+        let master = mac.result();
+        Ok(Wallet::from_key(&master.code()[..32]))
+    }
+
+    // the private key as base58check of PRIVATE_KEY_VERSION and the 32 byte
+    // ed25519 seed
+    pub fn export_key(&self) -> Result<String> {
+        if self.scheme != ED25519 {
+            return Err(format_err!("only ed25519 keys can be exported"));
+        }
+        let mut data = vec![PRIVATE_KEY_VERSION];
+        data.extend_from_slice(&self.secret_key[..32]);
+        Ok(bs58::encode(data).with_check().into_string())
+    }
+
+    pub fn from_exported_key(key: &str) -> Result<Wallet> {
+        let data = bs58::decode(key.trim())
+            .with_check(Some(PRIVATE_KEY_VERSION))
+            .into_vec()
+            .map_err(|e| format_err!("invalid private key: {}", e))?;
+        if data.len() != 33 {
+            return Err(format_err!("invalid private key: wrong length"));
         }
+        Ok(Wallet::from_key(&data[1..]))
+    }
+
+    // signature of the message by the wallet key, see verify_message
+    pub fn sign_message(&self, msg: &[u8]) -> Result<Vec<u8>> {
+        self.scheme()?.sign(&self.secret_key, &message_hash(msg))
     }
 
-    pub fn get_address(&self) -> String {
+    pub fn get_address(&self, params: &NetworkParams) -> String {
         let mut pub_hash = self.public_key.clone();
         hash_pub_key(&mut pub_hash);
-        let address = Address {
-            body: pub_hash,
-            scheme: Scheme::Base58,
-            hash_type: HashType::Script,
-            ..Default::default()
-        };
-        address.encode().unwrap()
+        params.encode_address(&pub_hash)
     }
 }
 
+// signed messages are prefixed so that a signature over one can never pass
+// for the signature of a transaction
+const MESSAGE_MAGIC: &[u8] = b"blockchain-rust signed message:\n";
+
+fn message_hash(msg: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input(MESSAGE_MAGIC);
+    hasher.input(msg);
+    let mut hash = [0; 32];
+    hasher.result(&mut hash);
+    hash
+}
+
+// whether `sig` is the signature of `msg` by the key `pub_key` of `scheme`
+pub fn verify_message(
+    scheme: &dyn SignatureScheme,
+    pub_key: &[u8],
+    msg: &[u8],
+    sig: &[u8],
+) -> bool {
+    scheme.verify(pub_key, &message_hash(msg), sig)
+}
+
 pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     let mut hasher1 = Sha256::new();
     hasher1.input(pub_key);
@@ -49,39 +168,242 @@ pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     hasher2.result(pub_key);
 }
 
+// keys of the wallet database that are not addresses, they start with a byte
+// no address starts with
+const SALT_KEY: &[u8] = b"\0salt";
+const CHECK_KEY: &[u8] = b"\0check";
+// encrypted under the passphrase to tell a wrong one apart from a damaged store
+const CHECK_VALUE: &[u8] = b"wallets";
+// tree of the wallet database holding the labels
+const LABELS_TREE: &str = "labels";
+// tree of the wallet database holding the watch-only addresses
+const WATCH_ONLY_TREE: &str = "watchonly";
+const PBKDF2_ROUNDS: u32 = 100_000;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+// the key protecting the wallets of an encrypted store, derived from the
+// passphrase with pbkdf2
+struct WalletCipher {
+    salt: Vec<u8>,
+    key: [u8; 32],
+}
+
+impl WalletCipher {
+    fn new(passphrase: &str, salt: Vec<u8>) -> WalletCipher {
+        let mut mac = Hmac::new(Sha256::new(), passphrase.as_bytes());
+        let mut key = [0; 32];
+        pbkdf2(&mut mac, &salt, PBKDF2_ROUNDS, &mut key);
+        WalletCipher { salt, key }
+    }
+
+    // nonce, ciphertext and tag of aes-256-gcm, `aad` ties the value to its key
+    fn encrypt(&self, data: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let mut output = vec![0; data.len()];
+        let mut tag = [0; TAG_SIZE];
+        AesGcm::new(KeySize::KeySize256, &self.key, &nonce, aad).encrypt(data, &mut output, &mut tag);
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&output);
+        sealed.extend_from_slice(&tag);
+        sealed
+    }
+
+    fn decrypt(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(format_err!("encrypted wallet is truncated"));
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_SIZE);
+        let (data, tag) = rest.split_at(rest.len() - TAG_SIZE);
+        let mut output = vec![0; data.len()];
+        if !AesGcm::new(KeySize::KeySize256, &self.key, nonce, aad).decrypt(data, &mut output, tag) {
+            return Err(format_err!("wrong passphrase"));
+        }
+        Ok(output)
+    }
+}
+
 pub struct Wallets {
+    // network of the addresses
+    params: NetworkParams,
+    // directory of the sled store
+    path: String,
     wallets: HashMap<String, Wallet>,
+    // names given to some of the addresses, kept in their own tree of the store
+    labels: HashMap<String, String>,
+    // addresses followed without a private key, they cannot spend
+    watch_only: HashSet<String>,
+    // set when the store is encrypted, or is to be encrypted on save
+    cipher: Option<WalletCipher>,
 }
 impl Wallets {
-    pub fn new() -> Result<Wallets> {
+    // the wallets of an unencrypted store
+    pub fn new(params: &NetworkParams, path: &str) -> Result<Wallets> {
+        Wallets::open(params, path, None)
+    }
+
+    // the wallets of the store at `path`, usually the wallets directory of
+    // the data directory. an encrypted one needs its passphrase, a
+    // passphrase given for an unencrypted store encrypts it on the next save
+    pub fn open(params: &NetworkParams, path: &str, passphrase: Option<&str>) -> Result<Wallets> {
         let mut wlt = Wallets {
+            params: *params,
+            path: path.to_string(),
             wallets: HashMap::<String, Wallet>::new(),
+            labels: HashMap::new(),
+            watch_only: HashSet::new(),
+            cipher: None,
         };
 
-        let db = sled::open("data/wallets")?;
+        let db = open_sled(&wlt.path)?;
+        match (db.get(SALT_KEY)?, passphrase) {
+            (Some(salt), Some(passphrase)) => {
+                let cipher = WalletCipher::new(passphrase, salt.to_vec());
+                if let Some(check) = db.get(CHECK_KEY)? {
+                    cipher.decrypt(&check, CHECK_KEY)?;
+                }
+                wlt.cipher = Some(cipher);
+            }
+            (Some(_), None) => return Err(format_err!("the wallets are encrypted, a passphrase is needed")),
+            (None, Some(passphrase)) => {
+                let mut salt = vec![0; 16];
+                OsRng.fill_bytes(&mut salt);
+                wlt.cipher = Some(WalletCipher::new(passphrase, salt));
+            }
+            (None, None) => {}
+        }
+
+        let encrypted = db.contains_key(SALT_KEY)?;
         for item in db.into_iter() {
             let i = item?;
+            if i.0.starts_with(b"\0") {
+                continue;
+            }
             let address = String::from_utf8(i.0.to_vec())?;
-            let wallet: Wallet = bincode::deserialize(&i.1.to_vec())?;
+            let wallet: Wallet = match &wlt.cipher {
+                Some(cipher) if encrypted => Wallet::deserialize(&cipher.decrypt(&i.1, &i.0)?)?,
+                _ => Wallet::deserialize(&i.1)?,
+            };
 
             wlt.wallets.insert(address, wallet);
         }
 
+        for item in db.open_tree(LABELS_TREE)?.iter() {
+            let (address, label) = item?;
+            wlt.labels.insert(
+                String::from_utf8(address.to_vec())?,
+                String::from_utf8(label.to_vec())?,
+            );
+        }
+
+        for item in db.open_tree(WATCH_ONLY_TREE)?.iter() {
+            let (address, _) = item?;
+            wlt.watch_only.insert(String::from_utf8(address.to_vec())?);
+        }
+
         drop(db);
         Ok(wlt)
     }
 
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
     pub fn create_wallet(&mut self) -> String {
         let wallet = Wallet::new();
-        let address = wallet.get_address();
+        let address = wallet.get_address(&self.params);
         self.wallets.insert(address.clone(), wallet);
         info!("Create wallet:{}", address);
         address
     }
 
+    // keep `wallet`, one created from a mnemonic for example
+    pub fn add_wallet(&mut self, wallet: Wallet) -> String {
+        let address = wallet.get_address(&self.params);
+        self.wallets.insert(address.clone(), wallet);
+        info!("Add wallet:{}", address);
+        address
+    }
+
+    // keep the wallet of a key written by Wallet::export_key, returns its address
+    pub fn import_key(&mut self, key: &str) -> Result<String> {
+        Ok(self.add_wallet(Wallet::from_exported_key(key)?))
+    }
+
+    // follow `address` without its private key, its balance and utxos can be
+    // looked up but it cannot spend. saved by save_all
+    pub fn add_watch_only(&mut self, address: &str) -> Result<()> {
+        self.params.decode_address(address)?;
+        if self.wallets.contains_key(address) {
+            return Err(format_err!("address {} already has a wallet", address));
+        }
+        self.watch_only.insert(address.to_string());
+        info!("Watch address:{}", address);
+        Ok(())
+    }
+
+    pub fn is_watch_only(&self, address: &str) -> bool {
+        self.watch_only.contains(address)
+    }
+
+    pub fn get_watch_only(&self) -> Vec<String> {
+        let mut addresses: Vec<String> = self.watch_only.iter().cloned().collect();
+        addresses.sort();
+        addresses
+    }
+
+    // forget the wallet or watch-only address `address` and remove it from
+    // the store, false if there was none
+    pub fn delete_wallet(&mut self, address: &str) -> Result<bool> {
+        if self.wallets.remove(address).is_none() && !self.watch_only.remove(address) {
+            return Ok(false);
+        }
+        self.labels.remove(address);
+        let db = open_sled(&self.path)?;
+        db.remove(address)?;
+        db.open_tree(LABELS_TREE)?.remove(address)?;
+        db.open_tree(WATCH_ONLY_TREE)?.remove(address)?;
+        db.flush()?;
+        drop(db);
+        info!("Delete wallet:{}", address);
+        Ok(true)
+    }
+
+    // name the wallet or watch-only address `address`, an empty label removes
+    // it. saved by save_all
+    pub fn set_label(&mut self, address: &str, label: &str) -> Result<()> {
+        if !self.wallets.contains_key(address) && !self.watch_only.contains(address) {
+            return Err(format_err!("no wallet for address {}", address));
+        }
+        if label.is_empty() {
+            self.labels.remove(address);
+        } else {
+            self.labels.insert(address.to_string(), label.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn get_label(&self, address: &str) -> Option<&str> {
+        self.labels.get(address).map(String::as_str)
+    }
+
+    // the addresses named `label`, several wallets may share one
+    pub fn get_addresses_by_label(&self, label: &str) -> Vec<String> {
+        let mut addresses: Vec<String> = self
+            .labels
+            .iter()
+            .filter(|(_, l)| l.as_str() == label)
+            .map(|(address, _)| address.clone())
+            .collect();
+        addresses.sort();
+        addresses
+    }
+
     pub fn get_all_wallets(&self) -> Vec<String> {
         let mut addresses = Vec::<String>::new();
-        for (address, _) in &self.wallets {
+        for address in self.wallets.keys() {
             addresses.push(address.clone());
         }
         addresses
@@ -91,14 +413,182 @@ impl Wallets {
         self.wallets.get(address)
     }
 
+    // the wallet of `address` to sign with, a watch-only address has no key
+    pub fn get_spendable_wallet(&self, address: &str) -> Result<&Wallet> {
+        match self.wallets.get(address) {
+            Some(wallet) => Ok(wallet),
+            None if self.watch_only.contains(address) => Err(format_err!(
+                "address {} is watch-only, there is no private key to spend with",
+                address
+            )),
+            None => Err(format_err!("no wallet for address {}", address)),
+        }
+    }
+
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
+        let db = open_sled(&self.path)?;
+        if let Some(cipher) = &self.cipher {
+            db.insert(SALT_KEY, cipher.salt.clone())?;
+            db.insert(CHECK_KEY, cipher.encrypt(CHECK_VALUE, CHECK_KEY))?;
+        }
         for (address, wallet) in &self.wallets {
-            let data = bincode::serialize(wallet)?;
+            let mut data = bincode::serialize(wallet)?;
+            if let Some(cipher) = &self.cipher {
+                data = cipher.encrypt(&data, address.as_bytes());
+            }
             db.insert(address, data)?;
         }
+        let labels = db.open_tree(LABELS_TREE)?;
+        labels.clear()?;
+        for (address, label) in &self.labels {
+            labels.insert(address, label.as_bytes())?;
+        }
+        let watch_only = db.open_tree(WATCH_ONLY_TREE)?;
+        for address in &self.watch_only {
+            watch_only.insert(address, &[])?;
+        }
         db.flush()?;
         drop(db);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::REGTEST;
+
+    #[test]
+    fn wallets_saved_at_a_path_are_found_there_again() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("mine");
+        let path = path.to_str().unwrap();
+        let mut wallets = Wallets::new(&REGTEST, path)?;
+        let address = wallets.create_wallet();
+        wallets.set_label(&address, "savings")?;
+        wallets.save_all()?;
+        drop(wallets);
+
+        let wallets = Wallets::new(&REGTEST, path)?;
+        assert_eq!(wallets.get_all_wallets(), vec![address.clone()]);
+        assert_eq!(wallets.get_label(&address), Some("savings"));
+        let wallet = wallets.get_wallet(&address).unwrap();
+        assert_eq!(wallet.get_address(&REGTEST), address);
+        Ok(())
+    }
+
+    #[test]
+    fn an_encrypted_store_opens_with_its_passphrase_only() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wallets");
+        let path = path.to_str().unwrap();
+        let mut wallets = Wallets::open(&REGTEST, path, Some("correct horse"))?;
+        let address = wallets.create_wallet();
+        wallets.save_all()?;
+        drop(wallets);
+
+        let wallets = Wallets::open(&REGTEST, path, Some("correct horse"))?;
+        assert!(wallets.is_encrypted());
+        assert_eq!(wallets.get_all_wallets(), vec![address.clone()]);
+        assert_eq!(wallets.get_wallet(&address).unwrap().get_address(&REGTEST), address);
+        drop(wallets);
+
+        assert!(Wallets::open(&REGTEST, path, Some("battery staple")).is_err());
+        match Wallets::open(&REGTEST, path, None) {
+            Err(e) => assert!(e.to_string().contains("a passphrase is needed"), "{}", e),
+            Ok(_) => panic!("an encrypted store opened without its passphrase"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn a_mnemonic_always_gives_the_same_address() -> Result<()> {
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+                      abandon abandon about";
+        let address = Wallet::from_mnemonic(phrase, "")?.get_address(&REGTEST);
+        assert_eq!(Wallet::from_mnemonic(phrase, "")?.get_address(&REGTEST), address);
+        assert_ne!(Wallet::from_mnemonic(phrase, "extra")?.get_address(&REGTEST), address);
+
+        let (wallet, phrase) = Wallet::new_with_mnemonic()?;
+        assert_eq!(phrase.split_whitespace().count(), 24);
+        let recovered = Wallet::from_mnemonic(&phrase, "")?;
+        assert_eq!(recovered.get_address(&REGTEST), wallet.get_address(&REGTEST));
+        assert_eq!(recovered.secret_key, wallet.secret_key);
+        Ok(())
+    }
+
+    #[test]
+    fn an_exported_key_imports_into_another_store() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut first = Wallets::new(&REGTEST, dir.path().join("first").to_str().unwrap())?;
+        let address = first.create_wallet();
+        let key = first.get_wallet(&address).unwrap().export_key()?;
+
+        let path = dir.path().join("second");
+        let mut second = Wallets::new(&REGTEST, path.to_str().unwrap())?;
+        assert_eq!(second.import_key(&key)?, address);
+        second.save_all()?;
+        drop(second);
+        let second = Wallets::new(&REGTEST, path.to_str().unwrap())?;
+        let imported = second.get_wallet(&address).unwrap();
+        assert_eq!(imported.public_key, first.get_wallet(&address).unwrap().public_key);
+
+        // one changed character breaks the checksum
+        let mut corrupted = key.clone();
+        let last = corrupted.pop().unwrap();
+        corrupted.push(if last == '2' { '3' } else { '2' });
+        let mut other = Wallets::new(&REGTEST, dir.path().join("third").to_str().unwrap())?;
+        let e = other.import_key(&corrupted).unwrap_err();
+        assert!(e.to_string().contains("invalid private key"), "{}", e);
+        assert!(other.get_all_wallets().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn a_signed_message_verifies_only_unchanged() -> Result<()> {
+        let wallet = Wallet::new();
+        let msg = b"pay 10 to bob";
+        let sig = wallet.sign_message(msg)?;
+        assert!(verify_message(&Ed25519Scheme, &wallet.public_key, msg, &sig));
+
+        assert!(!verify_message(&Ed25519Scheme, &wallet.public_key, b"pay 99 to bob", &sig));
+        assert!(!verify_message(&Ed25519Scheme, &Wallet::new().public_key, msg, &sig));
+        let mut tampered = sig.clone();
+        tampered[0] ^= 1;
+        assert!(!verify_message(&Ed25519Scheme, &wallet.public_key, msg, &tampered));
+        Ok(())
+    }
+
+    #[test]
+    fn labels_survive_a_reload_and_find_their_addresses() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wallets");
+        let path = path.to_str().unwrap();
+        let mut wallets = Wallets::new(&REGTEST, path)?;
+        let (rent, food, other) =
+            (wallets.create_wallet(), wallets.create_wallet(), wallets.create_wallet());
+        wallets.set_label(&rent, "bills")?;
+        wallets.set_label(&food, "bills")?;
+        wallets.set_label(&other, "savings")?;
+        assert!(wallets.set_label(&Wallet::new().get_address(&REGTEST), "bills").is_err());
+        wallets.save_all()?;
+        drop(wallets);
+
+        let mut wallets = Wallets::new(&REGTEST, path)?;
+        assert_eq!(wallets.get_label(&rent), Some("bills"));
+        let mut bills = vec![rent.clone(), food.clone()];
+        bills.sort();
+        assert_eq!(wallets.get_addresses_by_label("bills"), bills);
+        assert_eq!(wallets.get_addresses_by_label("savings"), vec![other.clone()]);
+        assert!(wallets.get_addresses_by_label("travel").is_empty());
+
+        // an empty label removes it for good
+        wallets.set_label(&other, "")?;
+        wallets.save_all()?;
+        drop(wallets);
+        let wallets = Wallets::new(&REGTEST, path)?;
+        assert_eq!(wallets.get_label(&other), None);
+        assert!(wallets.get_addresses_by_label("savings").is_empty());
+        Ok(())
+    }
+}